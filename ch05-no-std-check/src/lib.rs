@@ -0,0 +1,49 @@
+//! `ch05_rubust_trees`のデータ構造が`std`なしで使用できることを確認するためのクレート
+//!
+//! `cargo check -p ch05_no_std_check`で、`std`を無効にした`ch05_rubust_trees`をビルドします
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::{string::ToString, vec::Vec};
+use ch05_rubust_trees::heap::{Backing, PriorityQueue};
+use ch05_rubust_trees::trie::TrieTree;
+
+/// TrieTreeにキーを登録し、prefixから始まるキーの数を数えます
+pub fn count_keys_with_prefix(keys: &[&str], prefix: &str) -> usize {
+    let mut trie = TrieTree::default();
+    for (i, key) in keys.iter().enumerate() {
+        trie.add(key.to_string(), i);
+    }
+    trie.count_prefix(prefix)
+}
+
+/// HeapTreeを使用した優先度付きキューで値を降順に並べます
+pub fn sort_descending(values: &[u64]) -> Vec<u64> {
+    let mut queue = PriorityQueue::new(Backing::BinaryHeap);
+    for &value in values {
+        queue.push(value);
+    }
+    let mut sorted = Vec::with_capacity(values.len());
+    while let Some(value) = queue.pop() {
+        sorted.push(value);
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trie_should_work_without_std() {
+        let keys = ["/floor-1/a", "/floor-1/b", "/floor-2/a"];
+        assert_eq!(count_keys_with_prefix(&keys, "/floor-1"), 2);
+        assert_eq!(count_keys_with_prefix(&keys, ""), 3);
+    }
+
+    #[test]
+    fn heap_should_work_without_std() {
+        assert_eq!(sort_descending(&[3, 1, 4, 1, 5]), vec![5, 4, 3, 1, 1]);
+    }
+}
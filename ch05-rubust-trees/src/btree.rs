@@ -1,14 +1,22 @@
-use crate::iot::IoTDevice;
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
 
-type Tree = Box<Node>;
-type Key = u64;
+type Tree<K, V> = Box<Node<K, V>>;
 
-type ValueChildPair = (Option<IoTDevice>, Option<Tree>);
+type ValueChildPair<K, V> = (Option<V>, Option<Tree<K, V>>);
 
-/// B-treeノードが保持できる最大の子ノード数
+/// 子ノードの分割により親ノードに追加する、中央のキーと値・子ノードの組
+type SplitResult<K, V> = (K, ValueChildPair<K, V>);
+
+/// 値からB木のキーを導出する関数
+pub type KeyFn<K, V> = fn(&V) -> K;
+
+/// B-treeノードが保持できる最大の子ノード数 (次数) のデフォルト値
 /// キーを3つ以上持つノードは分割されます
 const DEFAULT_ORDER: usize = 3;
 
+/// 次数の最小値。次数2ではノードを分割すると空のノードができてしまいます
+const MIN_ORDER: usize = 3;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum NodeType {
     Leaf,
@@ -23,7 +31,8 @@ pub enum Direction {
 }
 #[derive(Debug, PartialEq)]
 /// B木の各ノードを表現する構造体
-/// - B木のノードはキーと値のペアを保持する(values)
+/// - B木のノードはキーと値のペアを保持する(keys, values)
+///   - i番目のキーとi番目の値がペアになる
 /// - キーと値のペアの間には、子ノードへのポインタがある(children)
 /// - もっとも左のペアの左側には、左の子ノードへのポインタがある(left_child)
 ///
@@ -88,43 +97,53 @@ pub enum Direction {
 ///                            │      v T1  T2 T3  T4
 ///                            v    <min
 /// ```
-pub struct Node {
-    values: Vec<Option<IoTDevice>>,
-    children: Vec<Option<Tree>>,
-    left_child: Option<Tree>,
+pub struct Node<K, V> {
+    keys: Vec<K>,
+    values: Vec<Option<V>>,
+    children: Vec<Option<Tree<K, V>>>,
+    left_child: Option<Tree<K, V>>,
     pub node_type: NodeType,
+    /// 自ノードを含む部分木に含まれるキーの数
+    size: usize,
 }
 
-impl Node {
-    pub fn new_leaf() -> Tree {
+impl<K: Ord, V> Node<K, V> {
+    pub fn new_leaf() -> Tree<K, V> {
         Node::new(NodeType::Leaf)
     }
 
-    pub fn new_regular() -> Tree {
+    pub fn new_regular() -> Tree<K, V> {
         Node::new(NodeType::Regular)
     }
 
-    fn new(node_type: NodeType) -> Tree {
+    fn new(node_type: NodeType) -> Tree<K, V> {
         Box::new(Node {
+            keys: vec![],
             values: vec![],
             children: vec![],
             left_child: None,
             node_type,
+            size: 0,
         })
     }
 
     fn from_nodes(
         node_type: NodeType,
-        left: Option<Box<Node>>,
-        values: Vec<Option<IoTDevice>>,
-        children: Vec<Option<Tree>>,
-    ) -> Tree {
-        Box::new(Node {
+        left: Option<Tree<K, V>>,
+        keys: Vec<K>,
+        values: Vec<Option<V>>,
+        children: Vec<Option<Tree<K, V>>>,
+    ) -> Tree<K, V> {
+        let mut node = Box::new(Node {
+            keys,
             values,
             children,
             left_child: left,
             node_type,
-        })
+            size: 0,
+        });
+        node.update_size();
+        node
     }
 
     pub fn len(&self) -> usize {
@@ -135,60 +154,95 @@ impl Node {
         self.values.is_empty()
     }
 
-    pub fn find_closest_index(&self, key: Key) -> Direction {
+    /// 自ノードを含む部分木に含まれるキーの数を取得します
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// 子ノードのキー数から部分木のキー数を再計算します
+    fn update_size(&mut self) {
+        let left = self.left_child.as_ref().map_or(0, |child| child.size);
+        let children: usize = self.children.iter().flatten().map(|child| child.size).sum();
+        self.size = self.values.len() + left + children;
+    }
+
+    fn key_at(&self, index: usize) -> Option<&K> {
+        self.keys.get(index)
+    }
+
+    pub fn find_closest_index(&self, key: &K) -> Direction {
         let mut index = Direction::Left;
-        for (i, pair) in self.values.iter().enumerate() {
-            if let Some(value) = pair {
-                if value.numeriacl_id <= key {
-                    index = Direction::Right(i);
-                } else {
-                    break;
-                }
+        for (i, k) in self.keys.iter().enumerate() {
+            if k <= key {
+                index = Direction::Right(i);
+            } else {
+                break;
             }
         }
         index
     }
 
-    pub fn add_key(&mut self, key: Key, value: ValueChildPair) -> bool {
-        let index = match self.find_closest_index(key) {
+    /// keyに一番近い子要素の位置 (0はleft_child、j>0はchildren\[j-1\]) を取得します
+    fn child_index(&self, key: &K) -> usize {
+        match self.find_closest_index(key) {
             Direction::Left => 0,
             Direction::Right(i) => i + 1,
-        };
+        }
+    }
+
+    pub fn add_key(&mut self, key: K, value: ValueChildPair<K, V>) -> bool {
+        let index = self.child_index(&key);
         let (dev, tree) = value;
 
+        self.keys.insert(index, key);
         self.values.insert(index, dev);
         self.children.insert(index, tree);
+        self.update_size();
         true
     }
 
-    fn set_left_child(&mut self, tree: Tree) {
+    fn set_left_child(&mut self, tree: Tree<K, V>) {
         self.left_child = Some(tree);
+        self.update_size();
     }
 
     // keyに一番近い子要素を削除する
-    pub fn remove_key(&mut self, key: Key) -> Option<(Key, ValueChildPair)> {
-        match self.find_closest_index(key) {
+    // 値を削除した場合は、keyの代わりに値とペアになっていたキーを返す
+    pub fn remove_key(&mut self, key: K) -> Option<SplitResult<K, V>> {
+        match self.find_closest_index(&key) {
             Direction::Left => {
                 let tree = self.left_child.take();
+                self.update_size();
                 Some((key, (None, tree)))
             }
             Direction::Right(i) => {
+                let key = self.keys.remove(i);
                 let value = self.values.remove(i);
                 let tree = self.children.remove(i);
+                self.update_size();
                 Some((key, (value, tree)))
             }
         }
     }
 
-    /// 完全一致するキーのデバイスを取得する
-    pub fn find_value(&self, key: Key) -> Option<&IoTDevice> {
-        self.values
-            .iter()
-            .find_map(|value| value.as_ref().filter(|device| device.numeriacl_id == key))
+    /// 完全一致するキーの位置を取得する
+    fn position(&self, key: &K) -> Option<usize> {
+        self.keys.iter().position(|k| k == key)
+    }
+
+    /// 完全一致するキーの値を取得する
+    pub fn find_value(&self, key: &K) -> Option<&V> {
+        self.values[self.position(key)?].as_ref()
+    }
+
+    /// 完全一致するキーの値の可変な参照を取得する
+    fn find_value_mut(&mut self, key: &K) -> Option<&mut V> {
+        let index = self.position(key)?;
+        self.values[index].as_mut()
     }
 
     /// キーに一番近い子要素を取得する
-    pub fn find_child(&self, key: Key) -> Option<&Tree> {
+    pub fn find_child(&self, key: &K) -> Option<&Tree<K, V>> {
         match self.find_closest_index(key) {
             Direction::Left => self.left_child.as_ref(),
             Direction::Right(i) => self.children.get(i).and_then(|child| child.as_ref()),
@@ -196,41 +250,45 @@ impl Node {
     }
 
     /// キーに一番近い要素の可変な参照を取得します
-    pub fn find_child_mut(&mut self, key: Key) -> Option<&mut Option<Tree>> {
+    pub fn find_child_mut(&mut self, key: &K) -> Option<&mut Option<Tree<K, V>>> {
         match self.find_closest_index(key) {
             Direction::Left => Some(&mut self.left_child),
             Direction::Right(i) => self.children.get_mut(i),
         }
     }
 
-    /// trueの場合、ノードが保持できる要素数を超えており、分割が必要です
-    fn is_overflow(&self) -> bool {
-        self.len() + 1 > DEFAULT_ORDER
+    /// trueの場合、次数orderのノードが保持できる要素数を超えており、分割が必要です
+    fn is_overflow(&self, order: usize) -> bool {
+        self.len() + 1 > order
     }
 
-    /// index以降の値と子ノードを自身のノードから削除して、返します
-    fn take_after(&mut self, index: usize) -> (IoTDevice, Tree) {
+    /// index以降のキー・値と子ノードを自身のノードから削除して、返します
+    fn take_after(&mut self, index: usize) -> ((K, V), Tree<K, V>) {
+        let new_keys = self.keys.split_off(index + 1);
+        let new_values = self.values.split_off(index + 1);
+        let new_children = self.children.split_off(index + 1);
+        let mid_key = self.keys.remove(index);
         let mid_value = self.values.remove(index);
         let mid_node = self.children.remove(index);
-        let mut new_values = vec![];
-        let mut new_children = vec![];
-        for _ in index..self.len() {
-            let value = self.values.remove(index);
-            let child = self.children.remove(index);
-            new_values.push(value);
-            new_children.push(child);
-        }
 
-        let new_node = Node::from_nodes(self.node_type.clone(), mid_node, new_values, new_children);
+        let new_node = Node::from_nodes(
+            self.node_type.clone(),
+            mid_node,
+            new_keys,
+            new_values,
+            new_children,
+        );
+        self.update_size();
 
-        (mid_value.unwrap(), new_node)
+        ((mid_key, mid_value.unwrap()), new_node)
     }
 
     /// ノードがオーバーフローした際にノードを分割します
     /// 新しいノードを作成し、中央の値より右側の値を新しいノードに移動します
-    /// 中央の値とその子ノードを返します
-    pub(self) fn split(&mut self) -> (IoTDevice, Tree) {
-        if !self.is_overflow() {
+    /// 中央のキーと値のペアとその子ノードを返します
+    /// 追加によりオーバーフローしたノードは次数と同じ数のキーを持つため、分割位置は次数/2になります
+    pub(self) fn split(&mut self, order: usize) -> ((K, V), Tree<K, V>) {
+        if !self.is_overflow(order) {
             panic!("Node is not overflowed");
         }
         let mid = self.len() / 2;
@@ -238,33 +296,260 @@ impl Node {
         (orphan_value, new_n)
     }
 
-    pub(self) fn children(&self) -> &Vec<Option<Tree>> {
-        &self.children
+    /// ノードを中央の値で左右のノードに分割します
+    /// 左のノード、中央のキーと値のペア、右のノードを返し、自身のノードは空になります
+    pub(self) fn split_into(&mut self) -> (Tree<K, V>, (K, V), Tree<K, V>) {
+        if self.is_empty() {
+            panic!("Node is empty");
+        }
+        let mid = self.len() / 2;
+        let (median, right) = self.take_after(mid);
+        let left = Box::new(core::mem::replace(self, *Node::new(self.node_type.clone())));
+        (left, median, right)
+    }
+
+    /// 次数orderのB木で、root以外のノードが保持する必要がある最小のキー数
+    fn min_len(order: usize) -> usize {
+        order.div_ceil(2) - 1
+    }
+
+    /// j番目の子ノードの可変な参照を取得します (0はleft_child、j>0はchildren\[j-1\])
+    fn child_mut(&mut self, j: usize) -> &mut Node<K, V> {
+        self.child_slot(j)
+            .as_mut()
+            .expect("regular node must have a child")
+    }
+
+    /// j番目の子ノードを保持する位置の可変な参照を取得します
+    fn child_slot(&mut self, j: usize) -> &mut Option<Tree<K, V>> {
+        if j == 0 {
+            &mut self.left_child
+        } else {
+            &mut self.children[j - 1]
+        }
+    }
+
+    /// キーに一致する値を部分木から削除して、返します
+    /// 削除により子ノードのキー数が最小値を下回った場合は、兄弟ノードから借りるか結合します
+    fn remove_rec(&mut self, key: &K, order: usize) -> Option<V> {
+        let removed = match (self.position(key), &self.node_type) {
+            (Some(i), NodeType::Leaf) => {
+                self.keys.remove(i);
+                self.children.remove(i);
+                self.values.remove(i)
+            }
+            (Some(i), NodeType::Regular) => {
+                // 左側の部分木の最大値 (直前の値) と入れ替えてから、部分木の最大値を削除する
+                let (predecessor_key, predecessor) = self.child_mut(i).remove_max(order);
+                self.keys[i] = predecessor_key;
+                let removed = core::mem::replace(&mut self.values[i], predecessor);
+                self.fix_underflow(i, order);
+                removed
+            }
+            (None, NodeType::Leaf) => return None,
+            (None, NodeType::Regular) => {
+                let j = self.child_index(key);
+                let removed = self.child_mut(j).remove_rec(key, order)?;
+                self.fix_underflow(j, order);
+                Some(removed)
+            }
+        };
+        self.update_size();
+        removed
+    }
+
+    /// 部分木の最大のキーと値を削除して、返します
+    fn remove_max(&mut self, order: usize) -> (K, Option<V>) {
+        let removed = match self.node_type {
+            NodeType::Leaf => {
+                self.children.pop();
+                let value = self.values.pop().flatten();
+                (self.keys.pop().expect("node must not be empty"), value)
+            }
+            NodeType::Regular => {
+                let j = self.len();
+                let removed = self.child_mut(j).remove_max(order);
+                self.fix_underflow(j, order);
+                removed
+            }
+        };
+        self.update_size();
+        removed
+    }
+
+    /// j番目の子ノードのキー数が最小値を下回っている場合に修正します
+    /// - 左の兄弟ノードに余裕がある場合: 区切りの値を子ノードの先頭に下ろし、兄弟ノードの最大値を区切りにする
+    /// - 右の兄弟ノードに余裕がある場合: 区切りの値を子ノードの末尾に下ろし、兄弟ノードの最小値を区切りにする
+    /// - どちらにも余裕がない場合: 区切りの値と兄弟ノードを子ノードと結合する
+    fn fix_underflow(&mut self, j: usize, order: usize) {
+        let min_len = Self::min_len(order);
+        if self.child_mut(j).len() >= min_len {
+            return;
+        }
+        if j > 0 && self.child_mut(j - 1).len() > min_len {
+            let left = self.child_mut(j - 1);
+            let key = left.keys.pop().expect("sibling must not be empty");
+            let value = left.values.pop().flatten();
+            let moved = left.children.pop().flatten();
+            left.update_size();
+            let separator_key = core::mem::replace(&mut self.keys[j - 1], key);
+            let separator = core::mem::replace(&mut self.values[j - 1], value);
+            let child = self.child_mut(j);
+            let old_left = core::mem::replace(&mut child.left_child, moved);
+            child.keys.insert(0, separator_key);
+            child.values.insert(0, separator);
+            child.children.insert(0, old_left);
+            child.update_size();
+        } else if j < self.len() && self.child_mut(j + 1).len() > min_len {
+            let right = self.child_mut(j + 1);
+            let key = right.keys.remove(0);
+            let value = right.values.remove(0);
+            let moved = core::mem::replace(&mut right.left_child, right.children.remove(0));
+            right.update_size();
+            let separator_key = core::mem::replace(&mut self.keys[j], key);
+            let separator = core::mem::replace(&mut self.values[j], value);
+            let child = self.child_mut(j);
+            child.keys.push(separator_key);
+            child.values.push(separator);
+            child.children.push(moved);
+            child.update_size();
+        } else if j > 0 {
+            self.merge_children(j - 1);
+        } else {
+            self.merge_children(j);
+        }
+    }
+
+    /// k番目とk+1番目の子ノードを、間にある区切りの値と合わせてk番目の子ノードに結合します
+    fn merge_children(&mut self, k: usize) {
+        let separator_key = self.keys.remove(k);
+        let separator = self.values.remove(k);
+        let right = self
+            .children
+            .remove(k)
+            .expect("regular node must have a child");
+        let right = *right;
+        let left = self.child_mut(k);
+        left.keys.push(separator_key);
+        left.values.push(separator);
+        left.children.push(right.left_child);
+        left.keys.extend(right.keys);
+        left.values.extend(right.values);
+        left.children.extend(right.children);
+        left.update_size();
+    }
+}
+
+/// B木の値をキーの昇順に辿るイテレータ
+/// 訪問中のノードと、そのノードで次に返す値の位置をスタックに保持します
+struct InOrder<'a, K, V> {
+    stack: Vec<(&'a Node<K, V>, usize)>,
+}
+
+impl<'a, K, V> InOrder<'a, K, V> {
+    fn new(root: Option<&'a Node<K, V>>) -> Self {
+        let mut iter = InOrder { stack: vec![] };
+        iter.push_left_spine(root);
+        iter
+    }
+
+    /// left_childを葉まで辿り、スタックに積みます
+    fn push_left_spine(&mut self, mut node: Option<&'a Node<K, V>>) {
+        while let Some(current) = node {
+            self.stack.push((current, 0));
+            node = current.left_child.as_deref();
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for InOrder<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, index) = self.stack.last_mut()?;
+            let node: &'a Node<K, V> = node;
+            if *index >= node.values.len() {
+                self.stack.pop();
+                continue;
+            }
+            let i = *index;
+            *index += 1;
+            // i番目の値の次は、その右側の子ノード (children[i]) の最小値
+            self.push_left_spine(node.children[i].as_deref());
+            if let Some(value) = node.values[i].as_ref() {
+                return Some((&node.keys[i], value));
+            }
+        }
     }
 }
 
-pub struct BTree {
-    root: Option<Tree>,
+/// キーKと値Vのペアを保持するB木
+/// デバイスをデバイスIDで管理する場合は`BTree<u64, IoTDevice>`になります
+pub struct BTree<K, V> {
+    root: Option<Tree<K, V>>,
     order: usize,
+    /// `add_value`で値からキーを導出する関数
+    key_of: Option<KeyFn<K, V>>,
     pub length: u64,
 }
 
-impl BTree {
+impl<K: Ord, V> BTree<K, V> {
+    /// 値からキーを導出する関数を指定してB木を作成します
+    /// `add_value`で値を追加する場合に使います
+    pub fn with_key_fn(key_of: KeyFn<K, V>) -> Self {
+        BTree {
+            key_of: Some(key_of),
+            ..Default::default()
+        }
+    }
+
+    /// 次数 (ノードが保持できる最大の子ノード数) を指定してB木を作成します
+    /// 各ノードは最大で次数-1個のキーを持ち、それを超えると分割されます
+    /// 次数が3未満の場合はpanicします
+    pub fn with_order(order: usize) -> Self {
+        assert!(
+            order >= MIN_ORDER,
+            "order must be at least {MIN_ORDER}: {order}"
+        );
+        BTree {
+            order,
+            ..Default::default()
+        }
+    }
+
     /// B木に値を追加します
-    pub fn add(&mut self, key: Key, value: IoTDevice) {
+    pub fn add(&mut self, key: K, value: V) {
         let root = self.root.take().unwrap_or(Node::new_leaf());
         let (new_root, _) = self.add_rec(root, key, value, true);
         self.root = Some(new_root);
     }
 
+    /// `with_key_fn`で指定した関数で値からキーを導出して、B木に値を追加します
+    /// キー関数を指定せずに (`with_key_fn`以外で) 作成したB木の場合はpanicします
+    pub fn add_value(&mut self, value: V) {
+        let key_of = self.key_of.expect("key function is not set");
+        self.add(key_of(&value), value);
+    }
+
+    /// キーが存在しない場合のみB木に値を追加します
+    /// キーがすでに存在する場合は、追加しようとした値をErrで返します
+    pub fn try_add(&mut self, key: K, value: V) -> Result<(), V> {
+        if self.find(&key).is_some() {
+            return Err(value);
+        }
+        self.add(key, value);
+        Ok(())
+    }
+
     /// B木に値を追加します
     fn add_rec(
         &mut self,
-        target: Tree,
-        key: Key,
-        value: IoTDevice,
+        target: Tree<K, V>,
+        key: K,
+        value: V,
         is_root: bool,
-    ) -> (Tree, Option<ValueChildPair>) {
+    ) -> (Tree<K, V>, Option<SplitResult<K, V>>) {
         let mut target = target;
         match target.node_type {
             NodeType::Leaf => {
@@ -273,57 +558,269 @@ impl BTree {
                 }
             }
             NodeType::Regular => {
-                let (key, (dev, child)) = target.remove_key(key).unwrap();
-                let new = self.add_rec(child.unwrap(), key, value, false);
-                match dev {
-                    Some(dev) => {
-                        target.add_key(key, (Some(dev), Some(new.0)));
-                    }
-                    None => {
-                        target.set_left_child(new.0);
-                    }
-                }
+                // キーに一番近い子ノードを取り出して再帰的に追加し、元の位置に戻す
+                let j = target.child_index(&key);
+                let child = target.child_slot(j).take();
+                let child = child.expect("regular node must have a child");
+                let (child, split_result) = self.add_rec(child, key, value, false);
+                *target.child_slot(j) = Some(child);
+                target.update_size();
                 // 子ノードへの要素追加により子ノードが分割された場合
                 // 分割地点の値と子ノードを自身のノードに追加する
-                if let Some(split_result) = new.1 {
-                    let split_key = split_result.0.as_ref().unwrap().numeriacl_id;
+                if let Some((split_key, split_result)) = split_result {
                     target.add_key(split_key, split_result);
                 }
             }
         };
 
         // ノードがオーバーフローしていない場合は分割処理をせずに終了
-        if !target.is_overflow() {
+        if !target.is_overflow(self.order) {
             return (target, None);
         }
 
         // ノードがオーバーフローしている場合は分割処理を行う
-        let (new_parent_value, sibiling) = target.split();
         if is_root {
             // ルートノードがオーバーフローした場合は
-            // - 現在のルートノードを分割
+            // - 現在のルートノードを左右のノードに分割
             // - 新しいルートノードを作成
-            // - 分割された現在のルートノードを新しいルートノードの子ノードに追加
+            // - 分割された左右のノードを新しいルートノードの子ノードに追加
+            let (left, (key, value), right) = target.split_into();
             let mut parent = Node::new_regular();
-            parent.set_left_child(target);
-            parent.add_key(
-                new_parent_value.numeriacl_id,
-                (Some(new_parent_value), Some(sibiling)),
-            );
+            parent.set_left_child(left);
+            parent.add_key(key, (Some(value), Some(right)));
             (parent, None)
         } else {
             // ルートノード以外がオーバーフローした場合は、親ノードへ分割したノードを返す
-            (target, Some((Some(new_parent_value), Some(sibiling))))
+            let ((key, value), sibiling) = target.split(self.order);
+            (target, Some((key, (Some(value), Some(sibiling)))))
+        }
+    }
+
+    /// keyを追加した場合に、ノードの分割が発生するかを木を変更せずに判定します
+    /// 追加先の葉ノードがすでに次数-1個のキーを持っている場合に分割されます
+    pub fn would_split_on(&self, key: &K) -> bool {
+        let Some(mut current) = self.root.as_ref() else {
+            return false;
+        };
+        while current.node_type == NodeType::Regular {
+            match current.find_child(key) {
+                Some(child) => current = child,
+                None => break,
+            }
+        }
+        current.len() + 1 >= self.order
+    }
+
+    /// キーが[low, high]の範囲に含まれる要素数を取得します
+    /// 範囲に完全に含まれる部分木は、ノードが保持するキー数を使って走査せずに数えます
+    pub fn count_range(&self, low: &K, high: &K) -> usize {
+        match self.root {
+            Some(ref root) if low <= high => Self::count_range_rec(root, low, high),
+            _ => 0,
+        }
+    }
+
+    fn count_range_rec(node: &Node<K, V>, low: &K, high: &K) -> usize {
+        let mut count = 0;
+        // 子ノードの前後にある区切りキー (Noneは境界なし)
+        let mut lower: Option<&K> = None;
+        for i in 0..=node.len() {
+            let upper = if i < node.len() { node.key_at(i) } else { None };
+            let child = if i == 0 {
+                node.left_child.as_ref()
+            } else {
+                node.children[i - 1].as_ref()
+            };
+            if let Some(child) = child {
+                let is_inside = lower.is_some_and(|l| low <= l) && upper.is_some_and(|u| u <= high);
+                let is_outside = lower.is_some_and(|l| l > high) || upper.is_some_and(|u| u < low);
+                if is_inside {
+                    count += child.size();
+                } else if !is_outside {
+                    count += Self::count_range_rec(child, low, high);
+                }
+            }
+            if upper.is_some_and(|u| low <= u && u <= high) {
+                count += 1;
+            }
+            lower = upper;
+        }
+        count
+    }
+
+    /// キーが[low, high]の範囲に含まれる値をキーの昇順に取得します
+    /// 区切りキーから範囲外と分かる部分木は辿りません
+    pub fn range(&self, low: &K, high: &K) -> Vec<&V> {
+        let mut values = vec![];
+        if let Some(root) = self.root.as_ref().filter(|_| low <= high) {
+            Self::range_rec(root, low, high, &mut values);
+        }
+        values
+    }
+
+    fn range_rec<'a>(node: &'a Node<K, V>, low: &K, high: &K, values: &mut Vec<&'a V>) {
+        // 子ノードの前後にある区切りキー (Noneは境界なし)
+        let mut lower: Option<&K> = None;
+        for i in 0..=node.len() {
+            let upper = if i < node.len() { node.key_at(i) } else { None };
+            let child = if i == 0 {
+                node.left_child.as_ref()
+            } else {
+                node.children[i - 1].as_ref()
+            };
+            let is_outside = lower.is_some_and(|l| l > high) || upper.is_some_and(|u| u < low);
+            if let Some(child) = child.filter(|_| !is_outside) {
+                Self::range_rec(child, low, high, values);
+            }
+            if upper.is_some_and(|u| low <= u && u <= high) {
+                values.extend(node.values[i].as_ref());
+            }
+            if lower.is_some_and(|l| l > high) {
+                break;
+            }
+            lower = upper;
+        }
+    }
+
+    /// 2つのB木を消費して、すべての値を持つ1つのB木を作成します
+    /// 両方の木を昇順に取り出して併合し、その列から平衡なB木を一括で構築します
+    /// キーが重複する場合はselfの値を残します
+    pub fn merge(self, other: BTree<K, V>) -> BTree<K, V> {
+        let key_of = self.key_of;
+        let order = self.order;
+        let mut left = self.into_sorted().into_iter().peekable();
+        let mut right = other.into_sorted().into_iter().peekable();
+
+        let mut merged: Vec<(K, V)> = vec![];
+        loop {
+            let next = match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) if r.0 < l.0 => right.next(),
+                (Some(_), _) => left.next(),
+                (None, _) => right.next(),
+            };
+            let Some((key, value)) = next else {
+                break;
+            };
+            if merged.last().is_none_or(|(last, _)| *last != key) {
+                merged.push((key, value));
+            }
+        }
+
+        let length = merged.len();
+        let mut height = 0;
+        while order.saturating_pow(height + 1) <= length {
+            height += 1;
+        }
+        let root = (length > 0).then(|| {
+            let mut items = merged.into_iter();
+            Self::build_sorted(&mut items, length, height, true, order)
+        });
+        BTree {
+            root,
+            order,
+            key_of,
+            length: length as u64,
+        }
+    }
+
+    /// B木を消費して、キーと値のペアをキーの昇順で取得します
+    fn into_sorted(self) -> Vec<(K, V)> {
+        let mut sorted = Vec::with_capacity(self.length as usize);
+        if let Some(root) = self.root {
+            Self::into_sorted_rec(*root, &mut sorted);
+        }
+        sorted
+    }
+
+    fn into_sorted_rec(node: Node<K, V>, sorted: &mut Vec<(K, V)>) {
+        if let Some(left) = node.left_child {
+            Self::into_sorted_rec(*left, sorted);
+        }
+        let pairs = node.keys.into_iter().zip(node.values).zip(node.children);
+        for ((key, value), child) in pairs {
+            if let Some(value) = value {
+                sorted.push((key, value));
+            }
+            if let Some(child) = child {
+                Self::into_sorted_rec(*child, sorted);
+            }
         }
     }
 
-    /// B木から値を削除します
-    pub fn remove(&mut self, _key: Key) {
-        todo!();
+    /// 昇順に並んだcount個の要素から、葉までの高さがheightの部分木を構築します
+    ///
+    /// 高さhの部分木が持てるキー数に1を足した値は
+    /// - 最大: order^(h+1) (すべてのノードがorder個の子を持つ場合)
+    /// - 最小: (t+1)^(h+1) (root以外のノードがt+1個の子を持つ場合、t = ceil(order/2)-1)
+    ///
+    /// になるため、子ノード数cは 各子の(キー数+1)がこの範囲に収まるように選び、要素を均等に分配します
+    fn build_sorted(
+        items: &mut impl Iterator<Item = (K, V)>,
+        count: usize,
+        height: u32,
+        is_root: bool,
+        order: usize,
+    ) -> Tree<K, V> {
+        if height == 0 {
+            let (keys, values) = items
+                .take(count)
+                .map(|(key, value)| (key, Some(value)))
+                .unzip();
+            let children = (0..count).map(|_| None).collect();
+            return Node::from_nodes(NodeType::Leaf, None, keys, values, children);
+        }
+
+        let min_children = order.div_ceil(2);
+        let units = count + 1;
+        let max_units = order.saturating_pow(height);
+        let min_units = min_children.saturating_pow(height);
+        let lower = units
+            .div_ceil(max_units)
+            .max(if is_root { 2 } else { min_children });
+        let upper = (units / min_units).min(order);
+        debug_assert!(lower <= upper, "no valid child count for {count} keys");
+        let child_count = lower;
+
+        let mut left_child = None;
+        let mut keys = vec![];
+        let mut values = vec![];
+        let mut children = vec![];
+        for i in 0..child_count {
+            // 子ノードごとに units / child_count (余りは先頭から1ずつ) を割り当てる
+            let child_units = units / child_count + usize::from(i < units % child_count);
+            let child = Self::build_sorted(items, child_units - 1, height - 1, false, order);
+            if i == 0 {
+                left_child = Some(child);
+            } else {
+                children.push(Some(child));
+            }
+            if i + 1 < child_count {
+                let (key, value) = items.next().expect("items must have count elements");
+                keys.push(key);
+                values.push(Some(value));
+            }
+        }
+        Node::from_nodes(NodeType::Regular, left_child, keys, values, children)
+    }
+
+    /// キーに一致する値をB木から削除して、返します
+    /// キーが存在しない場合は何もせずNoneを返します
+    /// 削除によりrootが空になった場合は、唯一の子ノードを新しいrootにします
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let root = self.root.as_mut()?;
+        let removed = root.remove_rec(key, self.order)?;
+        self.length -= 1;
+        if root.is_empty() {
+            self.root = match root.node_type {
+                NodeType::Leaf => None,
+                NodeType::Regular => root.left_child.take(),
+            };
+        }
+        Some(removed)
     }
 
     /// B木から値を取得します
-    pub fn find(&self, key: Key) -> Option<&IoTDevice> {
+    pub fn find(&self, key: &K) -> Option<&V> {
         let root = self.root.as_ref()?;
         let mut current = root;
         loop {
@@ -337,39 +834,211 @@ impl BTree {
         }
     }
 
+    /// キーがB木に含まれているかを判定します
+    pub fn contains(&self, key: &K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// 要素数を取得します
+    pub fn len(&self) -> usize {
+        self.length as usize
+    }
+
+    /// 要素が1つもない場合にtrueを返します
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// B木から値の可変な参照を取得します
+    /// `add_value`で追加した値の場合、キーを導出するフィールドを変更するとキーと一致しなくなるため、
+    /// それ以外のフィールドのみ変更してください
+    pub fn find_mut(&mut self, key: &K) -> Option<&mut V> {
+        let mut current = self.root.as_mut()?;
+        loop {
+            if current.find_value(key).is_some() {
+                return current.find_value_mut(key);
+            }
+            current = current.find_child_mut(key)?.as_mut()?;
+        }
+    }
+
+    /// [デバッグ用] rootからDirectionの順に子ノードを辿り、到達したノードを取得します
+    /// - Direction::Left: 左の子ノード (left_child)
+    /// - Direction::Right(i): i番目の値の右側の子ノード (children\[i\])
+    pub fn node_at_path(&self, path: &[Direction]) -> Option<&Node<K, V>> {
+        let mut current = self.root.as_ref()?;
+        for direction in path {
+            current = match direction {
+                Direction::Left => current.left_child.as_ref()?,
+                Direction::Right(i) => current.children.get(*i)?.as_ref()?,
+            };
+        }
+        Some(current)
+    }
+
     /// B木を走査しますして、各要素に対して関数を適用します
-    pub fn traverse(&self, _callback: impl Fn(&IoTDevice)) {
-        todo!();
+    pub fn traverse(&self, callback: impl Fn(&V)) {
+        self.iter().for_each(callback);
+    }
+
+    /// 値をキーの昇順に走査します
+    /// 値は複製せず、木が保持する値の参照を返します
+    pub fn iter(&self) -> impl Iterator<Item = &V> {
+        InOrder::new(self.root.as_deref()).map(|(_, value)| value)
     }
 
-    /// [デバッグ用] ノード数を取得します
+    /// ノード数を取得します
     pub fn node_count(&self) -> usize {
+        self.shape().1
+    }
+
+    /// 木の段数を取得します。rootだけの木は1、空の木は0です
+    pub fn height(&self) -> usize {
+        self.shape().0
+    }
+
+    /// 段数とノード数を1回の走査でまとめて取得します
+    fn shape(&self) -> (usize, usize) {
+        let mut height = 0;
         let mut count = 0;
-        let root = match self.root {
-            Some(ref node) => node,
-            None => return 0,
-        };
-        let mut stack = vec![root];
-        while let Some(node) = stack.pop() {
+        let mut stack: Vec<(&Node<K, V>, usize)> =
+            self.root.iter().map(|root| (&**root, 1)).collect();
+        while let Some((node, level)) = stack.pop() {
             count += 1;
-            match node.node_type {
-                NodeType::Leaf => {}
-                NodeType::Regular => {
-                    for child in node.children.iter().flatten() {
-                        stack.push(child);
-                    }
+            height = height.max(level);
+            if node.node_type == NodeType::Regular {
+                stack.extend(node.left_child.iter().map(|child| (&**child, level + 1)));
+                for child in node.children.iter().flatten() {
+                    stack.push((child, level + 1));
                 }
             }
         }
-        count
+        (height, count)
+    }
+}
+
+impl<K: Ord + core::fmt::Debug, V> BTree<K, V> {
+    /// B木の条件を満たしているかを検証します
+    /// - 各ノードのキーはソート済みで、親ノードの区切りキーの範囲に収まる
+    /// - root以外のノードは最小で ceil(次数/2)-1 個 (かつ1つ以上)、最大で次数-1個のキーを持つ
+    /// - すべての葉が同じ深さにある
+    /// - 各ノードが保持するキー数が部分木のキー数と一致する
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// B木の条件を検証し、最初に満たしていない条件と該当するノードのキーをErrで返します
+    /// 条件は`is_valid`を参照してください
+    pub fn validate(&self) -> Result<(), String> {
+        let size = match self.root {
+            Some(ref root) => {
+                self.valid_depth(root, None, None, true)?;
+                root.size()
+            }
+            None => 0,
+        };
+        if size as u64 != self.length {
+            return Err(format!(
+                "length is {}, but the tree has {} keys",
+                self.length, size
+            ));
+        }
+        Ok(())
+    }
+
+    /// 部分木がB木の条件を満たす場合は葉までの深さを返します
+    fn valid_depth(
+        &self,
+        node: &Node<K, V>,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        is_root: bool,
+    ) -> Result<usize, String> {
+        if node.keys.len() != node.len() || node.values.iter().any(Option::is_none) {
+            return Err(String::from("node has an empty value"));
+        }
+        let keys = &node.keys;
+        if keys.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(format!("keys are not sorted: {keys:?}"));
+        }
+        let in_range = keys.first().is_none_or(|k| lower.is_none_or(|l| l <= k))
+            && keys.last().is_none_or(|k| upper.is_none_or(|u| k <= u));
+        if !in_range {
+            return Err(format!(
+                "keys {keys:?} are out of the separator range [{lower:?}, {upper:?}]"
+            ));
+        }
+        let min_len = Node::<K, V>::min_len(self.order).max(1);
+        if !is_root && node.len() < min_len {
+            return Err(format!(
+                "node {keys:?} has fewer keys than the minimum {min_len}"
+            ));
+        }
+        if node.is_overflow(self.order) {
+            return Err(format!(
+                "node {keys:?} has more keys than the maximum {}",
+                self.order - 1
+            ));
+        }
+
+        let mut size = node.len();
+        let depth = match node.node_type {
+            NodeType::Leaf => {
+                if node.left_child.is_some() || node.children.iter().any(Option::is_some) {
+                    return Err(format!("leaf {keys:?} has children"));
+                }
+                0
+            }
+            NodeType::Regular => {
+                let missing_child = || format!("regular node {keys:?} is missing a child");
+                if node.children.len() != node.len() {
+                    return Err(missing_child());
+                }
+                let mut depths = vec![];
+                let left = node.left_child.as_ref().ok_or_else(missing_child)?;
+                depths.push(self.valid_depth(left, lower, keys.first(), false)?);
+                size += left.size();
+                for (i, child) in node.children.iter().enumerate() {
+                    let child = child.as_ref().ok_or_else(missing_child)?;
+                    let upper = keys.get(i + 1).or(upper);
+                    depths.push(self.valid_depth(child, Some(&keys[i]), upper, false)?);
+                    size += child.size();
+                }
+                if depths.windows(2).any(|pair| pair[0] != pair[1]) {
+                    return Err(format!(
+                        "leaves under {keys:?} are at different depths: {depths:?}"
+                    ));
+                }
+                depths[0] + 1
+            }
+        };
+        if size != node.size() {
+            return Err(format!(
+                "size of {keys:?} is {}, but its subtree has {size} keys",
+                node.size()
+            ));
+        }
+        Ok(depth)
+    }
+
+    /// [デバッグ用] 要素数・高さ・ノード数・B木の条件を満たしているかを1行で取得します
+    pub fn describe(&self) -> String {
+        format!(
+            "BTree {{ len: {}, height: {}, node_count: {}, valid: {} }}",
+            self.length,
+            self.height(),
+            self.node_count(),
+            self.is_valid()
+        )
     }
 }
 
-impl Default for BTree {
+impl<K: Ord, V> Default for BTree<K, V> {
     fn default() -> Self {
         BTree {
             root: None,
             order: DEFAULT_ORDER,
+            key_of: None,
             length: 0,
         }
     }
@@ -378,20 +1047,21 @@ impl Default for BTree {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::iot::IoTDevice;
 
     mod node {
         use super::*;
 
         #[test]
         fn test_new_leaf() {
-            let leaf = Node::new_leaf();
+            let leaf = Node::<u64, IoTDevice>::new_leaf();
             assert_eq!(leaf.len(), 0);
             assert!(leaf.is_empty());
         }
 
         #[test]
         fn test_new_regular() {
-            let regular = Node::new_regular();
+            let regular = Node::<u64, IoTDevice>::new_regular();
             assert_eq!(regular.len(), 0);
             assert!(regular.is_empty());
         }
@@ -399,7 +1069,7 @@ mod tests {
         #[test]
         fn test_add_key_left() {
             // Arrange
-            let mut leaf = Node::new_leaf();
+            let mut leaf = Node::<u64, IoTDevice>::new_leaf();
             let key = 10;
             let value = (Some(IoTDevice::new(10, "device", "")), None);
             assert!(leaf.add_key(key, value));
@@ -424,7 +1094,7 @@ mod tests {
         #[test]
         fn should_add_key_right() {
             // Arrange
-            let mut leaf = Node::new_leaf();
+            let mut leaf = Node::<u64, IoTDevice>::new_leaf();
             let key = 10;
             let value = (Some(IoTDevice::new(10, "device", "")), None);
             assert!(leaf.add_key(key, value));
@@ -449,7 +1119,7 @@ mod tests {
         #[test]
         fn should_remove_when_node_has_a_device() {
             // Arrange
-            let mut leaf = Node::new_leaf();
+            let mut leaf = Node::<u64, IoTDevice>::new_leaf();
             let key = 10;
             let value = (Some(IoTDevice::new(10, "device", "")), None);
             assert!(leaf.add_key(key, value));
@@ -468,7 +1138,7 @@ mod tests {
         #[test]
         fn should_remove_key_right() {
             // Arrange
-            let mut leaf = Node::new_leaf();
+            let mut leaf = Node::<u64, IoTDevice>::new_leaf();
             leaf.add_key(10, (Some(IoTDevice::new(10, "device", "")), None));
             leaf.add_key(20, (Some(IoTDevice::new(20, "new_device", "")), None));
 
@@ -487,17 +1157,17 @@ mod tests {
         #[should_panic]
         fn should_panic_when_node_is_overflowed_and_split() {
             // Arrange
-            let mut leaf = Node::new_leaf();
+            let mut leaf = Node::<u64, IoTDevice>::new_leaf();
             leaf.add_key(10, (Some(IoTDevice::new(10, "device", "")), None));
             leaf.add_key(20, (Some(IoTDevice::new(20, "new_device", "")), None));
             leaf.add_key(30, (Some(IoTDevice::new(30, "new_device", "")), None));
             leaf.add_key(40, (Some(IoTDevice::new(40, "new_device", "")), None));
 
             // Act
-            let (orphan, new_node) = leaf.split();
+            let (orphan, new_node) = leaf.split(DEFAULT_ORDER);
 
             // Assert
-            assert_eq!(orphan, IoTDevice::new(20, "new_device", ""));
+            assert_eq!(orphan, (20, IoTDevice::new(20, "new_device", "")));
             assert_eq!(new_node.len(), 1);
             assert_eq!(
                 new_node.values,
@@ -508,17 +1178,17 @@ mod tests {
         #[test]
         fn should_be_split_when_overflowed() {
             // Arrange
-            let mut leaf = Node::new_leaf();
+            let mut leaf = Node::<u64, IoTDevice>::new_leaf();
             leaf.add_key(10, (Some(IoTDevice::new(10, "device", "")), None));
             leaf.add_key(20, (Some(IoTDevice::new(20, "new_device", "")), None));
             leaf.add_key(30, (Some(IoTDevice::new(30, "new_device", "")), None));
             leaf.add_key(40, (Some(IoTDevice::new(40, "new_device", "")), None));
 
             // Act
-            let (orphan, new_node) = leaf.split();
+            let (orphan, new_node) = leaf.split(DEFAULT_ORDER);
 
             // Assert
-            assert_eq!(orphan, IoTDevice::new(30, "new_device", ""));
+            assert_eq!(orphan, (30, IoTDevice::new(30, "new_device", "")));
             assert_eq!(new_node.len(), 1);
             assert_eq!(
                 new_node.values,
@@ -526,15 +1196,42 @@ mod tests {
             );
         }
 
+        #[test]
+        fn should_split_into_left_median_and_right() {
+            // Arrange
+            let mut node = Node::<u64, IoTDevice>::new_leaf();
+            for id in [10, 20, 30, 40] {
+                node.add_key(id, (Some(IoTDevice::new(id, "device", "")), None));
+            }
+
+            // Act
+            let (left, median, right) = node.split_into();
+
+            // Assert
+            assert_eq!(
+                left.values,
+                vec![
+                    Some(IoTDevice::new(10, "device", "")),
+                    Some(IoTDevice::new(20, "device", ""))
+                ]
+            );
+            assert_eq!(median, (30, IoTDevice::new(30, "device", "")));
+            assert_eq!(right.values, vec![Some(IoTDevice::new(40, "device", ""))]);
+            assert_eq!(left.size(), 2);
+            assert_eq!(right.size(), 1);
+            assert!(node.is_empty());
+            assert_eq!(node.size(), 0);
+        }
+
         #[test]
         fn should_find_closest_mutable_child() {
             // Arrange
-            let mut node = Node::new_leaf();
+            let mut node = Node::<u64, IoTDevice>::new_leaf();
             node.add_key(10, (Some(IoTDevice::new(10, "device", "")), None));
             node.add_key(20, (Some(IoTDevice::new(20, "new_device", "")), None));
 
             // Act
-            let child = node.find_child_mut(15);
+            let child = node.find_child_mut(&15);
 
             // Assert
             assert_eq!(child, Some(&mut None));
@@ -544,10 +1241,12 @@ mod tests {
     mod btree {
         use super::*;
 
+        type DeviceTree = BTree<u64, IoTDevice>;
+
         #[test]
         fn should_add_value_when_btree_is_empty() {
             // Arrange
-            let mut btree = BTree::default();
+            let mut btree = DeviceTree::default();
             let key = 10;
             let device1 = IoTDevice::new(10, "device", "");
 
@@ -556,13 +1255,13 @@ mod tests {
 
             // Assert
             assert_eq!(btree.length, 1);
-            assert_eq!(btree.find(key), Some(&device1));
+            assert_eq!(btree.find(&key), Some(&device1));
         }
 
         #[test]
         fn should_add_value_when_btree_has_a_value() {
             // Arrange
-            let mut btree = BTree::default();
+            let mut btree = DeviceTree::default();
             let device1 = IoTDevice::new(10, "device", "");
             let device2 = IoTDevice::new(20, "new_device", "");
             btree.add(10, device1.clone());
@@ -572,15 +1271,15 @@ mod tests {
 
             // Assert
             assert_eq!(btree.length, 2);
-            assert_eq!(btree.find(10), Some(&device1));
-            assert_eq!(btree.find(20), Some(&device2));
+            assert_eq!(btree.find(&10), Some(&device1));
+            assert_eq!(btree.find(&20), Some(&device2));
             assert_eq!(btree.node_count(), 1);
         }
 
         #[test]
         fn should_add_value_when_root_need_to_be_split() {
             // Arrange
-            let mut btree = BTree::default();
+            let mut btree = DeviceTree::default();
             let device1 = IoTDevice::new(10, "device", "");
             let device2 = IoTDevice::new(20, "new_device", "");
             let device3 = IoTDevice::new(30, "new_device", "");
@@ -592,10 +1291,690 @@ mod tests {
 
             // Assert
             assert_eq!(btree.length, 3);
-            assert_eq!(btree.find(10), Some(&device1));
-            assert_eq!(btree.find(20), Some(&device2));
-            assert_eq!(btree.find(30), Some(&device3));
-            // assert_eq!(btree.node_count(), 3);
+            assert_eq!(btree.find(&10), Some(&device1));
+            assert_eq!(btree.find(&20), Some(&device2));
+            assert_eq!(btree.find(&30), Some(&device3));
+            assert_eq!(btree.node_count(), 3);
+        }
+
+        #[test]
+        fn should_find_every_value_when_sequential_keys_are_added() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+
+            // Act
+            for id in 1..=50 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Assert
+            assert_eq!(btree.length, 50);
+            assert!(btree.is_valid());
+            assert_eq!(
+                btree.node_at_path(&[]).unwrap().node_type,
+                NodeType::Regular
+            );
+            for id in 1..=50 {
+                assert_eq!(btree.find(&id), Some(&IoTDevice::new(id, "device", "")));
+            }
+            assert_eq!(btree.find(&0), None);
+            assert_eq!(btree.find(&51), None);
+        }
+
+        #[test]
+        fn should_find_every_value_when_keys_are_added_in_any_order() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+
+            // Act
+            for id in 1..=100 {
+                let key = id * 37 % 101;
+                btree.add(key, IoTDevice::new(key, "device", ""));
+            }
+
+            // Assert
+            assert_eq!(btree.length, 100);
+            assert!(btree.is_valid());
+            for id in 1..=100 {
+                assert_eq!(btree.find(&id).map(|d| d.numeriacl_id), Some(id));
+            }
+        }
+
+        #[test]
+        fn should_split_nodes_by_order_when_order_is_3() {
+            // Arrange
+            let mut btree = DeviceTree::with_order(3);
+
+            // Act
+            for id in 1..=3 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Assert
+            assert!(btree.is_valid());
+            assert_eq!(btree.node_count(), 3);
+            assert_eq!(btree.node_at_path(&[]).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn should_split_nodes_by_order_when_order_is_4() {
+            // Arrange
+            let mut btree = DeviceTree::with_order(4);
+            for id in 1..=3 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+            assert_eq!(btree.node_count(), 1);
+            assert!(btree.would_split_on(&4));
+
+            // Act
+            btree.add(4, IoTDevice::new(4, "device", ""));
+
+            // Assert
+            assert!(btree.is_valid());
+            assert_eq!(btree.node_count(), 3);
+            assert_eq!(btree.node_at_path(&[]).unwrap().key_at(0), Some(&3));
+            assert_eq!(btree.node_at_path(&[Direction::Left]).unwrap().len(), 2);
+            assert_eq!(btree.node_at_path(&[Direction::Right(0)]).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn should_split_child_by_order_when_order_is_4() {
+            // Arrange
+            let mut btree = DeviceTree::with_order(4);
+            for id in 1..=6 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+            // root: [3], children: [1, 2], [4, 5, 6]
+            assert_eq!(btree.node_count(), 3);
+
+            // Act
+            btree.add(7, IoTDevice::new(7, "device", ""));
+
+            // Assert
+            // root: [3, 6], children: [1, 2], [4, 5], [7]
+            let root = btree.node_at_path(&[]).unwrap();
+            assert!(btree.is_valid());
+            assert_eq!(root.keys, vec![3, 6]);
+            assert_eq!(btree.node_at_path(&[Direction::Right(0)]).unwrap().len(), 2);
+            assert_eq!(btree.node_at_path(&[Direction::Right(1)]).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn should_split_nodes_by_order_when_order_is_5() {
+            // Arrange
+            let mut btree = DeviceTree::with_order(5);
+            for id in 1..=4 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+            assert_eq!(btree.node_count(), 1);
+
+            // Act
+            btree.add(5, IoTDevice::new(5, "device", ""));
+
+            // Assert
+            assert!(btree.is_valid());
+            assert_eq!(btree.node_count(), 3);
+            assert_eq!(btree.node_at_path(&[]).unwrap().len(), 1);
+            assert_eq!(btree.node_at_path(&[Direction::Left]).unwrap().len(), 2);
+            assert_eq!(btree.node_at_path(&[Direction::Right(0)]).unwrap().len(), 2);
+        }
+
+        #[test]
+        fn should_find_every_value_for_each_order() {
+            let mut heights = vec![];
+            for order in [3, 4, 5, 8] {
+                // Arrange
+                let mut btree = DeviceTree::with_order(order);
+
+                // Act
+                for id in 1..=100 {
+                    let key = id * 37 % 101;
+                    btree.add(key, IoTDevice::new(key, "device", ""));
+                }
+
+                // Assert
+                assert!(btree.is_valid(), "order {order}: {}", btree.describe());
+                assert!((1..=100).all(|id| btree.find(&id).is_some()));
+                heights.push(btree.height());
+            }
+            // 次数が大きいほど木は低くなる
+            assert!(
+                heights.windows(2).all(|pair| pair[0] >= pair[1]),
+                "{heights:?}"
+            );
+        }
+
+        #[test]
+        #[should_panic(expected = "order must be at least 3")]
+        fn should_panic_when_order_is_too_small() {
+            DeviceTree::with_order(2);
+        }
+
+        #[test]
+        fn should_remove_leaf_values() {
+            // Arrange
+            let mut btree = btree_of(1..=20, "device");
+            assert!(btree.height() >= 3);
+
+            // Act
+            let removed = btree.remove(&20);
+            let removed_again = btree.remove(&20);
+
+            // Assert
+            assert_eq!(removed.map(|d| d.numeriacl_id), Some(20));
+            assert_eq!(removed_again, None);
+            assert_eq!(btree.length, 19);
+            assert!(btree.is_valid(), "{}", btree.describe());
+            assert_eq!(btree.find(&20), None);
+            assert!((1..=19).all(|id| btree.find(&id).is_some()));
+        }
+
+        #[test]
+        fn should_remove_value_in_regular_node() {
+            // Arrange
+            let mut btree = btree_of(1..=20, "device");
+            let root = btree.node_at_path(&[]).unwrap();
+            assert_eq!(root.node_type, NodeType::Regular);
+            let key = root.key_at(0).copied().unwrap();
+
+            // Act
+            let removed = btree.remove(&key);
+
+            // Assert
+            assert_eq!(removed.map(|d| d.numeriacl_id), Some(key));
+            assert_eq!(btree.length, 19);
+            assert!(btree.is_valid(), "{}", btree.describe());
+            assert_eq!(btree.find(&key), None);
+            assert!((1..=20)
+                .filter(|&id| id != key)
+                .all(|id| btree.find(&id).is_some()));
+        }
+
+        #[test]
+        fn should_merge_nodes_when_values_are_removed() {
+            // Arrange
+            let mut btree = btree_of(1..=20, "device");
+            let node_count = btree.node_count();
+
+            // Act
+            for id in 1..=10 {
+                assert!(btree.remove(&id).is_some());
+                assert!(
+                    btree.is_valid(),
+                    "after removing {id}: {}",
+                    btree.describe()
+                );
+            }
+
+            // Assert
+            assert!(btree.node_count() < node_count);
+            assert_eq!(btree.length, 10);
+            assert!((1..=10).all(|id| btree.find(&id).is_none()));
+            assert!((11..=20).all(|id| btree.find(&id).is_some()));
+        }
+
+        #[test]
+        fn should_remove_every_value_for_each_order() {
+            for order in [3, 4, 5, 8] {
+                // Arrange
+                let mut btree = DeviceTree::with_order(order);
+                for id in 1..=100 {
+                    btree.add(id, IoTDevice::new(id, "device", ""));
+                }
+
+                // Act & Assert
+                for id in 1..=100 {
+                    let key = id * 37 % 101;
+                    assert_eq!(btree.remove(&key).map(|d| d.numeriacl_id), Some(key));
+                    assert!(btree.is_valid(), "order {order}: {}", btree.describe());
+                }
+                assert_eq!(btree.length, 0);
+                assert_eq!(btree.node_count(), 0);
+            }
+        }
+
+        #[test]
+        fn should_not_panic_when_removing_from_empty_btree() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+
+            // Act
+            let removed = btree.remove(&10);
+
+            // Assert
+            assert_eq!(removed, None);
+            assert_eq!(btree.length, 0);
+        }
+
+        #[test]
+        fn should_iterate_values_in_key_order() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            for id in 1..=50 {
+                let key = id * 37 % 51;
+                btree.add(key, IoTDevice::new(key, "device", ""));
+            }
+
+            // Act
+            let keys = btree.iter().map(|d| d.numeriacl_id).collect::<Vec<_>>();
+
+            // Assert
+            assert_eq!(keys, (1..=50).collect::<Vec<_>>());
+            assert_eq!(
+                btree
+                    .iter()
+                    .filter(|d| d.numeriacl_id % 10 == 0)
+                    .take(3)
+                    .count(),
+                3
+            );
+            assert_eq!(DeviceTree::default().iter().next(), None);
+        }
+
+        #[test]
+        fn should_traverse_values_in_key_order() {
+            // Arrange
+            let btree = btree_of([3, 1, 2], "device");
+            let visited = core::cell::RefCell::new(vec![]);
+
+            // Act
+            btree.traverse(|d| visited.borrow_mut().push(d.numeriacl_id));
+
+            // Assert
+            assert_eq!(visited.into_inner(), vec![1, 2, 3]);
+        }
+
+        #[test]
+        fn should_update_value_through_find_mut() {
+            // Arrange
+            let mut btree = btree_of(1..=20, "device");
+
+            // Act
+            let device = btree.find_mut(&15).unwrap();
+            device.address = String::from("updated");
+            let missing = btree.find_mut(&21);
+
+            // Assert
+            assert_eq!(missing, None);
+            assert_eq!(btree.find(&15).unwrap().address, "updated");
+            assert_eq!(btree.find(&14).unwrap().address, "device");
+            assert!(btree.is_valid());
+        }
+
+        #[test]
+        fn should_get_values_in_range() {
+            // Arrange
+            let btree = btree_of(1..=30, "device");
+
+            // Act
+            let values = btree.range(&10, &20);
+
+            // Assert
+            assert_eq!(
+                values.iter().map(|d| d.numeriacl_id).collect::<Vec<_>>(),
+                (10..=20).collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn should_get_values_in_range_at_edges() {
+            // Arrange
+            let btree = btree_of(1..=30, "device");
+            let separator = btree.node_at_path(&[]).unwrap().key_at(0).copied().unwrap();
+            let ids =
+                |values: Vec<&IoTDevice>| values.iter().map(|d| d.numeriacl_id).collect::<Vec<_>>();
+
+            // Act & Assert
+            assert!(btree.range(&20, &10).is_empty());
+            assert!(btree.range(&31, &40).is_empty());
+            assert_eq!(ids(btree.range(&0, &3)), vec![1, 2, 3]);
+            assert_eq!(ids(btree.range(&29, &100)), vec![29, 30]);
+            assert_eq!(ids(btree.range(&separator, &separator)), vec![separator]);
+            assert_eq!(
+                ids(btree.range(&(separator - 1), &(separator + 1))),
+                vec![separator - 1, separator, separator + 1]
+            );
+            assert_eq!(btree.range(&1, &30).len(), 30);
+        }
+
+        #[test]
+        fn should_count_levels_and_nodes() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            assert_eq!((btree.height(), btree.node_count()), (0, 0));
+            btree.add(1, IoTDevice::new(1, "device", ""));
+            assert_eq!((btree.height(), btree.node_count()), (1, 1));
+
+            // Act
+            // 3で葉が分割されて新しいrootができ、5で右の葉がもう一度分割される
+            for id in 2..=5 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Assert
+            assert_eq!(btree.height(), 2);
+            assert_eq!(btree.node_count(), 4);
+            assert!(btree.is_valid());
+        }
+
+        #[test]
+        fn should_validate_btree_built_by_add_and_remove() {
+            // Arrange
+            let mut btree = DeviceTree::with_order(4);
+
+            // Act & Assert
+            assert_eq!(btree.validate(), Ok(()));
+            for id in 1..=200 {
+                let key = id * 71 % 211;
+                btree.add(key, IoTDevice::new(key, "device", ""));
+                assert_eq!(btree.validate(), Ok(()));
+            }
+            for id in 1..=150 {
+                btree.remove(&(id * 53 % 211));
+                assert_eq!(btree.validate(), Ok(()));
+            }
+        }
+
+        #[test]
+        fn should_report_node_keys_when_btree_is_broken() {
+            // Arrange
+            let mut missing_child = btree_of(1..=3, "device");
+            let mut unsorted = btree_of([1, 2], "device");
+            let mut wrong_length = btree_of(1..=3, "device");
+
+            // Act
+            missing_child.root.as_mut().unwrap().left_child = None;
+            unsorted.root.as_mut().unwrap().keys.swap(0, 1);
+            wrong_length.length = 4;
+
+            // Assert
+            assert_eq!(
+                missing_child.validate(),
+                Err(String::from("regular node [2] is missing a child"))
+            );
+            assert_eq!(
+                unsorted.validate(),
+                Err(String::from("keys are not sorted: [2, 1]"))
+            );
+            assert_eq!(
+                wrong_length.validate(),
+                Err(String::from("length is 4, but the tree has 3 keys"))
+            );
+        }
+
+        #[test]
+        fn should_describe_populated_btree() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            for id in 1..=10 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Act
+            let summary = btree.describe();
+
+            // Assert
+            assert!(btree.is_valid());
+            assert!(summary.contains("len: 10"), "{summary}");
+            assert!(summary.contains("height: 3, node_count: 8"), "{summary}");
+            assert!(summary.contains("valid: true"), "{summary}");
+            assert_eq!(
+                DeviceTree::default().describe(),
+                "BTree { len: 0, height: 0, node_count: 0, valid: true }"
+            );
+        }
+
+        #[test]
+        fn should_predict_split_when_target_leaf_is_full() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            for id in [10, 20, 30, 40] {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+            // root: [20], leaves: [10], [30, 40]
+
+            // Act
+            let full = btree.would_split_on(&35);
+            let has_room = btree.would_split_on(&5);
+
+            // Assert
+            assert!(full);
+            assert!(!has_room);
+            assert_eq!(btree.length, 4);
+            assert_eq!(btree.node_count(), 3);
+        }
+
+        #[test]
+        fn should_not_predict_split_when_btree_is_empty() {
+            // Arrange
+            let btree = DeviceTree::default();
+
+            // Act
+            let result = btree.would_split_on(&10);
+
+            // Assert
+            assert!(!result);
+        }
+
+        fn btree_of(ids: impl IntoIterator<Item = u64>, address: &str) -> DeviceTree {
+            let mut btree = DeviceTree::default();
+            for id in ids {
+                btree.add(id, IoTDevice::new(id, address, ""));
+            }
+            btree
+        }
+
+        #[test]
+        fn should_merge_disjoint_btrees() {
+            // Arrange
+            let a = btree_of((1..=20).filter(|id| id % 2 == 0), "a");
+            let b = btree_of((1..=20).filter(|id| id % 2 == 1), "b");
+
+            // Act
+            let merged = a.merge(b);
+
+            // Assert
+            assert!(merged.is_valid(), "{}", merged.describe());
+            assert_eq!(merged.length, 20);
+            for id in 1..=20 {
+                assert_eq!(merged.find(&id).map(|d| d.numeriacl_id), Some(id));
+            }
+            assert_eq!(merged.count_range(&1, &20), 20);
+        }
+
+        #[test]
+        fn should_merge_overlapping_btrees_keeping_self_values() {
+            // Arrange
+            let a = btree_of(1..=10, "a");
+            let b = btree_of(6..=15, "b");
+
+            // Act
+            let mut merged = a.merge(b);
+
+            // Assert
+            assert!(merged.is_valid(), "{}", merged.describe());
+            assert_eq!(merged.length, 15);
+            assert_eq!(merged.find(&8).unwrap().address, "a");
+            assert_eq!(merged.find(&13).unwrap().address, "b");
+            assert_eq!(merged.count_range(&0, &100), 15);
+            for id in 16..=30 {
+                merged.add(id, IoTDevice::new(id, "c", ""));
+            }
+            assert!(merged.is_valid(), "{}", merged.describe());
+        }
+
+        #[test]
+        fn should_build_valid_btree_for_any_merged_length() {
+            for n in 0..=100 {
+                // Arrange
+                let a = btree_of(1..=n, "a");
+
+                // Act
+                let merged = a.merge(DeviceTree::default());
+
+                // Assert
+                assert!(merged.is_valid(), "n = {n}: {}", merged.describe());
+                assert_eq!(merged.length, n);
+            }
+        }
+
+        #[test]
+        fn should_navigate_to_node_after_split() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            for id in [10, 20, 30, 40] {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Act
+            btree.add(50, IoTDevice::new(50, "device", ""));
+
+            // Assert
+            // root: [20, 40], children: [10], [30], [50]
+            let keys = |node: &Node<u64, IoTDevice>| {
+                (0..node.len())
+                    .map(|i| node.key_at(i).copied().unwrap())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(keys(btree.node_at_path(&[]).unwrap()), vec![20, 40]);
+            assert_eq!(
+                keys(btree.node_at_path(&[Direction::Left]).unwrap()),
+                vec![10]
+            );
+            assert_eq!(
+                keys(btree.node_at_path(&[Direction::Right(0)]).unwrap()),
+                vec![30]
+            );
+            assert_eq!(
+                keys(btree.node_at_path(&[Direction::Right(1)]).unwrap()),
+                vec![50]
+            );
+            assert!(btree.node_at_path(&[Direction::Right(2)]).is_none());
+            assert!(btree
+                .node_at_path(&[Direction::Left, Direction::Left])
+                .is_none());
+        }
+
+        #[test]
+        fn should_try_add_value_when_key_does_not_exist() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            btree.add(10, IoTDevice::new(10, "device", ""));
+
+            // Act
+            let result = btree.try_add(20, IoTDevice::new(20, "new_device", ""));
+
+            // Assert
+            assert_eq!(result, Ok(()));
+            assert_eq!(btree.length, 2);
+            assert_eq!(btree.find(&20), Some(&IoTDevice::new(20, "new_device", "")));
+        }
+
+        #[test]
+        fn should_reject_value_when_key_already_exists() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            for id in 1..=10 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Act
+            let result = btree.try_add(5, IoTDevice::new(5, "duplicated", ""));
+
+            // Assert
+            assert_eq!(result.unwrap_err().address, "duplicated");
+            assert_eq!(btree.length, 10);
+            assert_eq!(btree.find(&5).unwrap().address, "device");
+        }
+
+        #[test]
+        fn should_find_value_by_derived_key() {
+            // Arrange
+            let mut btree = DeviceTree::with_key_fn(|device| device.path.len() as u64);
+            for (id, path) in [
+                (30, "a"),
+                (10, "abc"),
+                (20, "ab"),
+                (40, "abcde"),
+                (50, "abcd"),
+            ] {
+                btree.add_value(IoTDevice::new(id, "device", path));
+            }
+
+            // Act
+            let found = btree.find(&3);
+
+            // Assert
+            assert_eq!(btree.length, 5);
+            assert_eq!(found, Some(&IoTDevice::new(10, "device", "abc")));
+            assert_eq!(btree.find(&5).map(|d| d.numeriacl_id), Some(40));
+            assert_eq!(btree.find(&10), None);
+            assert_eq!(btree.count_range(&2, &4), 3);
+        }
+
+        #[test]
+        fn should_report_membership_and_length() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            assert!(btree.is_empty());
+            for id in [5, 1, 9, 3, 7] {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Act
+            btree.remove(&9);
+
+            // Assert
+            assert_eq!(btree.len(), 4);
+            assert!(!btree.is_empty());
+            assert!(btree.contains(&1));
+            assert!(btree.contains(&7));
+            assert!(!btree.contains(&9));
+            assert!(!btree.contains(&4));
+        }
+
+        #[test]
+        fn should_find_value_when_key_and_value_are_not_devices() {
+            // Arrange
+            let mut btree: BTree<String, usize> = BTree::default();
+            let words = ["pear", "apple", "fig", "banana", "cherry", "date", "grape"];
+            for (i, word) in words.iter().enumerate() {
+                btree.add(String::from(*word), i);
+            }
+
+            // Act
+            let found = btree.find(&String::from("cherry"));
+
+            // Assert
+            assert_eq!(found, Some(&4));
+            assert_eq!(btree.find(&String::from("kiwi")), None);
+            assert_eq!(
+                btree.iter().copied().collect::<Vec<_>>(),
+                vec![1, 3, 4, 5, 2, 6, 0]
+            );
+            assert!(btree.is_valid(), "{}", btree.describe());
+        }
+
+        #[test]
+        fn should_count_keys_in_range() {
+            // Arrange
+            let mut btree = DeviceTree::default();
+            for id in 1..=100 {
+                btree.add(id, IoTDevice::new(id, "device", ""));
+            }
+
+            // Act
+            let count = btree.count_range(&25, &75);
+
+            // Assert
+            assert_eq!(count, 51);
+            assert_eq!(btree.count_range(&1, &100), 100);
+            assert_eq!(btree.count_range(&0, &0), 0);
+            assert_eq!(btree.count_range(&100, &200), 1);
+            assert_eq!(btree.count_range(&75, &25), 0);
+            for (low, high) in [(2, 3), (10, 33), (41, 41), (50, 99)] {
+                assert_eq!(btree.count_range(&low, &high), (high - low + 1) as usize);
+            }
         }
     }
 }
@@ -1,57 +1,434 @@
-use crate::iot::MessageNotification;
+use crate::iot::{IoTDevice, MessageNotification};
+use alloc::collections::BTreeMap;
+use alloc::{format, string::String, vec::Vec};
+
+/// 空のキューから要素を取り出そうとした場合のエラー
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmptyQueue;
+
+impl core::fmt::Display for EmptyQueue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "queue is empty")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EmptyQueue {}
 
 #[derive(Default)]
 pub struct MessageChecker {
     heap: HeapTree<MessageNotification>,
+    median: RunningMedian,
+    /// 保持する通知の最大数。Noneの場合は無制限
+    capacity: Option<usize>,
 }
 
 impl MessageChecker {
+    /// 通知の列からキューを一括で構築します
+    /// 1件ずつaddする場合のO(n log n)ではなく、O(n)でヒープを構築します
+    pub fn from_vec(notifications: Vec<MessageNotification>) -> Self {
+        let mut checker = MessageChecker {
+            heap: HeapTree::from_vec(notifications),
+            median: RunningMedian::default(),
+            capacity: None,
+        };
+        checker.rebuild_median();
+        checker
+    }
+
+    /// メッセージ数の少ない通知から取り出すキューを作成します
+    pub fn min() -> Self {
+        MessageChecker {
+            heap: HeapTree::new_min(),
+            median: RunningMedian::default(),
+            capacity: None,
+        }
+    }
+
+    /// 内部のヒープを、各ノードがd個の子ノードを持つd分ヒープにしたキューを作成します
+    /// 要素数が多い場合、dを大きくすると木が浅くなりバブルアップが速くなります
+    ///
+    /// # Panics
+    /// dが2未満の場合
+    pub fn with_arity(d: usize) -> Self {
+        MessageChecker {
+            heap: HeapTree::with_arity(d),
+            ..Default::default()
+        }
+    }
+
+    /// メッセージ数の多い通知を最大k件だけ保持するキューを作成します
+    /// 内部ではk件の最小ヒープを使い、k件を超えて追加すると
+    /// もっともメッセージ数の少ない通知を捨てます。
+    /// そのため`pop`と`peek`は、保持している中でもっともメッセージ数の少ない通知を返します
+    pub fn with_capacity(k: usize) -> Self {
+        MessageChecker {
+            capacity: Some(k),
+            ..MessageChecker::min()
+        }
+    }
+
     pub fn length(&self) -> usize {
         self.heap.length()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.length() == 0
+    }
+
+    /// キュー内の通知を取り出さずに走査するイテレータを取得します
+    /// 順番はヒープ内部の配列の順番で、優先度順にはなりません
+    pub fn iter(&self) -> impl Iterator<Item = &MessageNotification> {
+        self.heap.iter()
+    }
+
+    /// キュー内のすべての通知のメッセージ数の合計を、通知を取り出さずに取得します
+    pub fn total_messages(&self) -> u64 {
+        self.heap
+            .iter()
+            .map(|notification| notification.message_count)
+            .sum()
+    }
+
     pub fn add(&mut self, notification: MessageNotification) {
+        if self
+            .capacity
+            .is_some_and(|capacity| self.length() >= capacity)
+        {
+            // 保持している最小の通知よりメッセージ数が多い場合のみ入れ替える
+            if self.heap.peek().is_none_or(|min| min >= &notification) {
+                return;
+            }
+            self.pop();
+        }
+        self.median.add(notification.message_count);
         self.heap.add(notification);
     }
 
     pub fn pop(&mut self) -> Option<MessageNotification> {
-        self.heap.pop()
+        let notification = self.heap.pop()?;
+        self.median.remove(notification.message_count);
+        Some(notification)
+    }
+
+    /// otherの通知をすべて取り込みます
+    /// 取り出す順番はselfの設定 (`default`または`min`) に従います
+    pub fn merge(&mut self, other: MessageChecker) {
+        for notification in other.heap.iter() {
+            self.median.add(notification.message_count);
+        }
+        self.heap.merge(other.heap);
+        if let Some(capacity) = self.capacity {
+            while self.length() > capacity {
+                self.pop();
+            }
+        }
+    }
+
+    /// キューを消費して、通知を優先度の高い順に並べたVecを取得します
+    pub fn into_sorted_vec(self) -> Vec<MessageNotification> {
+        self.heap.into_sorted_vec()
+    }
+
+    /// 通知を優先度の高い順に取り出すイテレータを取得します
+    /// 途中で走査をやめた場合も、キューは空になります
+    pub fn drain(&mut self) -> impl Iterator<Item = MessageNotification> + '_ {
+        MessageDrain {
+            notifications: self.heap.drain(),
+            median: &mut self.median,
+        }
+    }
+
+    /// キューを消費して、通知をメッセージ数の多い順に並べたVecを取得します
+    /// `with_capacity(k)`で作成した場合は、追加した中でメッセージ数の多いk件になります
+    pub fn into_top_k(self) -> Vec<MessageNotification> {
+        let is_min = self.heap.min;
+        let mut sorted = self.into_sorted_vec();
+        if is_min {
+            sorted.reverse();
+        }
+        sorted
+    }
+
+    /// デバイスの通知のメッセージ数をnew_countに更新し、ヒープを再構築します
+    /// デバイスの通知が見つからない場合はfalseを返します
+    pub fn update_priority(&mut self, device: &IoTDevice, new_count: u64) -> bool {
+        let mut old_count = None;
+        self.heap.update_where(
+            |notification| &notification.device == device,
+            |notification| {
+                old_count = Some(notification.message_count);
+                notification.message_count = new_count;
+            },
+        );
+        let Some(old_count) = old_count else {
+            return false;
+        };
+        self.median.remove(old_count);
+        self.median.add(new_count);
+        true
+    }
+
+    /// もっとも優先度の高い通知 (`min`で作成した場合はもっともメッセージ数の少ない通知) を
+    /// 取り出さずに参照します
+    pub fn peek(&self) -> Option<&MessageNotification> {
+        self.heap.peek()
+    }
+
+    /// キュー内の通知のメッセージ数の中央値を取得します
+    /// 要素数が偶数の場合は、小さい方の中央値を返します
+    ///
+    /// 名前に反して値は近似ではなく正確な中央値です。
+    /// 2つのヒープでは先頭以外の値を取り除けないため、小さい方と大きい方の半分を2つの順序付き多重集合 (BTreeMap) で保持しています
+    pub fn approx_median(&self) -> Option<u64> {
+        self.median.median()
+    }
+
+    /// `pop`と同じく、もっとも優先度の高い通知を取り出します
+    /// (`min`や`with_capacity`で作成した場合はもっともメッセージ数の少ない通知)
+    /// キューが空の場合は`EmptyQueue`を返します
+    pub fn pop_expect(&mut self) -> Result<MessageNotification, EmptyQueue> {
+        self.pop().ok_or(EmptyQueue)
+    }
+
+    /// デバイスIDをbucketごとに区切り、区間ごとのメッセージ数の合計を取得します
+    /// キーは`device_id / bucket`になります
+    ///
+    /// # Panics
+    /// bucketが0の場合
+    pub fn group_by_range(&self, bucket: u64) -> BTreeMap<u64, u64> {
+        assert!(bucket > 0, "bucket must be greater than 0");
+        let mut groups = BTreeMap::new();
+        for notification in self.heap.iter() {
+            *groups
+                .entry(notification.device.numeriacl_id / bucket)
+                .or_insert(0) += notification.message_count;
+        }
+        groups
+    }
+
+    /// すべての通知のメッセージ数にfactorを掛けて (小数点以下切り捨て)、優先度を下げます
+    /// factorは0以上1以下に丸め、NaNは0として扱います。factorが1の場合は何もしません
+    pub fn decay(&mut self, factor: f64) {
+        let factor = if factor.is_nan() {
+            0.0
+        } else {
+            factor.clamp(0.0, 1.0)
+        };
+        if factor == 1.0 {
+            return;
+        }
+        self.heap.update_all(|notification| {
+            notification.message_count = Self::scale_down(notification.message_count, factor);
+        });
+        self.rebuild_median();
+    }
+
+    /// countに0以上1未満のfactorを掛け、小数点以下を切り捨てた値を返します
+    /// f64の積では2^53を超える値の精度が落ちるため、factorを仮数と指数に分解してu128の整数演算で計算します
+    fn scale_down(count: u64, factor: f64) -> u64 {
+        let bits = factor.to_bits();
+        let biased_exponent = (bits >> 52) & 0x7ff;
+        let fraction = bits & ((1 << 52) - 1);
+        // factor = mantissa * 2^-shift。正規化数は仮数に暗黙の1を補う
+        let (mantissa, shift) = if biased_exponent == 0 {
+            (fraction, 1074)
+        } else {
+            (fraction | (1 << 52), 1075 - biased_exponent)
+        };
+        if shift >= 128 {
+            return 0;
+        }
+        ((u128::from(count) * u128::from(mantissa)) >> shift) as u64
+    }
+
+    /// メッセージ数が0になった通知を取り除き、取り除いた数を返します
+    pub fn drop_zero_counts(&mut self) -> usize {
+        let before = self.length();
+        self.heap
+            .retain(|notification| notification.message_count > 0);
+        self.rebuild_median();
+        before - self.length()
+    }
+
+    fn rebuild_median(&mut self) {
+        self.median = RunningMedian::default();
+        for notification in self.heap.iter() {
+            self.median.add(notification.message_count);
+        }
+    }
+
+    /// [デバッグ用] キューの状態を1行で取得します
+    pub fn describe(&self) -> String {
+        self.heap.describe()
+    }
+}
+
+/// 2つの多重集合で中央値を管理する構造体
+/// - lower: 小さい方の半分。最大値が中央値になる
+/// - upper: 大きい方の半分
+///
+/// lowerの要素数はupperと同じか、1つだけ多い状態を保ちます。
+/// 値ごとの個数をBTreeMapで保持するため、最小値・最大値・任意の値をその場で取り除けます
+#[derive(Debug, Default)]
+struct RunningMedian {
+    lower: BTreeMap<u64, usize>,
+    lower_len: usize,
+    upper: BTreeMap<u64, usize>,
+    upper_len: usize,
+}
+
+impl RunningMedian {
+    fn add(&mut self, v: u64) {
+        match self.median() {
+            Some(median) if median < v => {
+                Self::insert(&mut self.upper, v);
+                self.upper_len += 1;
+            }
+            _ => {
+                Self::insert(&mut self.lower, v);
+                self.lower_len += 1;
+            }
+        }
+        self.rebalance();
+    }
+
+    /// 値vを1つ取り除きます
+    /// 中央値以下の値はlowerに、それより大きい値はupperに含まれます
+    fn remove(&mut self, v: u64) {
+        if self.median().is_some_and(|median| v <= median) {
+            if Self::take(&mut self.lower, v) {
+                self.lower_len -= 1;
+            }
+        } else if Self::take(&mut self.upper, v) {
+            self.upper_len -= 1;
+        }
+        self.rebalance();
+    }
+
+    fn median(&self) -> Option<u64> {
+        self.lower.last_key_value().map(|(&v, _)| v)
+    }
+
+    fn rebalance(&mut self) {
+        if self.lower_len > self.upper_len + 1 {
+            if let Some(v) = self.lower.last_key_value().map(|(&v, _)| v) {
+                Self::take(&mut self.lower, v);
+                Self::insert(&mut self.upper, v);
+                self.lower_len -= 1;
+                self.upper_len += 1;
+            }
+        } else if self.upper_len > self.lower_len {
+            if let Some(v) = self.upper.first_key_value().map(|(&v, _)| v) {
+                Self::take(&mut self.upper, v);
+                Self::insert(&mut self.lower, v);
+                self.upper_len -= 1;
+                self.lower_len += 1;
+            }
+        }
+    }
+
+    fn insert(set: &mut BTreeMap<u64, usize>, v: u64) {
+        *set.entry(v).or_insert(0) += 1;
+    }
+
+    /// 値vを1つ取り除きます。含まれていない場合はfalseを返します
+    fn take(set: &mut BTreeMap<u64, usize>, v: u64) -> bool {
+        let Some(count) = set.get_mut(&v) else {
+            return false;
+        };
+        *count -= 1;
+        if *count == 0 {
+            set.remove(&v);
+        }
+        true
     }
 }
 
 #[derive(Debug)]
-struct HeapTree<T: Ord> {
+pub(crate) struct HeapTree<T: Ord> {
     heap: Vec<T>,
+    /// trueの場合は小さい要素を優先する最小ヒープになります
+    min: bool,
+    /// 各ノードが持つ子ノードの最大数 (d分ヒープのd)
+    arity: usize,
 }
 
+/// 子ノード数のデフォルト値 (二分ヒープ)
+const DEFAULT_ARITY: usize = 2;
+
 impl<T: Ord> Default for HeapTree<T> {
     fn default() -> Self {
-        HeapTree { heap: Vec::new() }
+        HeapTree {
+            heap: Vec::new(),
+            min: false,
+            arity: DEFAULT_ARITY,
+        }
     }
 }
 
 impl<T: Ord> HeapTree<T> {
+    /// Vecからヒープを構築します (Floydの方法)
+    /// 葉ではない最後のノードからrootに向かってバブルダウンするため、O(n)で構築できます
+    pub fn from_vec(items: Vec<T>) -> Self {
+        let mut heap = HeapTree {
+            heap: items,
+            ..Default::default()
+        };
+        heap.heapify();
+        heap
+    }
+
+    /// 小さい要素から取り出す最小ヒープを作成します
+    pub fn new_min() -> Self {
+        HeapTree {
+            min: true,
+            ..Default::default()
+        }
+    }
+
+    /// 各ノードがd個の子ノードを持つd分ヒープを作成します
+    /// ノードiの子ノードは d*i+1 から d*i+d になります
+    ///
+    /// # Panics
+    /// dが2未満の場合
+    pub fn with_arity(d: usize) -> Self {
+        assert!(d >= 2, "arity must be at least 2: {d}");
+        HeapTree {
+            arity: d,
+            ..Default::default()
+        }
+    }
+
     fn parent(&self, index: usize) -> Option<usize> {
         if index == 0 {
             return None;
         }
-        Some((index - 1) / 2)
+        Some((index - 1) / self.arity)
     }
 
     fn is_higher_priority(&self, i1: usize, i2: usize) -> bool {
-        self.heap[i1] >= self.heap[i2]
-    }
-
-    fn get_largest_child(&self, index: usize) -> usize {
-        let left = index * 2;
-        let right = index * 2 + 1;
-        if self.is_higher_priority(left, right) {
-            left
+        if self.min {
+            self.heap[i1] <= self.heap[i2]
         } else {
-            right
+            self.heap[i1] >= self.heap[i2]
         }
     }
 
+    /// 子ノードの中でもっとも優先度が高いもののインデックスを取得します
+    /// 先頭len個の要素の中に存在する子ノードだけを比較します
+    fn get_largest_child(&self, index: usize, len: usize) -> usize {
+        let first = index * self.arity + 1;
+        let last = (first + self.arity).min(len);
+        (first + 1..last).fold(first, |largest, child| {
+            if self.is_higher_priority(largest, child) {
+                largest
+            } else {
+                child
+            }
+        })
+    }
+
     fn bubble_up(&mut self, index: usize) {
         let mut current = index;
         while let Some(parent) = self.parent(current) {
@@ -78,9 +455,14 @@ impl<T: Ord> HeapTree<T> {
     }
 
     pub fn bubble_down(&mut self, index: usize) {
+        self.bubble_down_within(index, self.length());
+    }
+
+    /// 先頭len個の要素をヒープとみなしてバブルダウンします
+    fn bubble_down_within(&mut self, index: usize, len: usize) {
         let mut current = index;
-        while (current * 2) + 1 < self.length() {
-            let largest_child = self.get_largest_child(current);
+        while current * self.arity + 1 < len {
+            let largest_child = self.get_largest_child(current, len);
             // 親ノードが子ノードよりも優先度が高い場合はバブルダウンを終了
             if self.is_higher_priority(current, largest_child) {
                 break;
@@ -97,10 +479,268 @@ impl<T: Ord> HeapTree<T> {
         } else {
             // vecの最後の要素が先頭に移動する
             let result = self.heap.swap_remove(0);
-            self.bubble_down(1);
+            self.bubble_down(0);
             Some(result)
         }
     }
+
+    /// rootの要素 (もっとも優先度の高い要素) を取り出さずに参照します
+    pub fn peek(&self) -> Option<&T> {
+        self.heap.first()
+    }
+
+    /// otherの要素をすべて取り込み、ヒープを再構築します
+    /// 要素を1つずつ追加せず、連結してからO(n)で再構築します
+    pub fn merge(&mut self, other: HeapTree<T>) {
+        self.heap.extend(other.heap);
+        self.heapify();
+    }
+
+    /// 要素を優先度の高い順に取り出すイテレータを取得します
+    /// 途中で走査をやめた場合も、イテレータの破棄時に残りの要素を取り除くため、ヒープは空になります
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        Drain { heap: self }
+    }
+
+    /// ヒープを消費して、優先度の高い順に並べたVecを取得します
+    /// ヒープソートで内部のVecをそのまま並べ替えるため、新たな領域を確保しません
+    pub fn into_sorted_vec(mut self) -> Vec<T> {
+        // rootを末尾に移動し、残りの要素でヒープを再構築することを繰り返すと優先度の低い順に並ぶ
+        for end in (1..self.length()).rev() {
+            self.heap.swap(0, end);
+            self.bubble_down_within(0, end);
+        }
+        self.heap.reverse();
+        self.heap
+    }
+
+    /// すべての要素にfを適用し、ヒープを再構築します
+    pub fn update_all(&mut self, f: impl FnMut(&mut T)) {
+        self.heap.iter_mut().for_each(f);
+        self.heapify();
+    }
+
+    /// predがtrueを返す最初の要素にfを適用し、その要素をバブルアップ・バブルダウンして
+    /// ヒープ条件を回復します。要素が見つからない場合はfalseを返します
+    pub fn update_where(&mut self, pred: impl Fn(&T) -> bool, f: impl FnOnce(&mut T)) -> bool {
+        let Some(index) = self.heap.iter().position(pred) else {
+            return false;
+        };
+        f(&mut self.heap[index]);
+        self.bubble_up(index);
+        self.bubble_down(index);
+        true
+    }
+
+    /// fがtrueを返す要素だけを残し、ヒープを再構築します
+    pub fn retain(&mut self, f: impl FnMut(&T) -> bool) {
+        self.heap.retain(f);
+        self.heapify();
+    }
+
+    /// 葉ではない最後のノードからrootに向かってバブルダウンし、ヒープを再構築します
+    fn heapify(&mut self) {
+        let parents = self.length().saturating_sub(2) / self.arity + 1;
+        for index in (0..parents.min(self.length())).rev() {
+            self.bubble_down(index);
+        }
+    }
+
+    /// 要素をヒープ内部の順番で走査します
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.heap.iter()
+    }
+
+    /// すべての親ノードが子ノード以上の優先度を持つかを検証します
+    pub fn is_valid(&self) -> bool {
+        (1..self.length()).all(|i| {
+            self.parent(i)
+                .is_some_and(|p| self.is_higher_priority(p, i))
+        })
+    }
+
+    /// 完全d分木 (d = arity) としての高さ (rootから葉までのエッジ数) を取得します。空のヒープは0です
+    fn height(&self) -> usize {
+        let mut height = 0;
+        // 深さheightまでのノード数
+        let mut nodes = 1;
+        let mut level = 1;
+        while nodes < self.length() {
+            level *= self.arity;
+            nodes += level;
+            height += 1;
+        }
+        height
+    }
+
+    /// [デバッグ用] 要素数・高さ・ヒープ条件を満たしているかを1行で取得します
+    pub fn describe(&self) -> String {
+        format!(
+            "HeapTree {{ len: {}, height: {}, valid: {} }}",
+            self.length(),
+            self.height(),
+            self.is_valid()
+        )
+    }
+}
+
+/// `HeapTree::drain`が返すイテレータ
+struct Drain<'a, T: Ord> {
+    heap: &'a mut HeapTree<T>,
+}
+
+impl<T: Ord> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.length(), Some(self.heap.length()))
+    }
+}
+
+impl<T: Ord> Drop for Drain<'_, T> {
+    fn drop(&mut self) {
+        self.heap.heap.clear();
+    }
+}
+
+/// `MessageChecker::drain`が返すイテレータ
+/// イテレータの破棄時に、残りの通知と合わせて中央値の管理もリセットします
+struct MessageDrain<'a, I> {
+    notifications: I,
+    median: &'a mut RunningMedian,
+}
+
+impl<I: Iterator<Item = MessageNotification>> Iterator for MessageDrain<'_, I> {
+    type Item = MessageNotification;
+
+    fn next(&mut self) -> Option<MessageNotification> {
+        self.notifications.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.notifications.size_hint()
+    }
+}
+
+impl<I> Drop for MessageDrain<'_, I> {
+    fn drop(&mut self) {
+        *self.median = RunningMedian::default();
+    }
+}
+
+/// 優先度付きキューの内部表現
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// ヒープ木 (HeapTree) を使用します
+    BinaryHeap,
+    /// ソート済みのVecを使用します。要素数が少なく、peekが多い場合に向いています
+    SortedVec,
+}
+
+#[derive(Debug)]
+enum Queue<T: Ord> {
+    BinaryHeap(HeapTree<T>),
+    /// 昇順に並べたVec。末尾がもっとも優先度の高い要素になる
+    SortedVec(Vec<T>),
+}
+
+/// 内部表現を切り替え可能な優先度付きキュー
+///
+/// # 例
+/// ```
+/// # use ch05_rubust_trees::heap::{Backing, PriorityQueue};
+/// let mut queue = PriorityQueue::new(Backing::SortedVec);
+/// queue.push(1);
+/// queue.push(3);
+/// queue.push(2);
+///
+/// assert_eq!(queue.peek(), Some(&3));
+/// assert_eq!(queue.pop(), Some(3));
+/// assert_eq!(queue.pop(), Some(2));
+/// ```
+///
+/// `default`はヒープ木を内部表現にした優先度付きキューを作成します
+/// ```
+/// # use ch05_rubust_trees::heap::PriorityQueue;
+/// let mut queue = PriorityQueue::<i32>::default();
+/// assert!(queue.is_empty());
+/// for priority in [3, -1, 7] {
+///     queue.push(priority);
+/// }
+///
+/// assert_eq!(queue.len(), 3);
+/// assert_eq!(queue.pop(), Some(7));
+/// assert_eq!(queue.pop(), Some(3));
+/// assert_eq!(queue.pop(), Some(-1));
+/// assert!(queue.is_empty());
+/// ```
+#[derive(Debug)]
+pub struct PriorityQueue<T: Ord> {
+    queue: Queue<T>,
+}
+
+impl<T: Ord> Default for PriorityQueue<T> {
+    fn default() -> Self {
+        PriorityQueue::new(Backing::BinaryHeap)
+    }
+}
+
+impl<T: Ord> PriorityQueue<T> {
+    pub fn new(backing: Backing) -> Self {
+        let queue = match backing {
+            Backing::BinaryHeap => Queue::BinaryHeap(HeapTree::default()),
+            Backing::SortedVec => Queue::SortedVec(Vec::new()),
+        };
+        PriorityQueue { queue }
+    }
+
+    pub fn backing(&self) -> Backing {
+        match self.queue {
+            Queue::BinaryHeap(_) => Backing::BinaryHeap,
+            Queue::SortedVec(_) => Backing::SortedVec,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.queue {
+            Queue::BinaryHeap(heap) => heap.length(),
+            Queue::SortedVec(vec) => vec.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, v: T) {
+        match &mut self.queue {
+            Queue::BinaryHeap(heap) => heap.add(v),
+            Queue::SortedVec(vec) => {
+                let index = vec.partition_point(|x| x <= &v);
+                vec.insert(index, v);
+            }
+        }
+    }
+
+    /// もっとも優先度の高い要素を取り出します
+    pub fn pop(&mut self) -> Option<T> {
+        match &mut self.queue {
+            Queue::BinaryHeap(heap) => heap.pop(),
+            Queue::SortedVec(vec) => vec.pop(),
+        }
+    }
+
+    /// もっとも優先度の高い要素を取り出さずに参照します
+    pub fn peek(&self) -> Option<&T> {
+        match &self.queue {
+            Queue::BinaryHeap(heap) => heap.peek(),
+            Queue::SortedVec(vec) => vec.last(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -164,4 +804,637 @@ mod tests {
         );
         assert_eq!(checker.length(), 0);
     }
+
+    #[test]
+    fn test_pop_in_descending_order_when_added_in_mixed_order() {
+        init();
+        let mut checker = MessageChecker::default();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [4, 9, 1, 7, 3] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        let mut counts = vec![];
+        while let Some(notification) = checker.pop() {
+            counts.push(notification.message_count);
+        }
+
+        assert_eq!(counts, vec![9, 7, 4, 3, 1]);
+        assert!(counts.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn test_bubble_down_when_node_has_only_left_child() {
+        init();
+        let mut heap = HeapTree::default();
+        for v in [10, 9, 8, 7, 6] {
+            heap.add(v);
+        }
+
+        // 6がrootに移動し、右の子を持たないindex 1で左の子 (7) と入れ替わる
+        assert_eq!(heap.pop(), Some(10));
+
+        assert_eq!(heap.heap, vec![9, 7, 8, 6]);
+        assert!(heap.is_valid());
+        let mut popped = vec![];
+        while let Some(v) = heap.pop() {
+            popped.push(v);
+        }
+        assert_eq!(popped, vec![9, 8, 7, 6]);
+    }
+
+    #[test]
+    fn test_peek() {
+        init();
+        let mut checker = MessageChecker::default();
+        assert_eq!(checker.peek(), None);
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [2, 8, 5] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        let top = checker.peek().map(|n| n.message_count);
+
+        assert_eq!(top, Some(8));
+        assert_eq!(checker.length(), 3);
+        assert_eq!(checker.pop().map(|n| n.message_count), Some(8));
+        assert_eq!(checker.peek().map(|n| n.message_count), Some(5));
+    }
+
+    #[test]
+    fn test_total_messages() {
+        init();
+        let mut checker = MessageChecker::default();
+        assert!(checker.is_empty());
+        assert_eq!(checker.total_messages(), 0);
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [2, 8, 5] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+        assert_eq!(checker.total_messages(), 15);
+
+        checker.pop();
+        checker.add(MessageNotification::new(4, device.clone()));
+        checker.pop();
+
+        assert_eq!(checker.total_messages(), 6);
+        assert!(!checker.is_empty());
+        checker.pop();
+        checker.pop();
+        assert!(checker.is_empty());
+        assert_eq!(checker.total_messages(), 0);
+    }
+
+    #[test]
+    fn test_iter() {
+        init();
+        let mut checker = MessageChecker::default();
+        let sensor = crate::iot::IoTDevice::new(1, "", "/sensor");
+        let camera = crate::iot::IoTDevice::new(2, "", "/camera");
+        for (count, device) in [(3, &sensor), (9, &camera), (1, &sensor), (4, &camera)] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        let mut counts: Vec<u64> = checker.iter().map(|n| n.message_count).collect();
+        counts.sort();
+        let sensor_counts = checker.iter().filter(|n| n.device == sensor).count();
+
+        assert_eq!(counts, vec![1, 3, 4, 9]);
+        assert_eq!(sensor_counts, 2);
+        assert_eq!(checker.length(), 4);
+    }
+
+    #[test]
+    fn test_from_vec() {
+        init();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        let notifications = (0..1000)
+            .map(|i| MessageNotification::new(i * 7919 % 1000, device.clone()))
+            .collect();
+
+        let mut checker = MessageChecker::from_vec(notifications);
+
+        assert_eq!(checker.length(), 1000);
+        assert!(checker.describe().contains("valid: true"));
+        assert_eq!(checker.approx_median(), Some(499));
+        let mut counts = vec![];
+        while let Some(notification) = checker.pop() {
+            counts.push(notification.message_count);
+        }
+        assert_eq!(counts, (0..1000).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_min_checker_pops_in_ascending_order() {
+        init();
+        let mut checker = MessageChecker::min();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [5, 1, 9, 3, 7, 7, 2] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+        assert_eq!(checker.peek().map(|n| n.message_count), Some(1));
+
+        let mut counts = vec![];
+        let mut medians = vec![];
+        while let Some(notification) = checker.pop() {
+            counts.push(notification.message_count);
+            medians.push(checker.approx_median());
+        }
+
+        assert_eq!(counts, vec![1, 2, 3, 5, 7, 7, 9]);
+        // 2, 3, 5, 7, 7, 9 -> 3, 5, 7, 7, 9 -> 5, 7, 7, 9 -> ...
+        assert_eq!(
+            medians,
+            vec![Some(5), Some(7), Some(7), Some(7), Some(7), Some(9), None]
+        );
+    }
+
+    #[test]
+    fn test_min_heap_keeps_heap_property() {
+        init();
+        let mut heap = HeapTree::new_min();
+        for v in [4, 8, 1, 6, 3, 9, 2] {
+            heap.add(v);
+        }
+
+        assert!(heap.is_valid());
+        assert_eq!(heap.pop(), Some(1));
+        assert_eq!(heap.pop(), Some(2));
+        assert!(heap.is_valid());
+    }
+
+    #[test]
+    fn test_update_priority() {
+        init();
+        let mut checker = MessageChecker::default();
+        for (id, count) in [(1, 10), (2, 7), (3, 1), (4, 4), (5, 6)] {
+            let device = crate::iot::IoTDevice::new(id, "", "");
+            checker.add(MessageNotification::new(count, device));
+        }
+
+        let raised = checker.update_priority(&crate::iot::IoTDevice::new(3, "", ""), 20);
+        let lowered = checker.update_priority(&crate::iot::IoTDevice::new(1, "", ""), 2);
+        let missing = checker.update_priority(&crate::iot::IoTDevice::new(9, "", ""), 5);
+
+        assert!(raised);
+        assert!(lowered);
+        assert!(!missing);
+        assert!(checker.describe().contains("valid: true"));
+        assert_eq!(checker.approx_median(), Some(6));
+        let mut popped = vec![];
+        while let Some(notification) = checker.pop() {
+            popped.push((notification.device.numeriacl_id, notification.message_count));
+        }
+        assert_eq!(popped, vec![(3, 20), (2, 7), (5, 6), (4, 4), (1, 2)]);
+    }
+
+    #[test]
+    fn test_into_sorted_vec() {
+        init();
+        for min in [false, true] {
+            let new_checker = if min {
+                MessageChecker::min
+            } else {
+                MessageChecker::default
+            };
+            let mut checkers = [new_checker(), new_checker()];
+            for (id, count) in [(1, 5), (2, 1), (3, 9), (4, 3), (5, 7), (6, 7), (7, 2)] {
+                let device = crate::iot::IoTDevice::new(id, "", "");
+                for checker in checkers.iter_mut() {
+                    checker.add(MessageNotification::new(count, device.clone()));
+                }
+            }
+            let [sorted, mut popped] = checkers;
+
+            let sorted = sorted.into_sorted_vec();
+
+            let mut expected = vec![];
+            while let Some(notification) = popped.pop() {
+                expected.push(notification.message_count);
+            }
+            assert_eq!(
+                sorted.iter().map(|n| n.message_count).collect::<Vec<_>>(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        init();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        let mut checker = MessageChecker::default();
+        let mut other = MessageChecker::default();
+        for i in 0..100 {
+            checker.add(MessageNotification::new(i * 2, device.clone()));
+            other.add(MessageNotification::new(i * 2 + 1, device.clone()));
+        }
+
+        checker.merge(other);
+
+        assert_eq!(checker.length(), 200);
+        assert!(checker.describe().contains("valid: true"));
+        assert_eq!(checker.approx_median(), Some(99));
+        let mut counts = vec![];
+        while let Some(notification) = checker.pop() {
+            counts.push(notification.message_count);
+        }
+        assert_eq!(counts, (0..200).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_top_k() {
+        init();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        let mut checker = MessageChecker::with_capacity(10);
+        let mut counts = vec![];
+        let mut seed: u64 = 42;
+        for _ in 0..1000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let count = seed >> 54;
+            counts.push(count);
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+        assert_eq!(checker.length(), 10);
+        assert!(checker.describe().contains("valid: true"));
+
+        let top = checker.into_top_k();
+
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        counts.truncate(10);
+        assert_eq!(
+            top.iter().map(|n| n.message_count).collect::<Vec<_>>(),
+            counts
+        );
+    }
+
+    #[test]
+    fn test_top_k_discards_lowest_priority() {
+        init();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        let mut checker = MessageChecker::with_capacity(3);
+        for count in [5, 1, 9, 3, 7] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        assert_eq!(checker.length(), 3);
+        assert_eq!(checker.peek().map(|n| n.message_count), Some(5));
+        assert_eq!(checker.approx_median(), Some(7));
+        let top = checker.into_top_k();
+        assert_eq!(
+            top.iter().map(|n| n.message_count).collect::<Vec<_>>(),
+            vec![9, 7, 5]
+        );
+        assert!(MessageChecker::with_capacity(0).into_top_k().is_empty());
+    }
+
+    #[test]
+    fn test_top_k_keeps_memory_bounded() {
+        init();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        let mut checker = MessageChecker::with_capacity(10);
+        for count in 0..1000 {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        assert_eq!(checker.length(), 10);
+        assert_eq!(median_stored_len(&checker), 10);
+        assert_eq!(checker.approx_median(), Some(994));
+    }
+
+    #[test]
+    fn test_checker_with_arity() {
+        init();
+        let mut checker = MessageChecker::with_arity(3);
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [4, 8, 1, 6, 3, 9, 2, 7, 5] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        assert_eq!(
+            checker.describe(),
+            "HeapTree { len: 9, height: 2, valid: true }"
+        );
+        assert_eq!(
+            checker
+                .into_top_k()
+                .iter()
+                .map(|n| n.message_count)
+                .collect::<Vec<_>>(),
+            vec![9, 8, 7, 6, 5, 4, 3, 2, 1]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "arity must be at least 2: 1")]
+    fn test_arity_must_be_at_least_two() {
+        MessageChecker::with_arity(1);
+    }
+
+    #[test]
+    fn test_d_ary_heap_pops_same_order_as_binary_heap() {
+        init();
+        let inputs: Vec<u64> = (0..200).map(|i| i * 37 % 101).collect();
+        let mut binary = HeapTree::default();
+        let mut quaternary = HeapTree::with_arity(4);
+        for &v in &inputs {
+            binary.add(v);
+            quaternary.add(v);
+        }
+        assert!(quaternary.is_valid());
+        assert_eq!(quaternary.parent(4), Some(0));
+        assert_eq!(quaternary.parent(5), Some(1));
+        assert_eq!(
+            quaternary.describe(),
+            "HeapTree { len: 200, height: 4, valid: true }"
+        );
+
+        let from_binary = binary.into_sorted_vec();
+        let mut from_quaternary = vec![];
+        while let Some(v) = quaternary.pop() {
+            from_quaternary.push(v);
+        }
+
+        let mut expected = inputs.clone();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(from_binary, expected);
+        assert_eq!(from_quaternary, expected);
+        let heapified = HeapTree::from_vec(inputs);
+        assert!(heapified.is_valid());
+    }
+
+    #[test]
+    fn test_drain() {
+        init();
+        let mut checker = MessageChecker::default();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [4, 8, 1, 6, 3] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        let first_two: Vec<u64> = checker.drain().take(2).map(|n| n.message_count).collect();
+
+        assert_eq!(first_two, vec![8, 6]);
+        assert_eq!(checker.length(), 0);
+        assert_eq!(checker.approx_median(), None);
+        checker.add(MessageNotification::new(2, device.clone()));
+        assert_eq!(
+            checker.drain().map(|n| n.message_count).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(checker.length(), 0);
+    }
+
+    #[test]
+    fn test_drain_keeps_median_until_iterator_is_dropped() {
+        init();
+        let mut checker = MessageChecker::default();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [4, 8, 1] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        // 破棄されなかったイテレータは通知を取り出さないため、中央値も残る
+        core::mem::forget(checker.drain());
+
+        assert_eq!(checker.length(), 3);
+        assert_eq!(checker.approx_median(), Some(4));
+        drop(checker.drain());
+        assert_eq!(checker.length(), 0);
+        assert_eq!(checker.approx_median(), None);
+    }
+
+    #[test]
+    fn test_pop_expect_when_notification_exists() {
+        init();
+        let mut checker = MessageChecker::default();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        checker.add(MessageNotification::new(1, device.clone()));
+        checker.add(MessageNotification::new(5, device));
+
+        assert_eq!(checker.pop_expect().map(|n| n.message_count), Ok(5));
+        assert_eq!(checker.pop_expect().map(|n| n.message_count), Ok(1));
+    }
+
+    #[test]
+    fn test_pop_expect_when_empty() {
+        init();
+        let mut checker = MessageChecker::default();
+
+        let result = checker.pop_expect();
+
+        assert_eq!(result, Err(EmptyQueue));
+        assert_eq!(EmptyQueue.to_string(), "queue is empty");
+    }
+
+    #[test]
+    fn test_approx_median() {
+        init();
+        let mut checker = MessageChecker::default();
+        assert_eq!(checker.approx_median(), None);
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        let mut medians = vec![];
+        for count in [5, 1, 9, 3, 7, 7, 2] {
+            checker.add(MessageNotification::new(count, device.clone()));
+            medians.push(checker.approx_median().unwrap());
+        }
+        assert_eq!(medians, vec![5, 1, 5, 3, 5, 5, 5]);
+
+        let mut medians = vec![];
+        while checker.pop().is_some() {
+            medians.push(checker.approx_median());
+        }
+        // 9, 7, 7, 5, 3, 2, 1の順に取り出される
+        assert_eq!(
+            medians,
+            vec![Some(3), Some(3), Some(2), Some(2), Some(1), Some(1), None]
+        );
+    }
+
+    /// 中央値の管理に保持している値の数
+    fn median_stored_len(checker: &MessageChecker) -> usize {
+        let median = &checker.median;
+        median.lower.values().sum::<usize>() + median.upper.values().sum::<usize>()
+    }
+
+    #[test]
+    fn test_approx_median_keeps_only_queued_counts() {
+        init();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for mut checker in [MessageChecker::default(), MessageChecker::min()] {
+            for count in 0..1000 {
+                checker.add(MessageNotification::new(count % 37, device.clone()));
+                checker.add(MessageNotification::new(count % 53, device.clone()));
+                checker.pop();
+                assert_eq!(median_stored_len(&checker), checker.length());
+            }
+            for count in 0..1000 {
+                checker.update_priority(&device, count % 41);
+                assert_eq!(median_stored_len(&checker), checker.length());
+            }
+            assert_eq!(checker.length(), 1000);
+        }
+    }
+
+    #[test]
+    fn test_group_by_range() {
+        init();
+        let mut checker = MessageChecker::default();
+        for (id, count) in [(1, 10), (999, 5), (1000, 7), (1500, 3), (3200, 1)] {
+            let device = crate::iot::IoTDevice::new(id, "", "");
+            checker.add(MessageNotification::new(count, device));
+        }
+
+        let groups = checker.group_by_range(1000);
+
+        assert_eq!(
+            groups.into_iter().collect::<Vec<_>>(),
+            vec![(0, 15), (1, 10), (3, 1)]
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        init();
+        let mut checker = MessageChecker::default();
+        assert_eq!(
+            checker.describe(),
+            "HeapTree { len: 0, height: 0, valid: true }"
+        );
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        for count in [3, 9, 1, 7, 5, 2] {
+            checker.add(MessageNotification::new(count, device.clone()));
+        }
+
+        let summary = checker.describe();
+
+        assert_eq!(summary, "HeapTree { len: 6, height: 2, valid: true }");
+    }
+
+    #[test]
+    fn test_decay() {
+        init();
+        let mut checker = MessageChecker::default();
+        for (id, count) in [(1, 10), (2, 7), (3, 1), (4, 4)] {
+            let device = crate::iot::IoTDevice::new(id, "", "");
+            checker.add(MessageNotification::new(count, device));
+        }
+
+        checker.decay(0.5);
+
+        assert!(checker.describe().contains("valid: true"));
+        assert_eq!(checker.approx_median(), Some(2));
+        assert_eq!(checker.drop_zero_counts(), 1);
+        let mut popped = vec![];
+        while let Some(notification) = checker.pop() {
+            popped.push((notification.device.numeriacl_id, notification.message_count));
+        }
+        assert_eq!(popped, vec![(1, 5), (2, 3), (4, 2)]);
+    }
+
+    #[test]
+    fn test_decay_clamps_factor() {
+        init();
+        let mut checker = MessageChecker::default();
+        let device = crate::iot::IoTDevice::new(1, "", "");
+        checker.add(MessageNotification::new(8, device.clone()));
+        checker.add(MessageNotification::new(3, device));
+
+        checker.decay(2.0);
+        assert_eq!(checker.pop().map(|n| n.message_count), Some(8));
+
+        checker.decay(-1.0);
+        assert_eq!(checker.pop().map(|n| n.message_count), Some(0));
+    }
+
+    #[test]
+    fn test_decay_rounds_down_large_counts() {
+        init();
+        let large = (1 << 53) + 1;
+        let mut checker = MessageChecker::default();
+        for (id, count) in [(1, u64::MAX), (2, large)] {
+            let device = crate::iot::IoTDevice::new(id, "", "");
+            checker.add(MessageNotification::new(count, device));
+        }
+
+        checker.decay(1.0);
+        assert_eq!(checker.approx_median(), Some(large));
+
+        checker.decay(0.5);
+        let mut popped = vec![];
+        while let Some(notification) = checker.pop() {
+            popped.push(notification.message_count);
+        }
+        assert_eq!(popped, vec![u64::MAX / 2, large / 2]);
+    }
+
+    #[test]
+    fn test_decay_scales_by_fraction_exactly() {
+        assert_eq!(MessageChecker::scale_down(u64::MAX, 0.0), 0);
+        assert_eq!(MessageChecker::scale_down(u64::MAX, 0.25), u64::MAX / 4);
+        assert_eq!(MessageChecker::scale_down(10, 0.1), 1);
+        assert_eq!(MessageChecker::scale_down(u64::MAX, f64::MIN_POSITIVE), 0);
+        assert_eq!(MessageChecker::scale_down(3, 0.999_999), 2);
+    }
+
+    fn drain_queue(mut queue: PriorityQueue<u64>) -> Vec<u64> {
+        let mut result = vec![];
+        while let Some(v) = queue.pop() {
+            result.push(v);
+        }
+        result
+    }
+
+    #[test]
+    fn priority_queue_should_pop_same_order_for_both_backings() {
+        init();
+        let inputs = [5, 1, 9, 3, 3, 7, 2, 8, 6, 4, 0];
+        let mut heap = PriorityQueue::new(Backing::BinaryHeap);
+        let mut sorted = PriorityQueue::new(Backing::SortedVec);
+        for v in inputs {
+            heap.push(v);
+            sorted.push(v);
+        }
+
+        assert_eq!(heap.peek(), Some(&9));
+        assert_eq!(sorted.peek(), Some(&9));
+
+        let from_heap = drain_queue(heap);
+        let from_sorted = drain_queue(sorted);
+        assert_eq!(from_heap, vec![9, 8, 7, 6, 5, 4, 3, 3, 2, 1, 0]);
+        assert_eq!(from_heap, from_sorted);
+    }
+
+    #[test]
+    fn priority_queue_should_return_none_when_empty() {
+        init();
+        for backing in [Backing::BinaryHeap, Backing::SortedVec] {
+            let mut queue = PriorityQueue::<u64>::new(backing);
+            assert_eq!(queue.backing(), backing);
+            assert_eq!(queue.peek(), None);
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn priority_queue_should_track_length_for_both_backings() {
+        init();
+        for backing in [Backing::BinaryHeap, Backing::SortedVec] {
+            let mut queue = PriorityQueue::new(backing);
+            assert!(queue.is_empty());
+            for v in [4, 2, 6] {
+                queue.push(v);
+            }
+            assert_eq!(queue.len(), 3);
+            queue.pop();
+            assert_eq!(queue.len(), 2);
+            assert!(!queue.is_empty());
+        }
+        assert_eq!(
+            PriorityQueue::<i32>::default().backing(),
+            Backing::BinaryHeap
+        );
+    }
 }
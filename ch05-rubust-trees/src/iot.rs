@@ -1,3 +1,5 @@
+use alloc::{format, string::String, vec, vec::Vec};
+
 #[derive(Clone, Debug, Eq)]
 pub struct IoTDevice {
     pub numeriacl_id: u64,
@@ -13,6 +15,85 @@ impl IoTDevice {
             path: path.into(),
         }
     }
+
+    /// `id,address,path`形式の文字列に変換します
+    /// 文字列からの変換ではpathだけがカンマを含められるため、addressにカンマを含む場合はエラーを返します
+    pub fn to_csv(&self) -> Result<String, ToCsvError> {
+        if self.address.contains(',') {
+            return Err(ToCsvError::CommaInAddress);
+        }
+        Ok(format!(
+            "{},{},{}",
+            self.numeriacl_id, self.address, self.path
+        ))
+    }
+}
+
+/// IoTDeviceの`id,address,path`形式の文字列への変換に失敗した場合のエラー
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ToCsvError {
+    /// addressにカンマが含まれている
+    CommaInAddress,
+}
+
+impl core::fmt::Display for ToCsvError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::CommaInAddress => write!(f, "address contains a comma"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ToCsvError {}
+
+/// IoTDeviceの文字列からの変換に失敗した場合のエラー
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseIoTDeviceError {
+    /// 必要なフィールドが存在しない
+    MissingField(&'static str),
+    /// idが数値ではない
+    InvalidId(core::num::ParseIntError),
+}
+
+impl core::fmt::Display for ParseIoTDeviceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::MissingField(field) => write!(f, "missing field: {}", field),
+            Self::InvalidId(e) => write!(f, "invalid id: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseIoTDeviceError {}
+
+/// `id,address,path`形式の文字列からIoTDeviceを生成します
+/// pathにはカンマを含めることができます
+/// 各フィールドの前後の空白は取り除かず、そのまま使用します。そのためidの前後に空白がある場合はエラーになります
+///
+/// # 例
+/// ```
+/// # use ch05_rubust_trees::iot::IoTDevice;
+/// let device: IoTDevice = "1,192.168.0.1,/floor-1/sensor".parse().unwrap();
+/// assert_eq!(device.numeriacl_id, 1);
+/// assert_eq!(device.address, "192.168.0.1");
+/// assert_eq!(device.path, "/floor-1/sensor");
+/// ```
+impl core::str::FromStr for IoTDevice {
+    type Err = ParseIoTDeviceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // 最初のカンマより前は常にidになるため、不足するのはaddressかpathです
+        let (id, rest) = s
+            .split_once(',')
+            .ok_or(ParseIoTDeviceError::MissingField("address"))?;
+        let (address, path) = rest
+            .split_once(',')
+            .ok_or(ParseIoTDeviceError::MissingField("path"))?;
+        let id = id.parse().map_err(ParseIoTDeviceError::InvalidId)?;
+        Ok(IoTDevice::new(id, address, path))
+    }
 }
 
 impl PartialEq for IoTDevice {
@@ -21,20 +102,34 @@ impl PartialEq for IoTDevice {
     }
 }
 
+/// `PartialEq`と同じくnumeriacl_idだけをハッシュ値の計算に使用します
+impl core::hash::Hash for IoTDevice {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.numeriacl_id.hash(state);
+    }
+}
+
 impl PartialOrd for IoTDevice {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.numeriacl_id.cmp(&other.numeriacl_id))
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for IoTDevice {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.numeriacl_id.cmp(&other.numeriacl_id)
+    }
+}
+
+/// デバイスID同士の差を距離とします
+impl crate::red_black_tree::Distance for IoTDevice {
+    fn distance(&self, other: &Self) -> u64 {
+        self.numeriacl_id.abs_diff(other.numeriacl_id)
     }
 }
 
-impl std::fmt::Display for IoTDevice {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for IoTDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.numeriacl_id)
     }
 }
@@ -56,14 +151,14 @@ impl MessageNotification {
 }
 
 impl PartialOrd for MessageNotification {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.message_count.cmp(&other.message_count))
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for MessageNotification {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.message_count.cmp(&other.message_count)
     }
 }
 
@@ -81,11 +176,289 @@ impl DeviceRegistry {
         self.trie.find(path)
     }
 
-    pub fn remove(&mut self, path: &str) {
-        self.trie.remove(path);
+    /// pathのデバイスを削除し、削除したデバイスを返します
+    pub fn remove(&mut self, path: &str) -> Option<IoTDevice> {
+        self.trie.remove(path)
     }
 
     pub fn length(&self) -> usize {
         self.trie.len()
     }
+
+    /// 登録されているすべてのデバイスをpathの順番で取得します
+    pub fn all(&self) -> Vec<&IoTDevice> {
+        self.trie.values()
+    }
+
+    /// pathがprefixから始まるデバイスをpathの順番で取得します
+    pub fn find_by_prefix(&self, prefix: &str) -> Vec<&IoTDevice> {
+        self.trie.find_prefix(prefix)
+    }
+
+    /// レジストリを消費して、すべてのデバイスをpathの順番で取得します
+    pub fn into_devices(self) -> Vec<IoTDevice> {
+        self.trie
+            .into_pairs()
+            .into_iter()
+            .map(|(_, device)| device)
+            .collect()
+    }
+
+    /// pathがold_prefixから始まるデバイスを、new_prefixから始まるpathに移動します
+    /// old_prefix以降のpathはそのまま保持され、移動したデバイス数を返します
+    /// 移動先のpathにデバイスが存在する場合は上書きされます
+    pub fn rename_prefix(&mut self, old_prefix: &str, new_prefix: &str) -> usize {
+        let mut cursor = self.trie.cursor(old_prefix);
+        let mut moved = vec![];
+        while let Some((path, device)) = cursor.next_entry(&self.trie) {
+            moved.push((path, device.clone()));
+        }
+
+        for (path, _) in moved.iter() {
+            self.trie.remove(path);
+        }
+        for (path, mut device) in moved.iter().cloned() {
+            device.path = format!("{}{}", new_prefix, &path[old_prefix.len()..]);
+            self.add(device);
+        }
+        moved.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_should_list_all_devices_ordered_by_path() {
+        let mut registry = DeviceRegistry::default();
+        registry.add(IoTDevice::new(1, "", "/floor-2/sensor"));
+        registry.add(IoTDevice::new(2, "", "/floor-1/sensor"));
+        registry.add(IoTDevice::new(3, "", "/floor-1"));
+        registry.add(IoTDevice::new(4, "", "/floor-10/sensor"));
+
+        let paths: Vec<&str> = registry.all().iter().map(|d| d.path.as_str()).collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                "/floor-1",
+                "/floor-1/sensor",
+                "/floor-10/sensor",
+                "/floor-2/sensor"
+            ]
+        );
+    }
+
+    #[test]
+    fn registry_should_list_remaining_devices_after_removal() {
+        let mut registry = DeviceRegistry::default();
+        assert!(registry.all().is_empty());
+        registry.add(IoTDevice::new(1, "", "/c"));
+        registry.add(IoTDevice::new(2, "", "/a"));
+        registry.add(IoTDevice::new(3, "", "/b"));
+        registry.remove("/a");
+
+        let ids: Vec<u64> = registry.all().iter().map(|d| d.numeriacl_id).collect();
+
+        assert_eq!(ids, vec![3, 1]);
+    }
+
+    #[test]
+    fn registry_should_find_devices_by_path_prefix() {
+        let mut registry = DeviceRegistry::default();
+        registry.add(IoTDevice::new(1, "", "/a/1"));
+        registry.add(IoTDevice::new(2, "", "/a/2"));
+        registry.add(IoTDevice::new(3, "", "/b/1"));
+        registry.add(IoTDevice::new(4, "", "/a"));
+
+        let ids = |devices: Vec<&IoTDevice>| -> Vec<u64> {
+            devices.iter().map(|d| d.numeriacl_id).collect()
+        };
+
+        assert_eq!(ids(registry.find_by_prefix("/a")), vec![4, 1, 2]);
+        assert_eq!(ids(registry.find_by_prefix("/a/")), vec![1, 2]);
+        assert_eq!(ids(registry.find_by_prefix("/b")), vec![3]);
+        assert!(registry.find_by_prefix("/c").is_empty());
+    }
+
+    #[test]
+    fn registry_should_drain_devices_ordered_by_path() {
+        let mut registry = DeviceRegistry::default();
+        registry.add(IoTDevice::new(1, "10.0.0.1", "/floor-2/sensor"));
+        registry.add(IoTDevice::new(2, "10.0.0.2", "/floor-1/sensor"));
+        registry.add(IoTDevice::new(3, "10.0.0.3", "/floor-1"));
+
+        let devices = registry.into_devices();
+
+        assert_eq!(
+            devices,
+            vec![
+                IoTDevice::new(3, "10.0.0.3", "/floor-1"),
+                IoTDevice::new(2, "10.0.0.2", "/floor-1/sensor"),
+                IoTDevice::new(1, "10.0.0.1", "/floor-2/sensor"),
+            ]
+        );
+        assert_eq!(devices[0].address, "10.0.0.3");
+    }
+
+    #[test]
+    fn registry_should_return_removed_device() {
+        let mut registry = DeviceRegistry::default();
+        registry.add(IoTDevice::new(1, "10.0.0.1", "/floor-1/sensor"));
+        registry.add(IoTDevice::new(2, "10.0.0.2", "/floor-1/camera"));
+
+        let removed = registry.remove("/floor-1/sensor");
+
+        assert_eq!(removed.map(|d| d.numeriacl_id), Some(1));
+        assert_eq!(registry.remove("/floor-1/sensor"), None);
+        assert_eq!(registry.remove("/floor-1"), None);
+        assert_eq!(registry.length(), 1);
+        assert_eq!(
+            registry.find("/floor-1/camera").map(|d| d.numeriacl_id),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn registry_should_rename_path_prefix() {
+        let mut registry = DeviceRegistry::default();
+        registry.add(IoTDevice::new(1, "", "/floor-1/sensor"));
+        registry.add(IoTDevice::new(2, "", "/floor-1/camera/front"));
+        registry.add(IoTDevice::new(3, "", "/floor-10/sensor"));
+        registry.add(IoTDevice::new(4, "", "/floor-2/sensor"));
+
+        let moved = registry.rename_prefix("/floor-1/", "/floor-A/");
+
+        assert_eq!(moved, 2);
+        assert_eq!(registry.length(), 4);
+        assert_eq!(registry.find("/floor-1/sensor"), None);
+        assert_eq!(registry.find("/floor-1/camera/front"), None);
+        let sensor = registry.find("/floor-A/sensor").unwrap();
+        assert_eq!(sensor.numeriacl_id, 1);
+        assert_eq!(sensor.path, "/floor-A/sensor");
+        assert_eq!(
+            registry
+                .find("/floor-A/camera/front")
+                .map(|d| d.numeriacl_id),
+            Some(2)
+        );
+        assert_eq!(
+            registry.find("/floor-10/sensor").map(|d| d.numeriacl_id),
+            Some(3)
+        );
+        assert_eq!(
+            registry.find("/floor-2/sensor").map(|d| d.numeriacl_id),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn registry_should_overwrite_device_when_renamed_path_exists() {
+        let mut registry = DeviceRegistry::default();
+        registry.add(IoTDevice::new(1, "", "/floor-1/sensor"));
+        registry.add(IoTDevice::new(2, "", "/floor-2/sensor"));
+        registry.add(IoTDevice::new(3, "", "/floor-2/camera"));
+
+        let moved = registry.rename_prefix("/floor-1/", "/floor-2/");
+
+        assert_eq!(moved, 1);
+        // 移動先のデバイスは置き換えられ、同じpathのデバイスが2つ数えられることはない
+        assert_eq!(registry.length(), 2);
+        assert_eq!(registry.all().len(), registry.length());
+        assert_eq!(registry.find("/floor-1/sensor"), None);
+        let sensor = registry.find("/floor-2/sensor").unwrap();
+        assert_eq!(sensor.numeriacl_id, 1);
+        assert_eq!(sensor.path, "/floor-2/sensor");
+        assert_eq!(
+            registry.find("/floor-2/camera").map(|d| d.numeriacl_id),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn devices_with_same_id_should_hash_equally() {
+        let mut devices = std::collections::HashSet::new();
+
+        devices.insert(IoTDevice::new(1, "10.0.0.1", "/floor-1/sensor"));
+        devices.insert(IoTDevice::new(1, "10.0.0.2", "/floor-2/sensor"));
+        devices.insert(IoTDevice::new(2, "10.0.0.1", "/floor-1/sensor"));
+
+        assert_eq!(devices.len(), 2);
+        assert!(devices.contains(&IoTDevice::new(1, "", "")));
+    }
+
+    #[test]
+    fn should_parse_device_from_csv_line() {
+        let device: IoTDevice = "42,10.0.0.1,/floor-1/room-2".parse().unwrap();
+
+        assert_eq!(device.numeriacl_id, 42);
+        assert_eq!(device.address, "10.0.0.1");
+        assert_eq!(device.path, "/floor-1/room-2");
+    }
+
+    #[test]
+    fn should_parse_path_containing_comma() {
+        let device: IoTDevice = "1,,/a,b".parse().unwrap();
+
+        assert_eq!(device.address, "");
+        assert_eq!(device.path, "/a,b");
+    }
+
+    #[test]
+    fn should_round_trip_with_to_csv() {
+        let device = IoTDevice::new(7, "10.0.0.7", "/floor-2/sensor");
+
+        let parsed: IoTDevice = device.to_csv().unwrap().parse().unwrap();
+
+        assert_eq!(device.to_csv().unwrap(), "7,10.0.0.7,/floor-2/sensor");
+        assert_eq!(parsed.to_csv(), device.to_csv());
+    }
+
+    #[test]
+    fn should_reject_address_containing_comma_in_to_csv() {
+        let device = IoTDevice::new(7, "a,b", "/p");
+
+        assert_eq!(device.to_csv(), Err(ToCsvError::CommaInAddress));
+    }
+
+    #[test]
+    fn should_round_trip_path_containing_comma_and_spaces() {
+        let device = IoTDevice::new(7, " 10.0.0.7 ", " /a,b ");
+
+        let parsed: IoTDevice = device.to_csv().unwrap().parse().unwrap();
+
+        assert_eq!(parsed.address, device.address);
+        assert_eq!(parsed.path, device.path);
+    }
+
+    #[test]
+    fn should_fail_when_id_has_surrounding_spaces() {
+        let result = " 1 ,10.0.0.1,/floor-1".parse::<IoTDevice>();
+
+        assert!(matches!(result, Err(ParseIoTDeviceError::InvalidId(_))));
+    }
+
+    #[test]
+    fn should_fail_when_fields_are_missing() {
+        assert_eq!(
+            "".parse::<IoTDevice>(),
+            Err(ParseIoTDeviceError::MissingField("address"))
+        );
+        assert_eq!(
+            "1,10.0.0.1".parse::<IoTDevice>(),
+            Err(ParseIoTDeviceError::MissingField("path"))
+        );
+    }
+
+    #[test]
+    fn should_fail_when_id_is_not_numeric() {
+        let result = "abc,10.0.0.1,/floor-1".parse::<IoTDevice>();
+
+        assert!(matches!(result, Err(ParseIoTDeviceError::InvalidId(_))));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "invalid id: invalid digit found in string"
+        );
+    }
 }
@@ -1,5 +1,28 @@
+//! # features
+//! - `std` (デフォルト): 無効にすると`alloc`のみを使用する`no_std`環境でビルドします
+//! - `logging` (デフォルト): `log`クレートでデバッグログを出力します
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "logging")]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        log::debug!($($arg)*)
+    };
+}
+
+/// `logging`が無効な場合は引数の評価のみ行い、何も出力しません
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
 pub mod btree;
 pub mod heap;
 pub mod iot;
+pub mod radix_tree;
 pub mod red_black_tree;
 pub mod trie;
@@ -0,0 +1,298 @@
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::{boxed::Box, string::String};
+
+/// 経路を圧縮したトライ木 (radix木) のノード
+///
+/// 子ノードを1つしか持たないInternalノードの連なりを、部分文字列のラベルを持つ1つの辺にまとめます。
+///
+/// 例: "team", "test", "toast"という文字列を格納する場合
+///
+/// ```text
+/// (root)
+///   |
+///   t
+///   ├── e
+///   │   ├── am [E]  <- "team"
+///   │   └── st [E]  <- "test"
+///   └── oast [E]    <- "toast"
+/// ```
+struct RadixNode<V> {
+    /// 親ノードからこのノードまでの辺のラベル。rootは空文字列
+    label: String,
+    /// このノードで終わるキーの値
+    value: Option<V>,
+    /// ラベルの先頭文字をキーにした子ノード
+    children: BTreeMap<char, Box<RadixNode<V>>>,
+}
+
+impl<V> RadixNode<V> {
+    fn new(label: &str, value: Option<V>) -> Self {
+        Self {
+            label: String::from(label),
+            value,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// ラベルをatの位置で分割します
+    /// 自ノードは前半のラベルを持つ中間ノードになり、後半のラベル・値・子ノードを新しい子ノードに移します
+    fn split_label(&mut self, at: usize) {
+        let suffix = self.label.split_off(at);
+        let first = suffix.chars().next().expect("suffix must not be empty");
+        let child = RadixNode {
+            label: suffix,
+            value: self.value.take(),
+            children: core::mem::take(&mut self.children),
+        };
+        self.children.insert(first, Box::new(child));
+    }
+
+    /// 値を持たず子ノードが1つだけの場合は、子ノードと結合して1つの辺にします
+    fn merge_single_child(&mut self) {
+        if self.value.is_some() || self.children.len() != 1 {
+            return;
+        }
+        let Some((_, child)) = self.children.pop_first() else {
+            return;
+        };
+        let child = *child;
+        self.label.push_str(&child.label);
+        self.value = child.value;
+        self.children = child.children;
+    }
+
+    fn remove_rec(&mut self, rest: &str) -> Option<V> {
+        let Some(c) = rest.chars().next() else {
+            return self.value.take();
+        };
+        let child = self.children.get_mut(&c)?;
+        let rest = rest.strip_prefix(child.label.as_str())?;
+        let value = child.remove_rec(rest)?;
+
+        // 使用されなくなった子ノードを削除し、中間ノードだけになった辺を結合する
+        if child.value.is_none() && child.children.is_empty() {
+            self.children.remove(&c);
+        } else {
+            child.merge_single_child();
+        }
+        Some(value)
+    }
+
+    fn node_count(&self) -> usize {
+        1 + self
+            .children
+            .values()
+            .map(|child| child.node_count())
+            .sum::<usize>()
+    }
+}
+
+/// 2つの文字列の共通するprefixのバイト数を取得します
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i)
+}
+
+/// 経路を圧縮したトライ木
+/// `TrieTree`と同じ操作を持ち、分岐の少ない長いキーを少ないノードで保持します
+///
+/// # 例
+/// ```
+/// # use ch05_rubust_trees::radix_tree::RadixTree;
+/// let mut tree = RadixTree::default();
+/// tree.add("/sensors/room1/temp".to_string(), 1);
+/// tree.add("/sensors/room1/humidity".to_string(), 2);
+///
+/// assert_eq!(tree.find("/sensors/room1/temp"), Some(&1));
+/// assert_eq!(tree.find("/sensors/room1"), None);
+/// assert_eq!(tree.remove("/sensors/room1/temp"), Some(1));
+/// assert_eq!(tree.len(), 1);
+/// ```
+pub struct RadixTree<V> {
+    length: usize,
+    root: RadixNode<V>,
+}
+
+impl<V> Default for RadixTree<V> {
+    fn default() -> Self {
+        Self {
+            length: 0,
+            root: RadixNode::new("", None),
+        }
+    }
+}
+
+impl<V> RadixTree<V> {
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// キーと値を追加します。すでにキーが存在する場合は値を置き換えます
+    /// キーが辺のラベルの途中で分岐する場合は、分岐する位置で辺を分割します
+    pub fn add(&mut self, key: String, v: V) {
+        debug!("[radix_tree::add] key: {}", key);
+        let mut node = &mut self.root;
+        let mut rest = key.as_str();
+        loop {
+            let Some(c) = rest.chars().next() else {
+                if node.value.replace(v).is_none() {
+                    self.length += 1;
+                }
+                return;
+            };
+            let child = match node.children.entry(c) {
+                Entry::Vacant(slot) => {
+                    slot.insert(Box::new(RadixNode::new(rest, Some(v))));
+                    self.length += 1;
+                    return;
+                }
+                Entry::Occupied(slot) => slot.into_mut(),
+            };
+            let common = common_prefix_len(&child.label, rest);
+            if common < child.label.len() {
+                child.split_label(common);
+            }
+            rest = &rest[common..];
+            node = child;
+        }
+    }
+
+    pub fn find(&self, s: &str) -> Option<&V> {
+        debug!("[radix_tree::find] s: {}", s);
+        let mut node = &self.root;
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            let child = node.children.get(&c)?;
+            rest = rest.strip_prefix(child.label.as_str())?;
+            node = child;
+        }
+        node.value.as_ref()
+    }
+
+    /// キーに対応する値を削除します
+    /// 使用されなくなったノードを削除し、中間ノードだけになった辺は結合します
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        debug!("[radix_tree::remove] key: {}", key);
+        let value = self.root.remove_rec(key)?;
+        self.length -= 1;
+        Some(value)
+    }
+
+    /// [デバッグ用] rootを含むノード数を取得します
+    pub fn node_count(&self) -> usize {
+        self.root.node_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec, vec::Vec};
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    /// 子ノードのラベルを辞書順で取得します
+    fn labels<V>(node: &RadixNode<V>) -> Vec<&str> {
+        node.children
+            .values()
+            .map(|child| child.label.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn add_should_split_edges_where_keys_diverge() {
+        // Arrange
+        init();
+        let mut tree = RadixTree::default();
+
+        // Act
+        tree.add("team".to_string(), 1);
+        tree.add("test".to_string(), 2);
+        tree.add("toast".to_string(), 3);
+
+        // Assert
+        assert_eq!(tree.len(), 3);
+        assert_eq!(labels(&tree.root), vec!["t"]);
+        let t = &tree.root.children[&'t'];
+        assert_eq!(labels(t), vec!["e", "oast"]);
+        assert_eq!(labels(&t.children[&'e']), vec!["am", "st"]);
+        assert_eq!(tree.node_count(), 6);
+        assert_eq!(tree.find("team"), Some(&1));
+        assert_eq!(tree.find("test"), Some(&2));
+        assert_eq!(tree.find("toast"), Some(&3));
+        assert_eq!(tree.find("te"), None);
+        assert_eq!(tree.find("tea"), None);
+        assert_eq!(tree.find("teams"), None);
+    }
+
+    #[test]
+    fn add_should_store_key_that_ends_in_the_middle_of_an_edge() {
+        // Arrange
+        init();
+        let mut tree = RadixTree::default();
+        tree.add("rust-lang".to_string(), 1);
+
+        // Act
+        tree.add("rust".to_string(), 2);
+        tree.add("rust".to_string(), 3);
+
+        // Assert
+        assert_eq!(tree.len(), 2);
+        assert_eq!(labels(&tree.root), vec!["rust"]);
+        assert_eq!(labels(&tree.root.children[&'r']), vec!["-lang"]);
+        assert_eq!(tree.find("rust"), Some(&3));
+        assert_eq!(tree.find("rust-lang"), Some(&1));
+    }
+
+    #[test]
+    fn remove_should_merge_edges_left_with_single_child() {
+        // Arrange
+        init();
+        let mut tree = RadixTree::default();
+        for (i, key) in ["team", "test", "toast"].iter().enumerate() {
+            tree.add(key.to_string(), i);
+        }
+
+        // Act
+        let removed = tree.remove("test");
+
+        // Assert
+        assert_eq!(removed, Some(1));
+        assert_eq!(tree.len(), 2);
+        assert_eq!(labels(&tree.root.children[&'t']), vec!["eam", "oast"]);
+        assert_eq!(tree.node_count(), 4);
+        assert_eq!(tree.remove("test"), None);
+        assert_eq!(tree.remove("te"), None);
+        assert_eq!(tree.find("team"), Some(&0));
+        assert_eq!(tree.remove("team"), Some(0));
+        assert_eq!(tree.remove("toast"), Some(2));
+        assert!(tree.is_empty());
+        assert_eq!(tree.node_count(), 1);
+    }
+
+    #[test]
+    fn add_should_handle_multibyte_labels() {
+        // Arrange
+        init();
+        let mut tree = RadixTree::default();
+
+        // Act
+        tree.add("センサー".to_string(), 1);
+        tree.add("センター".to_string(), 2);
+
+        // Assert
+        assert_eq!(labels(&tree.root), vec!["セン"]);
+        assert_eq!(tree.find("センサー"), Some(&1));
+        assert_eq!(tree.find("センター"), Some(&2));
+        assert_eq!(tree.find("セン"), None);
+    }
+}
@@ -1,5 +1,13 @@
-use log::debug;
-use std::{cell::RefCell, rc::Rc};
+use crate::heap::HeapTree;
+use alloc::collections::VecDeque;
+use alloc::rc::{Rc, Weak};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::cell::{Cell, RefCell};
 
 #[derive(Clone, Debug, PartialEq)]
 enum Color {
@@ -21,16 +29,21 @@ enum Rotation {
 
 struct Node<T>
 where
-    T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord,
+    T: core::fmt::Debug + core::fmt::Display + Clone,
 {
     pub color: Color,
     pub v: T,
-    pub parent: Option<Rc<RefCell<Node<T>>>>,
+    /// 親ノードへの弱い参照。子ノードは親ノードが強い参照で保持するため、循環参照になりません
+    pub parent: Option<Weak<RefCell<Node<T>>>>,
     left: Option<Rc<RefCell<Node<T>>>>,
     right: Option<Rc<RefCell<Node<T>>>>,
+    /// 自ノードを含む部分木のノード数
+    size: usize,
+    /// 同じ値が挿入された回数
+    count: u64,
 }
 
-impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> Node<T> {
+impl<T: core::fmt::Debug + core::fmt::Display + Clone> Node<T> {
     pub fn new(value: T) -> Node<T> {
         Node {
             color: Color::Red,
@@ -38,6 +51,8 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> Node<T> {
             parent: None,
             left: None,
             right: None,
+            size: 1,
+            count: 1,
         }
     }
 
@@ -45,6 +60,11 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> Node<T> {
         self.parent.is_none()
     }
 
+    /// 親ノードを取得します
+    fn parent(&self) -> Option<Rc<RefCell<Node<T>>>> {
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+
     pub fn switch_color(&mut self, color: Color) {
         assert!(
             self.color != color,
@@ -58,33 +78,162 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> Node<T> {
     pub fn set_color(&mut self, color: Color) {
         self.color = color;
     }
+
+    /// 子ノードの部分木のノード数から、自ノードの部分木のノード数を再計算します
+    fn update_size(&mut self) {
+        let size_of =
+            |child: &Option<Rc<RefCell<Node<T>>>>| child.as_ref().map_or(0, |c| c.borrow().size);
+        self.size = 1 + size_of(&self.left) + size_of(&self.right);
+    }
 }
 
-impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> PartialEq for Node<T> {
+impl<T: core::fmt::Debug + core::fmt::Display + Clone + PartialEq> PartialEq for Node<T> {
     fn eq(&self, other: &Self) -> bool {
         self.v == other.v
     }
 }
 
-impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> std::fmt::Debug for Node<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<T: core::fmt::Debug + core::fmt::Display + Clone> core::fmt::Debug for Node<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, r#"{{"color": {:?}, "v": "{:?}"}}"#, self.color, self.v)
     }
 }
 
-#[derive(PartialEq)]
+/// JSONの文字列として、引用符で囲みエスケープした値を追加します
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// 2つの値の数値的な距離
+/// `DeviceRegistry::k_nearest`で使用します
+pub trait Distance {
+    fn distance(&self, other: &Self) -> u64;
+}
+
 pub struct DeviceRegistry<T>
 where
-    T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord,
+    T: core::fmt::Debug + core::fmt::Display + Clone,
 {
     root: Option<Rc<RefCell<Node<T>>>>,
+    /// 異なる値の数 (ノード数)。同じ値の重複は`count`で数えます
     pub length: u64,
+    /// 挿入・検索で値を比較した回数
+    comparisons: Cell<u64>,
+    /// 挿入時の回転の回数
+    rotations: Cell<u64>,
+    /// 値の順序を決める比較関数。`Default`では値自身の順序 (`Ord`) を使います
+    compare: Compare<T>,
 }
 
+/// 2つの値を比較する関数
+type Compare<T> = Rc<dyn Fn(&T, &T) -> core::cmp::Ordering>;
+
 type Tree<T> = Rc<RefCell<Node<T>>>;
 type MaybeTree<T> = Option<Tree<T>>;
 
-impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T> {
+/// `insert_auto`がまとめて追加する昇順の列の最小の長さ
+const AUTO_RUN_THRESHOLD: usize = 16;
+
+/// ノードを昇順 (in-order) に辿るカーソル
+/// 次に訪問するノードまでの左側の経路をスタックに保持します
+/// `descending`がtrueの場合は左右を入れ替え、降順に辿ります
+struct InOrderNodes<T>
+where
+    T: core::fmt::Debug + core::fmt::Display + Clone,
+{
+    stack: Vec<Tree<T>>,
+    descending: bool,
+}
+
+impl<T: core::fmt::Debug + core::fmt::Display + Clone> InOrderNodes<T> {
+    fn new(root: MaybeTree<T>) -> Self {
+        Self::with_direction(root, false)
+    }
+
+    fn new_rev(root: MaybeTree<T>) -> Self {
+        Self::with_direction(root, true)
+    }
+
+    fn with_direction(root: MaybeTree<T>, descending: bool) -> Self {
+        let mut cursor = InOrderNodes {
+            stack: vec![],
+            descending,
+        };
+        cursor.push_spine(root);
+        cursor
+    }
+
+    /// 最初に訪問する側 (昇順なら左、降順なら右) の子ノードを辿り、スタックに積みます
+    fn push_spine(&mut self, mut node: MaybeTree<T>) {
+        while let Some(current) = node {
+            node = self.first_child(&current);
+            self.stack.push(current);
+        }
+    }
+
+    fn first_child(&self, node: &Tree<T>) -> MaybeTree<T> {
+        let node = node.borrow();
+        if self.descending {
+            node.right.clone()
+        } else {
+            node.left.clone()
+        }
+    }
+
+    fn second_child(&self, node: &Tree<T>) -> MaybeTree<T> {
+        let node = node.borrow();
+        if self.descending {
+            node.left.clone()
+        } else {
+            node.right.clone()
+        }
+    }
+}
+
+impl<T: core::fmt::Debug + core::fmt::Display + Clone> Iterator for InOrderNodes<T> {
+    type Item = Tree<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_spine(self.second_child(&node));
+        Some(node)
+    }
+}
+
+impl<T: core::fmt::Debug + core::fmt::Display + Clone> DeviceRegistry<T> {
+    /// 値から取り出したキーの順序で値を並べる、空の木を作成します
+    /// 値自身が`Ord`を実装していない場合や、別のフィールドで並べたい場合に使います
+    /// 挿入・検索・削除はすべてキーで比較し、キーが等しい値は同じ値とみなします
+    pub fn with_key<K: Ord>(key_fn: impl Fn(&T) -> K + 'static) -> DeviceRegistry<T> {
+        Self::with_compare(Rc::new(move |a: &T, b: &T| key_fn(a).cmp(&key_fn(b))))
+    }
+
+    fn with_compare(compare: Compare<T>) -> DeviceRegistry<T> {
+        DeviceRegistry {
+            root: None,
+            length: 0,
+            comparisons: Cell::new(0),
+            rotations: Cell::new(0),
+            compare,
+        }
+    }
+
+    fn cmp(&self, a: &T, b: &T) -> core::cmp::Ordering {
+        (self.compare)(a, b)
+    }
+
     /// ノードの挿入
     /// - 挿入フェーズ
     ///    - 追加するノードの色は赤
@@ -100,13 +249,69 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
     ///   - (3) uncleノードが黒 && 自ノードがparentの右
     ///     - parent左回転
     ///     - (2)を適用
+    ///
+    /// すでに等しい値が存在する場合はノードを追加せず、その値の`count`を1増やします
+    /// 保持する値は最初に挿入した値のままです
     pub fn insert(&mut self, value: T) {
-        let new_node = self.insert_internal(value);
+        let Some(new_node) = self.insert_internal(value) else {
+            return;
+        };
         debug!("--- start balancing {:?}", new_node.borrow().v);
         self.root = self.balance(new_node.clone());
         debug!("--- end balancing {:?}", new_node.borrow().v);
     }
 
+    /// 複数の値を挿入します。昇順に並んだ値の列 (run) を検出した場合は、まとめて追加します
+    ///
+    /// 判定方法:
+    /// - 木の最大値より大きく、直前の値より大きい値が続く間はバッファに溜めます
+    /// - 列が途切れたとき (または最後) に、列の長さが`AUTO_RUN_THRESHOLD`以上かつ木の要素数以上の場合は、
+    ///   木の値と列を合わせて平衡な木を作り直します (`from_heap_array`と同じ方法)
+    /// - それ以外の場合は、バッファの値を1つずつ`insert`します
+    ///
+    /// 作り直しは右端に追加するのではなく、木のすべての値を取り出してから平衡な木を作り直すため、
+    /// 1回の作り直しに O(木の要素数 + 列の長さ) かかります。
+    /// `run.len() >= length`の場合のみ作り直すので、このコストは列の長さの2倍以下になり、
+    /// 追加する1要素あたりの償却コストはO(1)になります。作り直しでは回転は発生しません
+    pub fn insert_auto(&mut self, values: impl IntoIterator<Item = T>) {
+        let mut max = self.max();
+
+        let mut run: Vec<T> = vec![];
+        for value in values {
+            if run
+                .last()
+                .or(max.as_ref())
+                .is_none_or(|last| self.cmp(last, &value).is_lt())
+            {
+                run.push(value);
+                continue;
+            }
+            // 列が途切れた。valueは列の末尾 (新しい最大値) 以下なので1つずつ挿入する
+            if let Some(last) = run.last() {
+                max = Some(last.clone());
+            }
+            self.flush_run(&mut run);
+            self.insert(value);
+        }
+        self.flush_run(&mut run);
+    }
+
+    /// 溜めた列を木に追加します。作り直しは木の要素数に比例するため、列が木の要素数以上の場合に限ります
+    fn flush_run(&mut self, run: &mut Vec<T>) {
+        if run.len() >= AUTO_RUN_THRESHOLD && run.len() as u64 >= self.length {
+            debug!("bulk append {} values", run.len());
+            let mut values = Self::values_with_counts(self.root.take());
+            values.extend(run.drain(..).map(|value| (value, 1)));
+            let rebuilt = Self::from_sorted_counts(values, self.compare.clone());
+            self.root = rebuilt.root;
+            self.length = rebuilt.length;
+        } else {
+            for value in run.drain(..) {
+                self.insert(value);
+            }
+        }
+    }
+
     fn pair(
         parent: Option<Rc<RefCell<Node<T>>>>,
         child: Option<Rc<RefCell<Node<T>>>>,
@@ -124,7 +329,7 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
                         parent.borrow_mut().right = Some(child.clone());
                     }
                 };
-                child.borrow_mut().parent = Some(parent.clone());
+                child.borrow_mut().parent = Some(Rc::downgrade(&parent));
                 debug!(
                     "parent: {:?} child: {:?}",
                     parent.borrow().v,
@@ -152,45 +357,60 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
     /// RedBlackOp::LeftNode: bはaの左側の子供になります
     /// RedBlackOp::RightNode: bはaの右側の子供になります
     fn decide_direction(&self, a: &T, b: &T) -> RedBlackOp {
-        if a <= b {
+        self.comparisons.set(self.comparisons.get() + 1);
+        if self.cmp(a, b).is_le() {
             RedBlackOp::RightNode
         } else {
             RedBlackOp::LeftNode
         }
     }
 
-    fn insert_internal(&mut self, value: T) -> Rc<RefCell<Node<T>>> {
-        self.length += 1;
+    /// 値を挿入し、追加したノードを返します
+    /// 等しい値がすでに存在する場合は、その値の`count`を1増やしてNoneを返します
+    fn insert_internal(&mut self, value: T) -> MaybeTree<T> {
         let maybe_root = self.root.take();
         let (maybe_root, new_node) = self.insert_rec(maybe_root.clone(), value);
         debug!("new_root: {:?}, new_node: {:?}", &maybe_root, &new_node);
         self.root = maybe_root;
-        new_node.clone()
+        if new_node.is_some() {
+            self.length += 1;
+        }
+        new_node
     }
 
     fn insert_rec(
         &mut self,
         mut maybe_current_node: Option<Rc<RefCell<Node<T>>>>,
         value: T,
-    ) -> (MaybeTree<T>, Rc<RefCell<Node<T>>>) {
+    ) -> (MaybeTree<T>, MaybeTree<T>) {
         match maybe_current_node.take() {
             None => {
                 // 葉に到達したので、新しいノードを追加
                 debug!("inserting new node {:?}", value);
                 let new_node = Rc::new(RefCell::new(Node::new(value)));
-                (Some(new_node.clone()), new_node)
+                (Some(new_node.clone()), Some(new_node))
             }
             Some(current_node) => {
-                let new: Rc<RefCell<Node<T>>>;
+                let new: MaybeTree<T>;
                 let current_value = current_node.borrow().v.clone();
                 debug!("--- current: {:?} new: {:?}", current_value, value);
 
-                match self.decide_direction(&current_value, &value) {
+                self.comparisons.set(self.comparisons.get() + 1);
+                let direction = match self.cmp(&current_value, &value) {
+                    core::cmp::Ordering::Less => RedBlackOp::RightNode,
+                    core::cmp::Ordering::Greater => RedBlackOp::LeftNode,
+                    core::cmp::Ordering::Equal => {
+                        // 等しい値が見つかったため、ノードを追加せずに重複数を増やす
+                        current_node.borrow_mut().count += 1;
+                        return (Some(current_node), None);
+                    }
+                };
+                match direction {
                     RedBlackOp::LeftNode => {
                         debug!("go to left: {:?} > new: {:?}", current_value, value);
                         let left = current_node.borrow().left.clone();
                         let (maybe_new_tree, new_node) = self.insert_rec(left, value);
-                        new = new_node.clone();
+                        new = new_node;
 
                         Self::pair(
                             Some(current_node.clone()),
@@ -205,7 +425,7 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
                         );
                         let right = current_node.borrow().right.clone();
                         let (maybe_new_tree, new_node) = self.insert_rec(right, value);
-                        new = new_node.clone();
+                        new = new_node;
 
                         Self::pair(
                             Some(current_node.clone()),
@@ -214,10 +434,11 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
                         );
                     }
                 }
+                current_node.borrow_mut().update_size();
                 debug!(
                     "--- return current: {:?} new: {:?}",
                     current_value,
-                    new.borrow().v,
+                    new.as_ref().map(|new| new.borrow().v.clone()),
                 );
 
                 (Some(current_node), new)
@@ -260,14 +481,7 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
                 };
 
                 next_parent.borrow_mut().color = Color::Black;
-                next_parent
-                    .borrow()
-                    .parent
-                    .as_ref()
-                    .unwrap()
-                    .clone()
-                    .borrow_mut()
-                    .color = Color::Red;
+                next_parent.borrow().parent().unwrap().borrow_mut().color = Color::Red;
                 let direction = match uncle_direction {
                     RedBlackOp::LeftNode => Rotation::Left,
                     RedBlackOp::RightNode => Rotation::Right,
@@ -291,14 +505,24 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
             );
 
             debug!("parent is {:?}", self.parent_or_panic(&inserted),);
+            #[cfg(debug_assertions)]
+            let (max_steps, mut steps) = (self.max_balance_steps(), 0);
             while parent_is_red && current_is_not_root {
                 debug!("current: {:?}", current.borrow().v);
-                let grand_parent = current.borrow().parent.as_ref().unwrap().clone();
+                #[cfg(debug_assertions)]
+                {
+                    steps += 1;
+                    assert!(
+                        steps <= max_steps,
+                        "balance did not finish within {max_steps} steps"
+                    );
+                }
                 let Some((maybe_uncle, which)) = self.uncle(current.clone()) else {
                     debug!("current does not have grand parent");
                     break;
                 };
                 let parent = self.parent_or_panic(&current);
+                let grand_parent = self.parent_or_panic(&parent);
                 match which {
                     //                 o  <- grand_parent
                     //                / \
@@ -355,7 +579,17 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
         })
     }
 
-    fn rotate(&self, node: Rc<RefCell<Node<T>>>, direction: Rotation) {
+    /// [デバッグ用] balanceのループ回数の上限
+    /// 赤黒木の高さは 2 * log2(n + 1) 以下であり、ループごとに少なくとも1段上に進むため、
+    /// 上限を超えた場合はbalanceが終了しない不具合とみなします
+    #[cfg(debug_assertions)]
+    fn max_balance_steps(&self) -> u64 {
+        let max_height = 2 * u64::from((self.length + 1).ilog2() + 1);
+        2 * max_height + 2
+    }
+
+    fn rotate(&mut self, node: Rc<RefCell<Node<T>>>, direction: Rotation) {
+        self.rotations.set(self.rotations.get() + 1);
         match direction {
             Rotation::Left => {
                 let r = node.borrow().right.clone();
@@ -370,14 +604,15 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
         }
     }
 
+    /// rotateしたノードがrootだった場合は、親ノードになった子ノードを新しいrootにします
     fn rotate_internal(
-        &self,
+        &mut self,
         node: Rc<RefCell<Node<T>>>,
         child: Option<Rc<RefCell<Node<T>>>>,
         grandchild: Option<Rc<RefCell<Node<T>>>>,
         rotation: Rotation,
     ) -> Rc<RefCell<Node<T>>> {
-        let p = node.borrow().parent.clone();
+        let p = node.borrow().parent();
         assert!(
             child.as_ref().is_some(),
             "if node does not have a child, it can not rotate"
@@ -394,6 +629,11 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
             Rotation::Right => RedBlackOp::LeftNode,
         };
         Self::pair(Some(node.clone()), grandchild.clone(), grandchild_direction);
+        // 子ノードになった自ノード、親ノードになった子ノードの順で部分木のノード数を更新する
+        node.borrow_mut().update_size();
+        if let Some(child) = child.as_ref() {
+            child.borrow_mut().update_size();
+        }
 
         // (2)/(4) 左子ノードの親ノード <=> 自ノードの親ノード
         match p {
@@ -407,24 +647,21 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
             // (例外) 左子ノードの親ノード = None (左子ノードがrootになる場合)
             None => {
                 child.as_ref().unwrap().borrow_mut().parent = None;
+                self.root = child.clone();
                 child.clone().unwrap()
             }
         }
     }
 
     fn parent_or_panic(&self, node: &Rc<RefCell<Node<T>>>) -> Rc<RefCell<Node<T>>> {
-        node.borrow().parent.as_ref().unwrap().clone()
-    }
-
-    fn _grand_parent(&self, node: Rc<RefCell<Node<T>>>) -> Option<Rc<RefCell<Node<T>>>> {
-        node.borrow().parent.as_ref()?.borrow().parent.clone()
+        node.borrow().parent().unwrap()
     }
 
     /// uncleノードを取得
     /// which:
     fn uncle(&self, node: Rc<RefCell<Node<T>>>) -> Option<(MaybeTree<T>, RedBlackOp)> {
-        let parent = (node.borrow().parent).clone()?;
-        let grand_parent = (parent.borrow().parent).clone()?;
+        let parent = node.borrow().parent()?;
+        let grand_parent = parent.borrow().parent()?;
         // 親ノードが祖父ノードのある方向にある場合、uncleノードは親ノードの反対側になる
         let uncle_and_which =
             match self.decide_direction(&grand_parent.borrow().v, &parent.borrow().v) {
@@ -442,432 +679,2260 @@ impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> DeviceRegistry<T
 
     pub fn find(&self, value: T) -> Option<T> {
         let root = self.root.as_ref()?.clone();
-        Self::find_rec(&root, value)
+        self.find_rec(&root, value)
     }
 
-    fn find_rec(current: &Rc<RefCell<Node<T>>>, value: T) -> Option<T> {
-        match current.borrow().v.cmp(&value) {
-            std::cmp::Ordering::Less => current
+    fn find_rec(&self, current: &Rc<RefCell<Node<T>>>, value: T) -> Option<T> {
+        self.comparisons.set(self.comparisons.get() + 1);
+        match self.cmp(&current.borrow().v, &value) {
+            core::cmp::Ordering::Less => current
                 .borrow()
                 .right
                 .as_ref()
-                .and_then(|r| Self::find_rec(r, value)),
-            std::cmp::Ordering::Greater => current
+                .and_then(|r| self.find_rec(r, value)),
+            core::cmp::Ordering::Greater => current
                 .borrow()
                 .left
                 .as_ref()
-                .and_then(|l| Self::find_rec(l, value)),
-            std::cmp::Ordering::Equal => Some(current.borrow().v.clone()),
+                .and_then(|l| self.find_rec(l, value)),
+            core::cmp::Ordering::Equal => Some(current.borrow().v.clone()),
         }
     }
 
-    pub fn walk(&self, mut callback: impl FnMut(&T, usize)) {
-        self.root.as_ref().inspect(|&root| {
-            Self::walk_rec(root.clone(), &mut callback, 0);
-        });
+    /// すべての値を削除します
+    /// 親ノードへの参照は弱い参照のため、rootを手放すとすべてのノードが解放されます
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.length = 0;
     }
 
-    fn walk_rec(node: Rc<RefCell<Node<T>>>, callback: &mut impl FnMut(&T, usize), level: usize) {
-        let left = node.borrow().left.clone();
-        let right = node.borrow().right.clone();
-        debug!("current: {:?} level: {}", node.borrow().v, level);
-        callback(&node.clone().borrow().v, level);
-        right.inspect(|r| Self::walk_rec(r.clone(), callback, level + 1));
-        left.inspect(|l| Self::walk_rec(l.clone(), callback, level + 1));
+    /// valueと等しい値が挿入された回数を取得します。存在しない場合は0です
+    pub fn count(&self, value: &T) -> u64 {
+        self.find_node(value).map_or(0, |node| node.borrow().count)
     }
-}
 
-impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> std::fmt::Display
-    for DeviceRegistry<T>
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.walk(&mut |value: &T, level| {
-            let indent = "  ".repeat(level);
-            writeln!(f, "{}- {}", indent, value).unwrap();
-        });
-        Ok(())
+    /// valueと等しい値を1つ削除し、保持していた値を返します
+    /// 重複している (`count`が2以上の) 場合は`count`を1減らすだけで、ノードは残ります
+    /// 存在しない場合はNoneを返します
+    pub fn remove(&mut self, value: &T) -> Option<T> {
+        let node = self.find_node(value)?;
+        if node.borrow().count > 1 {
+            node.borrow_mut().count -= 1;
+            return Some(node.borrow().v.clone());
+        }
+        let removed = node.borrow().v.clone();
+        self.length -= 1;
+        self.remove_node(node);
+        Some(removed)
     }
-}
 
-impl<T: std::fmt::Debug + std::fmt::Display + Clone + Eq + Ord> Default for DeviceRegistry<T> {
-    fn default() -> Self {
-        DeviceRegistry {
-            root: None,
-            length: 0,
+    /// ノードを木から取り除き、赤黒木の条件を満たすように修正します
+    /// - 子ノードが2つある場合は、次のノード (右部分木の最小値) の値と入れ替え、次のノードを取り除きます
+    /// - 取り除くノードの子ノードは高々1つなので、取り除くノードの位置に子ノードを繋ぎます
+    /// - 取り除いたノードが黒の場合は黒ノードの数が1つ減るため、`fix_double_black`で修正します
+    fn remove_node(&mut self, node: Tree<T>) {
+        let has_two_children = {
+            let n = node.borrow();
+            n.left.is_some() && n.right.is_some()
+        };
+        let target = if has_two_children {
+            let next = Self::leftmost(node.borrow().right.clone()).unwrap();
+            {
+                let mut n = node.borrow_mut();
+                let mut next = next.borrow_mut();
+                core::mem::swap(&mut n.v, &mut next.v);
+                core::mem::swap(&mut n.count, &mut next.count);
+            }
+            next
+        } else {
+            node
+        };
+
+        let (parent, child, color) = {
+            let mut t = target.borrow_mut();
+            let child = t.left.take().or_else(|| t.right.take());
+            (
+                t.parent.take().and_then(|p| p.upgrade()),
+                child,
+                t.color.clone(),
+            )
+        };
+        match parent.as_ref() {
+            Some(p) => {
+                let direction = if Self::is_left_child(p, &target) {
+                    RedBlackOp::LeftNode
+                } else {
+                    RedBlackOp::RightNode
+                };
+                Self::pair(Some(p.clone()), child.clone(), direction);
+            }
+            None => {
+                Self::pair(None, child.clone(), RedBlackOp::LeftNode);
+                self.root = child.clone();
+            }
+        }
+        // 取り除いたノードの祖先の部分木のノード数を更新する
+        let mut ancestor = parent.clone();
+        while let Some(a) = ancestor {
+            a.borrow_mut().update_size();
+            ancestor = a.borrow().parent();
+        }
+
+        if color == Color::Black {
+            match child.as_ref() {
+                Some(c) if c.borrow().color == Color::Red => c.borrow_mut().set_color(Color::Black),
+                _ => self.fix_double_black(child, parent),
+            }
+        }
+        // 回転によりrootが変わっている可能性があるため、rootまで辿り直す
+        let mut root = self.root.clone();
+        while let Some(p) = root.as_ref().and_then(|r| r.borrow().parent()) {
+            root = Some(p);
+        }
+        if let Some(r) = root.as_ref() {
+            r.borrow_mut().set_color(Color::Black);
         }
+        self.root = root;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{cell::RefCell, rc::Rc};
+    /// 黒ノードの数が1つ少ない部分木 (node) を修正します。nodeがNoneの場合は葉を表します
+    /// - (1) 兄弟ノードが赤: 親ノードを回転し、兄弟ノードを黒にしてから (2)-(4) を適用
+    /// - (2) 兄弟ノードの子ノードがどちらも黒: 兄弟ノードを赤にし、親ノードで再帰的に修正
+    /// - (3) 兄弟ノードの遠い側の子ノードが黒: 兄弟ノードを回転し、(4) を適用
+    /// - (4) 兄弟ノードの遠い側の子ノードが赤: 親ノードを回転し、色を入れ替えて終了
+    fn fix_double_black(&mut self, mut node: MaybeTree<T>, mut parent: MaybeTree<T>) {
+        let is_black =
+            |n: &MaybeTree<T>| n.as_ref().is_none_or(|n| n.borrow().color == Color::Black);
+        while is_black(&node) {
+            let Some(p) = parent.clone() else {
+                break;
+            };
+            let node_is_left = match node.as_ref() {
+                Some(n) => Self::is_left_child(&p, n),
+                None => p.borrow().left.is_none(),
+            };
+            // nodeの側へ回転する場合はrotation(node_is_left)、反対側へはrotation(!node_is_left)
+            let rotation = |left: bool| {
+                if left {
+                    Rotation::Left
+                } else {
+                    Rotation::Right
+                }
+            };
+            let sibling_of = |p: &Tree<T>| {
+                let p = p.borrow();
+                if node_is_left {
+                    p.right.clone()
+                } else {
+                    p.left.clone()
+                }
+            };
+            let children_of = |s: &Tree<T>| {
+                let s = s.borrow();
+                // (近い側の子ノード, 遠い側の子ノード)
+                if node_is_left {
+                    (s.left.clone(), s.right.clone())
+                } else {
+                    (s.right.clone(), s.left.clone())
+                }
+            };
 
-    use crate::iot::IoTDevice;
+            let mut sibling = sibling_of(&p).expect("sibling of a double black node must exist");
+            if sibling.borrow().color == Color::Red {
+                sibling.borrow_mut().set_color(Color::Black);
+                p.borrow_mut().set_color(Color::Red);
+                self.rotate(p.clone(), rotation(node_is_left));
+                sibling = sibling_of(&p).expect("sibling of a double black node must exist");
+            }
 
-    use super::{DeviceRegistry, Node};
+            let (near, far) = children_of(&sibling);
+            if is_black(&near) && is_black(&far) {
+                sibling.borrow_mut().set_color(Color::Red);
+                parent = p.borrow().parent();
+                node = Some(p);
+                continue;
+            }
+            if is_black(&far) {
+                if let Some(near) = near.as_ref() {
+                    near.borrow_mut().set_color(Color::Black);
+                }
+                sibling.borrow_mut().set_color(Color::Red);
+                self.rotate(sibling.clone(), rotation(!node_is_left));
+                sibling = sibling_of(&p).expect("sibling of a double black node must exist");
+            }
+            let parent_color = p.borrow().color.clone();
+            sibling.borrow_mut().set_color(parent_color);
+            p.borrow_mut().set_color(Color::Black);
+            if let Some(far) = children_of(&sibling).1 {
+                far.borrow_mut().set_color(Color::Black);
+            }
+            self.rotate(p, rotation(node_is_left));
+            return;
+        }
+        if let Some(n) = node {
+            n.borrow_mut().set_color(Color::Black);
+        }
+    }
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+    /// childがparentの左の子ノードかを判定します
+    fn is_left_child(parent: &Tree<T>, child: &Tree<T>) -> bool {
+        parent
+            .borrow()
+            .left
+            .as_ref()
+            .is_some_and(|l| Rc::ptr_eq(l, child))
     }
 
-    fn value(id: u64) -> IoTDevice {
-        IoTDevice::new(id, "", "")
+    /// valueと一致する値が存在するかを確認します。値を複製せずに辿ります
+    pub fn contains(&self, value: &T) -> bool {
+        self.find_node(value).is_some()
     }
 
-    fn node(value: IoTDevice) -> Rc<RefCell<Node<IoTDevice>>> {
-        Rc::new(RefCell::new(Node::new(value)))
+    pub fn len(&self) -> usize {
+        self.length as usize
     }
 
-    #[test]
-    fn test_frist_node() {
-        let mut registry = DeviceRegistry::default();
-        registry.insert(IoTDevice::new(5, "", ""));
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
 
-        assert_eq!(registry.length, 1);
-        assert_eq!(registry.root, Some(node(IoTDevice::new(5, "", ""))));
+    /// valueと一致する値がすでに存在する場合はその値を返します
+    /// 存在しない場合はvalueを挿入し、挿入した値を返します
+    pub fn find_or_insert(&mut self, value: T) -> T {
+        if let Some(existing) = self.find(value.clone()) {
+            return existing;
+        }
+        self.insert(value.clone());
+        value
     }
 
-    #[test]
-    fn when_second_node_value_is_larger_then_root_node_should_have_right_child() {
-        let mut registry = DeviceRegistry::default();
-        registry.insert_internal(value(5));
-        registry.insert_internal(value(6));
-        registry.insert_internal(value(4));
+    /// valueと一致する値を持つノードの値をvalueで置き換え、置き換える前の値を返します
+    /// 一致するノードが存在しない場合は何もせずNoneを返します
+    /// 一致する値同士は順序も等しいため、置き換えても二分探索木の条件は保たれます
+    pub fn update(&mut self, value: T) -> Option<T> {
+        let node = self.find_node(&value)?;
+        let previous = core::mem::replace(&mut node.borrow_mut().v, value);
+        Some(previous)
+    }
 
-        assert_eq!(registry.length, 3);
+    /// 最小の値を取得します。rootから左の子ノードを辿ります
+    /// 木が空の場合はNoneを返します
+    pub fn min(&self) -> Option<T> {
+        Self::leftmost(self.root.clone()).map(|node| node.borrow().v.clone())
     }
 
-    #[test]
-    fn tree_should_be_balanced() {
-        let mut registry = DeviceRegistry::default();
-        registry.insert(value(1));
-        registry.insert(value(2));
-        registry.insert(value(3));
+    /// 最大の値を取得します。rootから右の子ノードを辿ります
+    /// 木が空の場合はNoneを返します
+    pub fn max(&self) -> Option<T> {
+        Self::rightmost(self.root.clone()).map(|node| node.borrow().v.clone())
+    }
 
-        assert_eq!(registry.length, 3);
-        assert_eq!(
-            format!("{}", registry),
-            r#"- 2
-  - 3
-  - 1
-"#
-        );
+    /// value以下で最大の値を取得します
+    /// 該当する値がない場合はNoneを返します
+    pub fn floor(&self, value: &T) -> Option<T> {
+        self.floor_node(value).map(|node| node.borrow().v.clone())
     }
 
-    #[test]
-    fn when_complex_tree_should_be_balanced() {
-        let mut registry = DeviceRegistry::default();
-        registry.insert(value(2));
-        registry.insert(value(1));
-        registry.insert(value(4));
-        registry.insert(value(3));
-        registry.insert(value(7));
-        registry.insert(value(6));
-        registry.insert(value(5));
+    /// value以上で最小の値を取得します
+    /// 該当する値がない場合はNoneを返します
+    pub fn ceiling(&self, value: &T) -> Option<T> {
+        self.ceiling_node(value).map(|node| node.borrow().v.clone())
+    }
 
-        assert_eq!(registry.length, 7);
-        assert_eq!(
-            format!("{}", registry),
-            "- 4\n  - 6\n    - 7\n    - 5\n  - 2\n    - 3\n    - 1\n"
-        );
+    /// value以下で最大の値を持つノードを探します
+    /// valueより小さい値を見つけるたびに候補として記録し、右の子ノードに進みます
+    fn floor_node(&self, value: &T) -> MaybeTree<T> {
+        let mut candidate = None;
+        let mut current = self.root.clone();
+        while let Some(node) = current {
+            current = match self.cmp(&node.borrow().v, value) {
+                core::cmp::Ordering::Equal => return Some(node.clone()),
+                core::cmp::Ordering::Less => {
+                    candidate = Some(node.clone());
+                    node.borrow().right.clone()
+                }
+                core::cmp::Ordering::Greater => node.borrow().left.clone(),
+            };
+        }
+        candidate
     }
 
-    #[test]
-    fn test_insert_internal() {
-        init();
-        let mut registry = DeviceRegistry::default();
-        let p = value(6);
-        registry.insert_internal(p);
-        let n = value(4);
-        registry.insert_internal(n);
-        let r = value(5);
-        registry.insert_internal(r);
-        let l = value(2);
-        registry.insert_internal(l);
-        let gl = value(1);
-        registry.insert_internal(gl);
-        let gr = value(3);
-        registry.insert_internal(gr);
+    /// value以上で最小の値を持つノードを探します
+    fn ceiling_node(&self, value: &T) -> MaybeTree<T> {
+        let mut candidate = None;
+        let mut current = self.root.clone();
+        while let Some(node) = current {
+            current = match self.cmp(&node.borrow().v, value) {
+                core::cmp::Ordering::Equal => return Some(node.clone()),
+                core::cmp::Ordering::Greater => {
+                    candidate = Some(node.clone());
+                    node.borrow().left.clone()
+                }
+                core::cmp::Ordering::Less => node.borrow().right.clone(),
+            };
+        }
+        candidate
+    }
 
-        assert_eq!(registry.length, 6);
-        let should_p = &registry.root.as_ref().unwrap().borrow();
-        assert!(should_p.is_root());
-        let should_n = &should_p.left.as_ref().unwrap().borrow();
-        assert_eq!(should_n.v, value(4));
-        let should_r = &should_n.right.as_ref().unwrap().borrow();
-        assert_eq!(should_r.v, value(5));
-        let should_l = &should_n.left.as_ref().unwrap().borrow();
-        assert_eq!(should_l.v, value(2));
-        let should_gl = &should_l.left.as_ref().unwrap().borrow();
-        assert_eq!(should_gl.v, value(1));
-        let should_gr = &should_l.right.as_ref().unwrap().borrow();
-        assert_eq!(should_gr.v, value(3));
+    /// 昇順でk番目 (0始まり) の値を取得します
+    /// kが要素数以上の場合はNoneを返します
+    pub fn select(&self, k: usize) -> Option<T> {
+        let mut k = k;
+        let mut current = self.root.clone();
+        while let Some(node) = current {
+            let left_size = node.borrow().left.as_ref().map_or(0, |l| l.borrow().size);
+            current = match k.cmp(&left_size) {
+                core::cmp::Ordering::Less => node.borrow().left.clone(),
+                core::cmp::Ordering::Equal => return Some(node.borrow().v.clone()),
+                core::cmp::Ordering::Greater => {
+                    k -= left_size + 1;
+                    node.borrow().right.clone()
+                }
+            };
+        }
+        None
     }
 
-    #[test]
-    fn test_rotate_when_node_is_left_child_then_rotate_right_should_make_left_child_of_parent_left_child_of_node(
-    ) {
-        // 6
-        //  l 4
-        //    l 2
-        //      l 1
-        //      r 3
-        //    r 5
-        // after rotate based on 4
-        // 6
-        //  l 2
-        //    l 1
-        //    r 4
-        //      l 3
-        //      r 5
-        init();
-        let mut registry = DeviceRegistry::default();
-        let p = value(6);
-        registry.insert_internal(p.clone());
-        let n = value(4);
-        registry.insert_internal(n.clone());
+    /// valueより小さい値の数を取得します
+    pub fn rank(&self, value: &T) -> usize {
+        let mut rank = 0;
+        let mut current = self.root.clone();
+        while let Some(node) = current {
+            current = if self.cmp(&node.borrow().v, value).is_lt() {
+                let left_size = node.borrow().left.as_ref().map_or(0, |l| l.borrow().size);
+                rank += left_size + 1;
+                node.borrow().right.clone()
+            } else {
+                node.borrow().left.clone()
+            };
+        }
+        rank
+    }
+
+    /// 部分木の中で最小の値を持つノードを取得します
+    fn leftmost(mut node: MaybeTree<T>) -> MaybeTree<T> {
+        while let Some(left) = node.as_ref().and_then(|n| n.borrow().left.clone()) {
+            node = Some(left);
+        }
+        node
+    }
+
+    /// 部分木の中で最大の値を持つノードを取得します
+    fn rightmost(mut node: MaybeTree<T>) -> MaybeTree<T> {
+        while let Some(right) = node.as_ref().and_then(|n| n.borrow().right.clone()) {
+            node = Some(right);
+        }
+        node
+    }
+
+    /// valueより大きい値のうち最小の値を取得します
+    /// valueが存在する場合は親ポインタを辿って次のノードを求め、
+    /// 存在しない場合はvalueを挿入する位置から見た次の値 (`ceiling`と同じ) を返します
+    pub fn successor(&self, value: &T) -> Option<T> {
+        let next = match self.find_node(value) {
+            Some(node) => Self::next_node(&node),
+            None => self.ceiling_node(value),
+        };
+        next.map(|node| node.borrow().v.clone())
+    }
+
+    /// valueより小さい値のうち最大の値を取得します
+    /// valueが存在しない場合は`floor`と同じ値を返します
+    pub fn predecessor(&self, value: &T) -> Option<T> {
+        let prev = match self.find_node(value) {
+            Some(node) => Self::prev_node(&node),
+            None => self.floor_node(value),
+        };
+        prev.map(|node| node.borrow().v.clone())
+    }
+
+    /// 昇順で次のノードを取得します
+    fn next_node(node: &Tree<T>) -> MaybeTree<T> {
+        if let Some(right) = node.borrow().right.clone() {
+            return Self::leftmost(Some(right));
+        }
+        // 左の子ノードとして辿ってきた親ノードが次のノードになる
+        let mut child = node.clone();
+        let mut parent = node.borrow().parent();
+        while let Some(p) = parent {
+            let is_left = p
+                .borrow()
+                .left
+                .as_ref()
+                .is_some_and(|l| Rc::ptr_eq(l, &child));
+            if is_left {
+                return Some(p);
+            }
+            parent = p.borrow().parent();
+            child = p;
+        }
+        None
+    }
+
+    /// 昇順で前のノードを取得します
+    fn prev_node(node: &Tree<T>) -> MaybeTree<T> {
+        if let Some(left) = node.borrow().left.clone() {
+            return Self::rightmost(Some(left));
+        }
+        let mut child = node.clone();
+        let mut parent = node.borrow().parent();
+        while let Some(p) = parent {
+            let is_right = p
+                .borrow()
+                .right
+                .as_ref()
+                .is_some_and(|r| Rc::ptr_eq(r, &child));
+            if is_right {
+                return Some(p);
+            }
+            parent = p.borrow().parent();
+            child = p;
+        }
+        None
+    }
+
+    /// valueと一致する値を持つノードを取得します
+    fn find_node(&self, value: &T) -> MaybeTree<T> {
+        let mut current = self.root.clone();
+        while let Some(node) = current {
+            let next = match self.cmp(&node.borrow().v, value) {
+                core::cmp::Ordering::Less => node.borrow().right.clone(),
+                core::cmp::Ordering::Greater => node.borrow().left.clone(),
+                core::cmp::Ordering::Equal => return Some(node.clone()),
+            };
+            current = next;
+        }
+        None
+    }
+
+    /// aとbを持つノードの最小共通祖先 (lowest common ancestor) の値を取得します
+    /// rootから辿り、aとbが異なる側に分かれたノードが最小共通祖先になります
+    /// aまたはbが存在しない場合はNoneを返します
+    pub fn lca(&self, a: &T, b: &T) -> Option<T> {
+        self.find_node(a)?;
+        self.find_node(b)?;
+        let mut current = self.root.clone()?;
+        loop {
+            let next = {
+                let node = current.borrow();
+                let (a, b) = (self.cmp(a, &node.v), self.cmp(b, &node.v));
+                if a.is_lt() && b.is_lt() {
+                    node.left.clone()
+                } else if a.is_gt() && b.is_gt() {
+                    node.right.clone()
+                } else {
+                    return Some(node.v.clone());
+                }
+            };
+            current = next?;
+        }
+    }
+
+    /// 昇順に並んだ異なる値と重複数のペアから赤黒木を作成します
+    fn from_sorted_counts(values: Vec<(T, u64)>, compare: Compare<T>) -> DeviceRegistry<T> {
+        let length = values.len();
+        let red_level = if length > 1 { length.ilog2() } else { 0 };
+        let mut values = values.into_iter();
+        let root = Self::build_heap_shape(0, length, red_level, &mut values);
+        DeviceRegistry {
+            root,
+            length: length as u64,
+            comparisons: Cell::new(0),
+            rotations: Cell::new(0),
+            compare,
+        }
+    }
+
+    fn build_heap_shape(
+        index: usize,
+        length: usize,
+        red_level: u32,
+        values: &mut impl Iterator<Item = (T, u64)>,
+    ) -> MaybeTree<T> {
+        if index >= length {
+            return None;
+        }
+        let left = Self::build_heap_shape(index * 2 + 1, length, red_level, values);
+        let (value, count) = values.next()?;
+        let node = Rc::new(RefCell::new(Node::new(value)));
+        node.borrow_mut().count = count;
+        let right = Self::build_heap_shape(index * 2 + 2, length, red_level, values);
+
+        let level = (index + 1).ilog2();
+        let color = if level == red_level && level > 0 {
+            Color::Red
+        } else {
+            Color::Black
+        };
+        node.borrow_mut().set_color(color);
+        Self::pair(Some(node.clone()), left, RedBlackOp::LeftNode);
+        Self::pair(Some(node.clone()), right, RedBlackOp::RightNode);
+        node.borrow_mut().update_size();
+        Some(node)
+    }
+
+    /// 赤黒木の条件を満たしているかを検証します
+    /// 条件の詳細は`validate`を参照してください
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// 赤黒木の条件を検証し、最初に満たしていない条件と該当するノードの値をErrで返します
+    /// - rootノードは黒
+    /// - 赤ノードの子ノードは黒
+    /// - rootから各葉までの経路に含まれる黒ノードの数 (black height) が等しい
+    /// - 子ノードの親ポインタが自ノードを指している
+    /// - 二分探索木として値が昇順に並んでいる
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(root) = self.root.as_ref() {
+            let root = root.borrow();
+            if root.color != Color::Black {
+                return Err(format!("root is not black: {}", root.v));
+            }
+            if !root.is_root() {
+                return Err(format!("root has a parent: {}", root.v));
+            }
+        }
+        Self::valid_black_height(&self.root)?;
+        Self::valid_size(&self.root)?;
+        let values: Vec<T> = self.iter().collect();
+        if let Some(pair) = values
+            .windows(2)
+            .find(|pair| self.cmp(&pair[0], &pair[1]).is_gt())
+        {
+            return Err(format!("values are not sorted: {} > {}", pair[0], pair[1]));
+        }
+        Ok(())
+    }
+
+    /// 部分木が赤黒木の条件を満たす場合は黒ノードの数 (black height) を返します
+    fn valid_black_height(node: &MaybeTree<T>) -> Result<usize, String> {
+        let Some(node) = node else {
+            return Ok(1);
+        };
+        let current = node.borrow();
+        let mut heights = vec![];
+        for child in [&current.left, &current.right] {
+            if let Some(c) = child {
+                let c = c.borrow();
+                if !c.parent().is_some_and(|p| Rc::ptr_eq(&p, node)) {
+                    return Err(format!("parent of {} is not {}", c.v, current.v));
+                }
+                if current.color == Color::Red && c.color == Color::Red {
+                    return Err(format!(
+                        "red node has a red child: {} -> {}",
+                        current.v, c.v
+                    ));
+                }
+            }
+            heights.push(Self::valid_black_height(child)?);
+        }
+        if heights[0] != heights[1] {
+            return Err(format!(
+                "black height differs at {}: left {}, right {}",
+                current.v, heights[0], heights[1]
+            ));
+        }
+        Ok(heights[0] + usize::from(current.color == Color::Black))
+    }
+
+    /// 各ノードが保持する部分木のノード数が実際のノード数と一致する場合は、部分木のノード数を返します
+    fn valid_size(node: &MaybeTree<T>) -> Result<usize, String> {
+        let Some(node) = node else {
+            return Ok(0);
+        };
+        let current = node.borrow();
+        let size = 1 + Self::valid_size(&current.left)? + Self::valid_size(&current.right)?;
+        if size != current.size {
+            return Err(format!(
+                "size of {} is {}, but its subtree has {} nodes",
+                current.v, current.size, size
+            ));
+        }
+        Ok(size)
+    }
+
+    /// rootから最も遠い葉までのエッジ数を取得します。空の木は0です
+    pub fn height(&self) -> usize {
+        self.heights().0
+    }
+
+    /// rootから葉までの経路に含まれる黒ノードの数を取得します。空の木は0です
+    /// 赤黒木の条件により、どの経路でも同じ数になります
+    pub fn black_height(&self) -> usize {
+        self.heights().1
+    }
+
+    /// 高さと黒ノードの数 (black height) を1回の走査でまとめて取得します
+    fn heights(&self) -> (usize, usize) {
+        Self::heights_rec(&self.root).unwrap_or((0, 0))
+    }
+
+    fn heights_rec(node: &MaybeTree<T>) -> Option<(usize, usize)> {
+        let node = node.as_ref()?.borrow();
+        let left = Self::heights_rec(&node.left);
+        let right = Self::heights_rec(&node.right);
+        let height = [left, right]
+            .iter()
+            .map(|h| h.map_or(0, |(h, _)| h + 1))
+            .max()
+            .unwrap_or(0);
+        let black_height = left.map_or(0, |(_, b)| b) + usize::from(node.color == Color::Black);
+        Some((height, black_height))
+    }
+
+    /// [デバッグ用] 要素数・高さ・赤黒木の条件を満たしているかを1行で取得します
+    pub fn describe(&self) -> String {
+        format!(
+            "DeviceRegistry {{ len: {}, height: {}, valid: {} }}",
+            self.length,
+            self.height(),
+            self.is_valid()
+        )
+    }
+
+    /// 木の構造をJSONとして出力します
+    /// 各ノードは`{"color": "Red" | "Black", "value": 値, "left": 子ノード, "right": 子ノード}`で表し、
+    /// 子ノードが存在しない場合 (と空の木) は`null`になります。値は`Display`で文字列にします
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        Self::to_json_rec(&self.root, &mut json);
+        json
+    }
+
+    fn to_json_rec(node: &MaybeTree<T>, json: &mut String) {
+        let Some(node) = node else {
+            json.push_str("null");
+            return;
+        };
+        let node = node.borrow();
+        json.push_str(&format!(r#"{{"color":"{:?}","value":"#, node.color));
+        push_json_string(json, &node.v.to_string());
+        json.push_str(r#","left":"#);
+        Self::to_json_rec(&node.left, json);
+        json.push_str(r#","right":"#);
+        Self::to_json_rec(&node.right, json);
+        json.push('}');
+    }
+
+    /// rootノードの左部分木をコピーし、独立した赤黒木として取得します
+    /// 部分木はそのままでは赤黒木の条件を満たさないため、平衡な木として作り直します
+    /// 木が空の場合はNoneを返します
+    pub fn left_subtree(&self) -> Option<DeviceRegistry<T>> {
+        let left = self.root.as_ref()?.borrow().left.clone();
+        Some(self.copy_subtree(left))
+    }
+
+    /// rootノードの右部分木をコピーし、独立した赤黒木として取得します
+    /// 木が空の場合はNoneを返します
+    pub fn right_subtree(&self) -> Option<DeviceRegistry<T>> {
+        let right = self.root.as_ref()?.borrow().right.clone();
+        Some(self.copy_subtree(right))
+    }
+
+    fn copy_subtree(&self, node: MaybeTree<T>) -> DeviceRegistry<T> {
+        Self::from_sorted_counts(Self::values_with_counts(node), self.compare.clone())
+    }
+
+    /// 部分木の値と重複数のペアを昇順に取得します
+    fn values_with_counts(node: MaybeTree<T>) -> Vec<(T, u64)> {
+        InOrderNodes::new(node)
+            .map(|node| {
+                let node = node.borrow();
+                (node.v.clone(), node.count)
+            })
+            .collect()
+    }
+
+    /// 値を昇順に走査します
+    /// 各値は複製して返すため、走査中にノードを借用し続けません
+    pub fn iter(&self) -> impl Iterator<Item = T> {
+        InOrderNodes::new(self.root.clone()).map(|node| node.borrow().v.clone())
+    }
+
+    /// 値を降順に走査します (右, 自ノード, 左の順)
+    /// `iter`と同様に各値は複製して返します
+    pub fn iter_rev(&self) -> impl Iterator<Item = T> {
+        InOrderNodes::new_rev(self.root.clone()).map(|node| node.borrow().v.clone())
+    }
+
+    /// 値を昇順に走査し、値とノードが赤かどうかのペアを返します
+    pub fn iter_colored(&self) -> impl Iterator<Item = (T, bool)> {
+        InOrderNodes::new(self.root.clone()).map(|node| {
+            let node = node.borrow();
+            (node.v.clone(), node.color == Color::Red)
+        })
+    }
+
+    /// 比較回数の統計をリセットします
+    pub fn reset_stats(&mut self) {
+        self.comparisons.set(0);
+        self.rotations.set(0);
+    }
+
+    /// 前回のリセット以降に、挿入・検索で値を比較した回数を取得します
+    pub fn comparison_count(&self) -> u64 {
+        self.comparisons.get()
+    }
+
+    /// 前回のリセット以降に、挿入時に回転した回数を取得します
+    pub fn rotation_count(&self) -> u64 {
+        self.rotations.get()
+    }
+
+    /// rootノードの値を取得します
+    /// 木が空の場合はNoneを返します
+    pub fn root_value(&self) -> Option<T> {
+        self.root.as_ref().map(|root| root.borrow().v.clone())
+    }
+
+    pub fn walk(&self, mut callback: impl FnMut(&T, usize)) {
+        self.root.as_ref().inspect(|&root| {
+            Self::walk_rec(root.clone(), &mut callback, 0);
+        });
+    }
+
+    fn walk_rec(node: Rc<RefCell<Node<T>>>, callback: &mut impl FnMut(&T, usize), level: usize) {
+        let left = node.borrow().left.clone();
+        let right = node.borrow().right.clone();
+        debug!("current: {:?} level: {}", node.borrow().v, level);
+        callback(&node.clone().borrow().v, level);
+        right.inspect(|r| Self::walk_rec(r.clone(), callback, level + 1));
+        left.inspect(|l| Self::walk_rec(l.clone(), callback, level + 1));
+    }
+
+    /// rootから深さの順に、同じ深さの中では左から右へノードを走査します (幅優先探索)
+    /// callback(ノードの値, rootからの深さ)
+    pub fn walk_levels(&self, mut callback: impl FnMut(&T, usize)) {
+        let mut queue: VecDeque<(Tree<T>, usize)> = VecDeque::new();
+        if let Some(root) = self.root.as_ref() {
+            queue.push_back((root.clone(), 0));
+        }
+        while let Some((node, level)) = queue.pop_front() {
+            let current = node.borrow();
+            callback(&current.v, level);
+            for child in [&current.left, &current.right].into_iter().flatten() {
+                queue.push_back((child.clone(), level + 1));
+            }
+        }
+    }
+
+    /// 各ノードの値を、親ノードと兄弟ノードの値と共に走査します
+    /// callback(ノードの値, 親ノードの値, 兄弟ノードの値)
+    /// 走査順は`walk`と同じです
+    pub fn walk_with_context(&self, mut callback: impl FnMut(&T, Option<&T>, Option<&T>)) {
+        self.root.as_ref().inspect(|&root| {
+            Self::walk_with_context_rec(root, None, None, &mut callback);
+        });
+    }
+
+    fn walk_with_context_rec(
+        node: &Tree<T>,
+        parent: Option<&T>,
+        sibling: Option<&T>,
+        callback: &mut impl FnMut(&T, Option<&T>, Option<&T>),
+    ) {
+        let current = node.borrow();
+        callback(&current.v, parent, sibling);
+        let left = current.left.as_ref().map(|l| l.borrow());
+        let right = current.right.as_ref().map(|r| r.borrow());
+        if let Some(r) = current.right.as_ref() {
+            Self::walk_with_context_rec(r, Some(&current.v), left.as_ref().map(|l| &l.v), callback);
+        }
+        if let Some(l) = current.left.as_ref() {
+            Self::walk_with_context_rec(
+                l,
+                Some(&current.v),
+                right.as_ref().map(|r| &r.v),
+                callback,
+            );
+        }
+    }
+
+    /// 複数のDeviceRegistryの値を昇順にマージします
+    /// 各木の昇順カーソルの先頭をヒープで管理するk-wayマージで、重複する値は1つにまとめます
+    /// 値の比較には先頭の木の比較関数を使います。比較関数どうしは比較できないため、
+    /// すべての木を同じ順序 (`default`どうし、または同じキーの`with_key`どうし) で作成してください
+    pub fn merge_all(registries: &[DeviceRegistry<T>]) -> impl Iterator<Item = T> + '_ {
+        let compare = registries.first().map(|registry| registry.compare.clone());
+        let mut cursors: Vec<InOrderNodes<T>> = registries
+            .iter()
+            .map(|registry| InOrderNodes::new(registry.root.clone()))
+            .collect();
+        let mut heap = HeapTree::default();
+        if let Some(compare) = compare.as_ref() {
+            for (i, cursor) in cursors.iter_mut().enumerate() {
+                if let Some(node) = cursor.next() {
+                    heap.add(MergeHead::new(node.borrow().v.clone(), i, compare));
+                }
+            }
+        }
+
+        let mut last: Option<T> = None;
+        core::iter::from_fn(move || {
+            let compare = compare.as_ref()?;
+            while let Some(MergeHead { value, index, .. }) = heap.pop() {
+                if let Some(node) = cursors[index].next() {
+                    heap.add(MergeHead::new(node.borrow().v.clone(), index, compare));
+                }
+                if last
+                    .as_ref()
+                    .is_none_or(|last| compare(last, &value).is_ne())
+                {
+                    last = Some(value.clone());
+                    return Some(value);
+                }
+            }
+            None
+        })
+    }
+}
+
+/// `merge_all`のヒープに積む、木の番号indexとその木の先頭の値
+/// `HeapTree`は大きい要素から取り出すため、比較関数の逆順で並べます
+struct MergeHead<T> {
+    value: T,
+    index: usize,
+    compare: Compare<T>,
+}
+
+impl<T> MergeHead<T> {
+    fn new(value: T, index: usize, compare: &Compare<T>) -> Self {
+        MergeHead {
+            value,
+            index,
+            compare: compare.clone(),
+        }
+    }
+}
+
+impl<T> Ord for MergeHead<T> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (self.compare)(&other.value, &self.value).then(other.index.cmp(&self.index))
+    }
+}
+
+impl<T> PartialOrd for MergeHead<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for MergeHead<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<T> Eq for MergeHead<T> {}
+
+/// 値自身の順序 (`Ord`) を使う場合のみ利用できる機能
+impl<T: core::fmt::Debug + core::fmt::Display + Clone + Ord> DeviceRegistry<T> {
+    /// 配列を完全二分木 (ヒープと同じ配置) とみなし、同じ形の赤黒木を作成します
+    /// - index iのノードの子ノードは 2i+1, 2i+2
+    /// - 二分探索木の条件を満たすため、値は昇順に並べ替えてから中間順 (in-order) に配置します
+    /// - 最下段のノードを赤、それ以外を黒に塗ることで赤黒木の条件を満たします
+    pub fn from_heap_array(arr: Vec<T>) -> DeviceRegistry<T> {
+        let mut values = arr;
+        values.sort();
+        Self::from_sorted(values)
+    }
+
+    /// 昇順に並んだ値から、高さが最小の赤黒木をO(n)で作成します
+    /// 1件ずつ挿入する場合と異なり、回転は発生しません
+    /// 完全二分木の形に中間順で値を配置するため、各部分木のrootはその範囲の中央の値になり、
+    /// 最下段のノードだけを赤に塗ります
+    /// 等しい値が続く場合は1つのノードにまとめ、`count`に重複数を保持します
+    pub fn from_sorted(values: Vec<T>) -> DeviceRegistry<T> {
+        debug_assert!(
+            values.windows(2).all(|pair| pair[0] <= pair[1]),
+            "values must be sorted"
+        );
+        let mut grouped: Vec<(T, u64)> = vec![];
+        for value in values {
+            match grouped.last_mut() {
+                Some((last, count)) if *last == value => *count += 1,
+                _ => grouped.push((value, 1)),
+            }
+        }
+        Self::from_sorted_counts(grouped, Rc::new(|a: &T, b: &T| a.cmp(b)))
+    }
+}
+
+impl<T: core::fmt::Debug + core::fmt::Display + Clone + Distance> DeviceRegistry<T> {
+    /// targetとの距離が近い順にk個の値を取得します
+    /// 距離が等しい場合は小さい値を優先します
+    ///
+    /// target以下で最大のノード (floor) とその次のノードから、前後にカーソルを広げながら探索します
+    pub fn k_nearest(&self, target: &T, k: usize) -> Vec<T> {
+        let floor = self.floor_node(target);
+        let mut lower = floor.clone();
+        let mut upper = match floor {
+            Some(ref floor) => Self::next_node(floor),
+            None => Self::leftmost(self.root.clone()),
+        };
+
+        let mut nearest = vec![];
+        while nearest.len() < k {
+            let take_lower = match (&lower, &upper) {
+                (Some(l), Some(u)) => {
+                    l.borrow().v.distance(target) <= u.borrow().v.distance(target)
+                }
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_lower {
+                let node = lower.take().unwrap();
+                nearest.push(node.borrow().v.clone());
+                lower = Self::prev_node(&node);
+            } else {
+                let node = upper.take().unwrap();
+                nearest.push(node.borrow().v.clone());
+                upper = Self::next_node(&node);
+            }
+        }
+        nearest
+    }
+}
+
+impl<T: core::fmt::Debug + core::fmt::Display + Clone> core::fmt::Display for DeviceRegistry<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.walk(&mut |value: &T, level| {
+            let indent = "  ".repeat(level);
+            writeln!(f, "{}- {}", indent, value).unwrap();
+        });
+        Ok(())
+    }
+}
+
+impl<T: core::fmt::Debug + core::fmt::Display + Clone + Ord> Default for DeviceRegistry<T> {
+    fn default() -> Self {
+        DeviceRegistry::with_compare(Rc::new(|a: &T, b: &T| a.cmp(b)))
+    }
+}
+
+// 比較関数は比較できないため、木の構造と統計のみを比較します
+impl<T: core::fmt::Debug + core::fmt::Display + Clone + PartialEq> PartialEq for DeviceRegistry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root
+            && self.length == other.length
+            && self.comparisons == other.comparisons
+            && self.rotations == other.rotations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::iot::IoTDevice;
+
+    use super::{Color, DeviceRegistry, Node};
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn value(id: u64) -> IoTDevice {
+        IoTDevice::new(id, "", "")
+    }
+
+    fn node(value: IoTDevice) -> Rc<RefCell<Node<IoTDevice>>> {
+        Rc::new(RefCell::new(Node::new(value)))
+    }
+
+    #[test]
+    fn test_frist_node() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(IoTDevice::new(5, "", ""));
+
+        assert_eq!(registry.length, 1);
+        assert_eq!(registry.root, Some(node(IoTDevice::new(5, "", ""))));
+    }
+
+    #[test]
+    fn when_second_node_value_is_larger_then_root_node_should_have_right_child() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert_internal(value(5));
+        registry.insert_internal(value(6));
+        registry.insert_internal(value(4));
+
+        assert_eq!(registry.length, 3);
+    }
+
+    #[test]
+    fn tree_should_be_balanced() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(1));
+        registry.insert(value(2));
+        registry.insert(value(3));
+
+        assert_eq!(registry.length, 3);
+        assert_eq!(
+            format!("{}", registry),
+            r#"- 2
+  - 3
+  - 1
+"#
+        );
+    }
+
+    #[test]
+    fn when_complex_tree_should_be_balanced() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(2));
+        registry.insert(value(1));
+        registry.insert(value(4));
+        registry.insert(value(3));
+        registry.insert(value(7));
+        registry.insert(value(6));
+        registry.insert(value(5));
+
+        assert_eq!(registry.length, 7);
+        assert_eq!(
+            format!("{}", registry),
+            "- 2\n  - 4\n    - 6\n      - 7\n      - 5\n    - 3\n  - 1\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_internal() {
+        init();
+        let mut registry = DeviceRegistry::default();
+        let p = value(6);
+        registry.insert_internal(p);
+        let n = value(4);
+        registry.insert_internal(n);
         let r = value(5);
+        registry.insert_internal(r);
+        let l = value(2);
+        registry.insert_internal(l);
+        let gl = value(1);
+        registry.insert_internal(gl);
+        let gr = value(3);
+        registry.insert_internal(gr);
+
+        assert_eq!(registry.length, 6);
+        let should_p = &registry.root.as_ref().unwrap().borrow();
+        assert!(should_p.is_root());
+        let should_n = &should_p.left.as_ref().unwrap().borrow();
+        assert_eq!(should_n.v, value(4));
+        let should_r = &should_n.right.as_ref().unwrap().borrow();
+        assert_eq!(should_r.v, value(5));
+        let should_l = &should_n.left.as_ref().unwrap().borrow();
+        assert_eq!(should_l.v, value(2));
+        let should_gl = &should_l.left.as_ref().unwrap().borrow();
+        assert_eq!(should_gl.v, value(1));
+        let should_gr = &should_l.right.as_ref().unwrap().borrow();
+        assert_eq!(should_gr.v, value(3));
+    }
+
+    #[test]
+    fn test_rotate_when_node_is_left_child_then_rotate_right_should_make_left_child_of_parent_left_child_of_node(
+    ) {
+        // 6
+        //  l 4
+        //    l 2
+        //      l 1
+        //      r 3
+        //    r 5
+        // after rotate based on 4
+        // 6
+        //  l 2
+        //    l 1
+        //    r 4
+        //      l 3
+        //      r 5
+        init();
+        let mut registry = DeviceRegistry::default();
+        let p = value(6);
+        registry.insert_internal(p.clone());
+        let n = value(4);
+        registry.insert_internal(n.clone());
+        let r = value(5);
+        registry.insert_internal(r.clone());
+        let l = value(2);
+        registry.insert_internal(l.clone());
+        let gl = value(1);
+        registry.insert_internal(gl.clone());
+        let gr = value(3);
+        registry.insert_internal(gr.clone());
+
+        // Act
+        let node = registry
+            .root
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .left
+            .as_ref()
+            .unwrap()
+            .clone();
+
+        registry.rotate(node.clone(), super::Rotation::Right);
+
+        // Assert
+        assert_eq!(registry.length, 6);
+        let new_p = registry.root.as_ref().unwrap().clone();
+        assert!(new_p.borrow().is_root());
+        let new_pl = new_p.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_pl.borrow().v, l);
+        let new_ll = new_pl.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_ll.borrow().v, gl);
+        let new_lr = new_pl.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_lr.borrow().v, n);
+        let new_nl = new_lr.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_nl.borrow().v, gr);
+        let new_nr = new_lr.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_nr.borrow().v, r);
+    }
+
+    #[test]
+    fn test_rotate_when_node_is_left_child_then_rotate_right_should_make_right_child_of_parent_left_child_of_node(
+    ) {
+        // 6
+        //  r 10
+        //    l 8
+        //      l 7
+        //      r 9
+        //    r 11
+        // after rotate based on 10
+        // 6
+        //  r 8
+        //    l 7
+        //    r 10
+        //      l 9
+        //      r 11
+
+        init();
+        let mut registry = DeviceRegistry::default();
+        let p = value(6);
+        registry.insert_internal(p.clone());
+        let n = value(10);
+        registry.insert_internal(n.clone());
+        let r = value(11);
         registry.insert_internal(r.clone());
-        let l = value(2);
+        let l = value(8);
         registry.insert_internal(l.clone());
-        let gl = value(1);
+        let gl = value(7);
         registry.insert_internal(gl.clone());
-        let gr = value(3);
+        let gr = value(9);
+        registry.insert_internal(gr.clone());
+
+        // Act
+        let node = registry
+            .root
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .right
+            .as_ref()
+            .unwrap()
+            .clone();
+
+        registry.rotate(node.clone(), super::Rotation::Right);
+
+        // Assert
+        assert_eq!(registry.length, 6);
+        let new_p = registry.root.as_ref().unwrap().clone();
+        assert!(new_p.borrow().is_root());
+        let new_pl = new_p.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_pl.borrow().v, l);
+        let new_ll = new_pl.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_ll.borrow().v, gl);
+        let new_lr = new_pl.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_lr.borrow().v, n);
+        let new_nl = new_lr.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_nl.borrow().v, gr);
+        let new_nr = new_lr.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_nr.borrow().v, r);
+    }
+
+    #[test]
+    fn test_rotate_when_node_is_root_rotate_right_should_make_left_child_root() {
+        // 6
+        //  l 4
+        //    l 2
+        //    r 5
+        //  r 10
+        // after rotate based on 6
+        // 4
+        //  l 2
+        //  r 6
+        //    l 5
+        //    r 10
+
+        init();
+        let mut registry = DeviceRegistry::default();
+        let n = value(6);
+        registry.insert_internal(n.clone());
+        let l = value(4);
+        registry.insert_internal(l.clone());
+        let r = value(10);
+        registry.insert_internal(r.clone());
+        let gl = value(2);
+        registry.insert_internal(gl.clone());
+        let gr = value(5);
+        registry.insert_internal(gr.clone());
+
+        // Act
+        let node = registry.root.as_ref().unwrap().clone();
+
+        registry.rotate(node.clone(), super::Rotation::Right);
+
+        // Assert
+        let new_p = node.borrow().parent().unwrap();
+        assert!(new_p.borrow().is_root());
+        assert!(Rc::ptr_eq(registry.root.as_ref().unwrap(), &new_p));
+        assert_eq!(new_p.borrow().v, l);
+        let new_l = new_p
+            .borrow()
+            .right
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .left
+            .as_ref()
+            .unwrap()
+            .clone();
+        assert_eq!(new_l.borrow().v, gr);
+    }
+
+    #[test]
+    fn test_rotate_when_node_is_left_child_then_rotate_left_should_make_right_child_of_parent_right_child_of_node(
+    ) {
+        // 6
+        //  r 10
+        //    l 8
+        //    r 12
+        //      l 11
+        //      r 13
+        // after rotate based on 10
+        // 6
+        //  r 12
+        //    l 10
+        //      l 8
+        //      r 11
+        //    r 13
+        init();
+        let mut registry = DeviceRegistry::default();
+        let p = value(6);
+        registry.insert_internal(p.clone());
+        let n = value(10);
+        registry.insert_internal(n.clone());
+        let l = value(8);
+        registry.insert_internal(l.clone());
+        let r = value(12);
+        registry.insert_internal(r.clone());
+        let gl = value(11);
+        registry.insert_internal(gl.clone());
+        let gr = value(13);
         registry.insert_internal(gr.clone());
 
-        // Act
-        let node = registry
-            .root
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .left
-            .as_ref()
-            .unwrap()
-            .clone();
+        // Act
+        let node = registry
+            .root
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .right
+            .as_ref()
+            .unwrap()
+            .clone();
+
+        registry.rotate(node.clone(), super::Rotation::Left);
+
+        // Assert
+        assert_eq!(registry.length, 6);
+        let new_p = registry.root.as_ref().unwrap().clone();
+        assert!(new_p.borrow().is_root());
+        assert_eq!(new_p.borrow().v, p);
+        let new_pr = new_p.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_pr.borrow().v, r);
+        let new_rl = new_pr.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_rl.borrow().v, n);
+        let new_rr = new_pr.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_rr.borrow().v, gr);
+        let new_nl = new_rl.borrow().left.as_ref().unwrap().clone();
+        assert_eq!(new_nl.borrow().v, l);
+        let new_nr = new_rl.borrow().right.as_ref().unwrap().clone();
+        assert_eq!(new_nr.borrow().v, gl);
+    }
+
+    #[test]
+    fn when_only_root_node_and_search_value_exists_then_returns_the_matched_element() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(5));
+        let result = registry.find(value(5));
+        assert_eq!(result, Some(value(5)));
+    }
+
+    #[test]
+    fn when_some_nodes_and_search_value_exists_then_returns_the_matched_element() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(5));
+        registry.insert(value(6));
+        registry.insert(value(4));
+        let result = registry.find(value(6));
+        assert_eq!(result, Some(value(6)));
+    }
+
+    #[test]
+    fn when_threre_are_some_nodes_and_search_value_does_not_exist_then_returns_none() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(5));
+        registry.insert(value(6));
+        registry.insert(value(4));
+        let result = registry.find(value(7));
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn lca_of_two_leaves_is_their_lowest_common_ancestor() {
+        // - 2
+        //   - 4
+        //     - 6
+        //       - 7
+        //       - 5
+        //     - 3
+        //   - 1
+        let mut registry = DeviceRegistry::default();
+        for id in [2, 1, 4, 3, 7, 6, 5] {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.lca(&value(1), &value(3)), Some(value(2)));
+        assert_eq!(registry.lca(&value(5), &value(7)), Some(value(6)));
+        assert_eq!(registry.lca(&value(3), &value(5)), Some(value(4)));
+        assert_eq!(registry.lca(&value(7), &value(6)), Some(value(6)));
+        assert_eq!(registry.lca(&value(1), &value(1)), Some(value(1)));
+    }
+
+    #[test]
+    fn when_value_does_not_exist_then_lca_returns_none() {
+        let mut registry = DeviceRegistry::default();
+        for id in [2, 1, 4] {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.lca(&value(1), &value(99)), None);
+        assert_eq!(DeviceRegistry::default().lca(&value(1), &value(1)), None);
+    }
+
+    #[test]
+    fn merge_all_should_yield_sorted_unique_values() {
+        let mut a = DeviceRegistry::default();
+        let mut b = DeviceRegistry::default();
+        let mut c = DeviceRegistry::default();
+        for id in [5, 1, 9, 3] {
+            a.insert(value(id));
+        }
+        for id in [2, 3, 8, 5, 10] {
+            b.insert(value(id));
+        }
+        for id in [9, 4, 1] {
+            c.insert(value(id));
+        }
+        let empty = DeviceRegistry::default();
+
+        let merged: Vec<u64> = DeviceRegistry::merge_all(&[a, b, empty, c])
+            .map(|device| device.numeriacl_id)
+            .collect();
+
+        assert_eq!(merged, vec![1, 2, 3, 4, 5, 8, 9, 10]);
+    }
+
+    #[test]
+    fn merge_all_should_use_the_registry_ordering() {
+        let mut a =
+            DeviceRegistry::with_key(|device: &IoTDevice| core::cmp::Reverse(device.numeriacl_id));
+        let mut b =
+            DeviceRegistry::with_key(|device: &IoTDevice| core::cmp::Reverse(device.numeriacl_id));
+        for id in [5, 1, 9, 3] {
+            a.insert(value(id));
+        }
+        for id in [2, 3, 8] {
+            b.insert(value(id));
+        }
+
+        let merged: Vec<u64> = DeviceRegistry::merge_all(&[a, b])
+            .map(|device| device.numeriacl_id)
+            .collect();
+
+        assert_eq!(merged, vec![9, 8, 5, 3, 2, 1]);
+        assert_eq!(DeviceRegistry::<IoTDevice>::merge_all(&[]).count(), 0);
+    }
+
+    #[test]
+    fn tree_built_by_insert_should_be_valid() {
+        let mut registry = DeviceRegistry::default();
+        assert!(registry.is_valid());
+        for id in [2, 1, 4, 3, 7, 6, 5, 10, 8, 9] {
+            registry.insert(value(id));
+            assert!(registry.is_valid(), "after inserting {id}");
+        }
+    }
+
+    #[test]
+    fn from_heap_array_should_build_valid_tree_with_heap_shape() {
+        let heap_order = [9, 7, 8, 3, 5, 6, 4, 1, 2].map(value).to_vec();
+
+        let registry = DeviceRegistry::from_heap_array(heap_order);
+
+        assert!(registry.is_valid());
+        assert_eq!(registry.length, 9);
+        let mut levels = vec![0; 4];
+        registry.walk(|_, level| levels[level] += 1);
+        assert_eq!(levels, vec![1, 2, 4, 2]);
+        for id in 1..=9 {
+            assert_eq!(registry.find(value(id)), Some(value(id)));
+        }
+    }
+
+    #[test]
+    fn from_heap_array_should_handle_small_arrays() {
+        assert!(DeviceRegistry::<IoTDevice>::from_heap_array(vec![]).is_valid());
+        for n in 1..=16 {
+            let registry = DeviceRegistry::from_heap_array((1..=n).map(value).collect());
+            assert!(registry.is_valid(), "n = {n}");
+            assert_eq!(registry.length, n);
+        }
+    }
+
+    #[test]
+    fn when_walk_with_context_then_leaf_reports_parent_and_sibling() {
+        let mut registry = DeviceRegistry::default();
+        for id in [2, 1, 4, 3, 7, 6, 5] {
+            registry.insert(value(id));
+        }
+        let mut contexts = vec![];
+        registry.walk_with_context(|v, parent, sibling| {
+            contexts.push((
+                v.numeriacl_id,
+                parent.map(|p| p.numeriacl_id),
+                sibling.map(|s| s.numeriacl_id),
+            ));
+        });
+
+        assert_eq!(contexts.len(), 7);
+        assert_eq!(contexts[0], (2, None, None));
+        assert!(contexts.contains(&(5, Some(6), Some(7))));
+        assert!(contexts.contains(&(3, Some(4), Some(6))));
+        assert!(contexts.contains(&(1, Some(2), Some(4))));
+    }
+
+    #[test]
+    fn when_walk_levels_then_nodes_are_visited_level_by_level() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=7 {
+            registry.insert(value(id));
+        }
+        let mut visited = vec![];
+
+        registry.walk_levels(|v, level| visited.push((v.numeriacl_id, level)));
+
+        // 2 -> 1, 4 -> 3, 6 -> 5, 7
+        assert_eq!(
+            visited,
+            vec![(2, 0), (1, 1), (4, 1), (3, 2), (6, 2), (5, 3), (7, 3)]
+        );
+        let mut empty = 0;
+        DeviceRegistry::<IoTDevice>::default().walk_levels(|_, _| empty += 1);
+        assert_eq!(empty, 0);
+    }
+
+    #[test]
+    fn when_describe_then_summary_contains_metrics() {
+        let mut registry = DeviceRegistry::default();
+        assert_eq!(
+            registry.describe(),
+            "DeviceRegistry { len: 0, height: 0, valid: true }"
+        );
+        for id in 1..=7 {
+            registry.insert(value(id));
+        }
+
+        let summary = registry.describe();
+
+        assert_eq!(summary, "DeviceRegistry { len: 7, height: 3, valid: true }");
+    }
+
+    #[test]
+    fn when_insert_sequential_values_then_comparisons_are_n_log_n() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=15 {
+            registry.insert(value(id));
+        }
+
+        let inserted = registry.comparison_count();
+        // n log2(n) ≒ 15 * 4
+        assert!((15..=2 * 15 * 4).contains(&inserted), "{inserted}");
+
+        registry.reset_stats();
+        assert_eq!(registry.comparison_count(), 0);
+        for id in 1..=15 {
+            registry.find(value(id));
+        }
+        let found = registry.comparison_count();
+        assert!((15..=15 * 4 * 2).contains(&found), "{found}");
+    }
+
+    #[test]
+    fn when_subtrees_are_extracted_then_union_equals_tree_without_root() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=20 {
+            registry.insert(value(id));
+        }
+        let root = registry.root_value().unwrap();
+
+        let left = registry.left_subtree().unwrap();
+        let right = registry.right_subtree().unwrap();
+
+        assert!(left.is_valid());
+        assert!(right.is_valid());
+        assert_eq!(left.length + right.length, registry.length - 1);
+        let mut ids = vec![];
+        left.walk(|v, _| ids.push(v.numeriacl_id));
+        right.walk(|v, _| ids.push(v.numeriacl_id));
+        ids.sort();
+        let expected: Vec<u64> = (1..=20).filter(|&id| id != root.numeriacl_id).collect();
+        assert_eq!(ids, expected);
+        assert!(left.find(root.clone()).is_none());
+        assert_eq!(registry.find(root.clone()), Some(root));
+    }
+
+    #[test]
+    fn when_tree_is_empty_then_subtrees_are_none() {
+        let registry = DeviceRegistry::<IoTDevice>::default();
+        assert!(registry.left_subtree().is_none());
+        assert!(registry.right_subtree().is_none());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn when_many_values_are_inserted_then_balance_step_limit_is_not_exceeded() {
+        let mut ascending = DeviceRegistry::default();
+        let mut shuffled = DeviceRegistry::default();
+        for id in 1..=1000 {
+            ascending.insert(value(id));
+            shuffled.insert(value(id * 7919 % 1009));
+        }
+
+        assert!(ascending.is_valid());
+        assert!(shuffled.is_valid());
+    }
+
+    #[test]
+    fn when_value_exists_then_find_or_insert_returns_stored_value() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(IoTDevice::new(1, "stored", ""));
+        registry.insert(value(2));
+
+        let found = registry.find_or_insert(IoTDevice::new(1, "new", ""));
+
+        assert_eq!(found.address, "stored");
+        assert_eq!(registry.length, 2);
+    }
+
+    #[test]
+    fn when_value_does_not_exist_then_find_or_insert_inserts_value() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(1));
+        registry.insert(value(2));
+
+        let inserted = registry.find_or_insert(IoTDevice::new(3, "new", ""));
+
+        assert_eq!(inserted.address, "new");
+        assert_eq!(registry.length, 3);
+        assert_eq!(registry.find(value(3)).unwrap().address, "new");
+        assert!(registry.is_valid());
+    }
+
+    #[test]
+    fn when_sorted_values_are_inserted_auto_then_rotations_are_reduced() {
+        let mut naive = DeviceRegistry::default();
+        for id in 1..=10000 {
+            naive.insert(value(id));
+        }
+        let mut auto = DeviceRegistry::default();
+
+        auto.insert_auto((1..=10000).map(value));
+
+        assert!(auto.is_valid());
+        assert_eq!(auto.length, 10000);
+        assert!(auto.rotation_count() < naive.rotation_count());
+        assert_eq!(auto.rotation_count(), 0);
+        assert_eq!(auto.find(value(5000)), Some(value(5000)));
+    }
+
+    #[test]
+    fn when_unsorted_values_are_inserted_auto_then_tree_is_valid() {
+        let mut registry = DeviceRegistry::default();
+        for id in [50, 10, 30] {
+            registry.insert(value(id));
+        }
+
+        let values = [20, 5]
+            .into_iter()
+            .chain(51..=100)
+            .chain([40, 1, 2, 3])
+            .chain(101..=110);
+        registry.insert_auto(values.map(value));
+
+        assert!(registry.is_valid());
+        assert_eq!(registry.length, 3 + 2 + 50 + 4 + 10);
+        let mut ids = vec![];
+        registry.walk(|v, _| ids.push(v.numeriacl_id));
+        ids.sort();
+        let mut expected: Vec<u64> = [50, 10, 30, 20, 5, 40, 1, 2, 3]
+            .into_iter()
+            .chain(51..=110)
+            .collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn when_k_nearest_then_values_are_ordered_by_distance() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=20 {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(
+            registry.k_nearest(&value(10), 3),
+            vec![value(10), value(9), value(11)]
+        );
+        assert_eq!(
+            registry.k_nearest(&value(1), 3),
+            vec![value(1), value(2), value(3)]
+        );
+        assert_eq!(
+            registry.k_nearest(&value(25), 2),
+            vec![value(20), value(19)]
+        );
+        assert_eq!(registry.k_nearest(&value(0), 1), vec![value(1)]);
+        assert_eq!(registry.k_nearest(&value(10), 0), vec![]);
+        assert_eq!(registry.k_nearest(&value(10), 100).len(), 20);
+    }
+
+    #[test]
+    fn when_target_is_missing_then_k_nearest_prefers_smaller_on_tie() {
+        let mut registry = DeviceRegistry::default();
+        for id in [2, 4, 6, 8] {
+            registry.insert(value(id));
+        }
+
+        let nearest = registry.k_nearest(&value(5), 4);
+
+        assert_eq!(nearest, vec![value(4), value(6), value(2), value(8)]);
+        assert!(DeviceRegistry::default().k_nearest(&value(5), 3).is_empty());
+    }
+
+    #[test]
+    fn when_iter_colored_then_values_are_in_order_with_colors() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=7 {
+            registry.insert(value(id));
+        }
+
+        let colored: Vec<(u64, bool)> = registry
+            .iter_colored()
+            .map(|(v, is_red)| (v.numeriacl_id, is_red))
+            .collect();
+
+        // 2(B) -> 1(B), 4(R) -> 3(B), 6(B) -> 5(R), 7(R)
+        assert_eq!(
+            colored,
+            vec![
+                (1, false),
+                (2, false),
+                (3, false),
+                (4, true),
+                (5, true),
+                (6, false),
+                (7, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn when_iter_then_values_are_sorted() {
+        let mut registry = DeviceRegistry::default();
+        for id in [5, 3, 8, 1, 4, 7, 9, 2, 6] {
+            registry.insert(value(id));
+        }
+
+        let ids: Vec<u64> = registry.iter().map(|v| v.numeriacl_id).collect();
+
+        assert_eq!(ids, (1..=9).collect::<Vec<_>>());
+        assert_eq!(DeviceRegistry::<IoTDevice>::default().iter().count(), 0);
+    }
+
+    #[test]
+    fn when_tree_has_values_then_min_and_max_return_extremes() {
+        let mut registry = DeviceRegistry::default();
+        for id in [5, 3, 8, 1, 4, 7, 9] {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.min(), Some(value(1)));
+        assert_eq!(registry.max(), Some(value(9)));
+    }
+
+    #[test]
+    fn when_tree_is_empty_then_min_and_max_return_none() {
+        let registry = DeviceRegistry::<IoTDevice>::default();
+        assert_eq!(registry.min(), None);
+        assert_eq!(registry.max(), None);
+    }
+
+    #[test]
+    fn when_tree_is_built_by_insert_then_validate_returns_ok() {
+        let mut registry = DeviceRegistry::default();
+        assert_eq!(registry.validate(), Ok(()));
+        for id in 1..=100 {
+            registry.insert(value(id * 37 % 101));
+            assert_eq!(registry.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn when_root_is_red_then_validate_names_root() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=3 {
+            registry.insert(value(id));
+        }
+        registry.root.as_ref().unwrap().borrow_mut().color = Color::Red;
+
+        assert_eq!(registry.validate(), Err("root is not black: 2".to_string()));
+        assert!(!registry.is_valid());
+    }
+
+    #[test]
+    fn when_red_node_has_red_child_then_validate_names_nodes() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=4 {
+            registry.insert(value(id));
+        }
+        // 2(B) -> 1(B), 3(B) -> 4(R)
+        let three = registry.find_node(&value(3)).unwrap();
+        three.borrow_mut().color = Color::Red;
+
+        assert_eq!(
+            registry.validate(),
+            Err("red node has a red child: 3 -> 4".to_string())
+        );
+    }
+
+    #[test]
+    fn when_black_heights_differ_then_validate_names_node() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=3 {
+            registry.insert(value(id));
+        }
+        let one = registry.find_node(&value(1)).unwrap();
+        one.borrow_mut().color = Color::Black;
+
+        assert_eq!(
+            registry.validate(),
+            Err("black height differs at 2: left 2, right 1".to_string())
+        );
+    }
+
+    #[test]
+    fn when_floor_and_ceiling_then_nearest_candidates_are_returned() {
+        let mut registry = DeviceRegistry::default();
+        for id in [10, 20, 30, 40, 50] {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.floor(&value(30)), Some(value(30)));
+        assert_eq!(registry.ceiling(&value(30)), Some(value(30)));
+        assert_eq!(registry.floor(&value(35)), Some(value(30)));
+        assert_eq!(registry.ceiling(&value(35)), Some(value(40)));
+    }
+
+    #[test]
+    fn when_value_is_below_min_then_floor_returns_none() {
+        let mut registry = DeviceRegistry::default();
+        for id in [10, 20, 30] {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.floor(&value(5)), None);
+        assert_eq!(registry.ceiling(&value(5)), Some(value(10)));
+    }
+
+    #[test]
+    fn when_value_is_above_max_then_ceiling_returns_none() {
+        let mut registry = DeviceRegistry::default();
+        for id in [10, 20, 30] {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.ceiling(&value(35)), None);
+        assert_eq!(registry.floor(&value(35)), Some(value(30)));
+        let empty = DeviceRegistry::<IoTDevice>::default();
+        assert_eq!(empty.floor(&value(1)), None);
+        assert_eq!(empty.ceiling(&value(1)), None);
+    }
+
+    #[test]
+    fn when_select_and_rank_then_order_statistics_match_sequential_ids() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=100 {
+            registry.insert(value(id));
+        }
+
+        assert_eq!(registry.validate(), Ok(()));
+        for k in 0..100 {
+            assert_eq!(registry.select(k), Some(value(k as u64 + 1)));
+        }
+        assert_eq!(registry.select(100), None);
+        for id in 1..=100 {
+            assert_eq!(registry.rank(&value(id)), id as usize - 1);
+        }
+        assert_eq!(registry.rank(&value(0)), 0);
+        assert_eq!(registry.rank(&value(1000)), 100);
+    }
+
+    #[test]
+    fn when_values_are_inserted_in_any_order_then_sizes_follow_rotations() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=100 {
+            registry.insert(value(id * 37 % 101));
+            assert_eq!(registry.validate(), Ok(()));
+        }
+
+        assert_eq!(registry.select(0), Some(value(1)));
+        assert_eq!(registry.select(49), Some(value(50)));
+        assert_eq!(registry.rank(&value(50)), 49);
+        let from_heap = DeviceRegistry::from_heap_array((1..=10).map(value).collect());
+        assert_eq!(from_heap.validate(), Ok(()));
+        assert_eq!(from_heap.select(9), Some(value(10)));
+    }
+
+    #[test]
+    fn when_update_existing_value_then_previous_value_is_returned() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=10 {
+            registry.insert(value(id));
+        }
+        let updated = IoTDevice::new(5, "updated", "/updated");
+
+        let previous = registry.update(updated.clone());
+
+        assert_eq!(previous.map(|d| d.address), Some(String::new()));
+        assert_eq!(
+            registry.find(value(5)).map(|d| d.address),
+            Some("updated".to_owned())
+        );
+        assert_eq!(registry.length, 10);
+        assert_eq!(registry.validate(), Ok(()));
+    }
+
+    #[test]
+    fn when_update_missing_value_then_none_is_returned() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(1));
+
+        assert_eq!(registry.update(value(2)), None);
+        assert_eq!(registry.find(value(2)), None);
+    }
+
+    #[test]
+    fn when_values_are_inserted_then_contains_and_len_reflect_them() {
+        let mut registry = DeviceRegistry::default();
+        assert!(registry.is_empty());
+        assert_eq!(registry.len(), 0);
+        assert!(!registry.contains(&value(1)));
+
+        for id in 1..=10 {
+            registry.insert(value(id));
+        }
+
+        assert!(!registry.is_empty());
+        assert_eq!(registry.len(), 10);
+        assert!((1..=10).all(|id| registry.contains(&value(id))));
+        assert!(!registry.contains(&value(0)));
+        assert!(!registry.contains(&value(11)));
+    }
+
+    #[test]
+    fn when_tree_is_empty_then_heights_are_zero() {
+        let registry: DeviceRegistry<IoTDevice> = DeviceRegistry::default();
+
+        assert_eq!(registry.height(), 0);
+        assert_eq!(registry.black_height(), 0);
+    }
+
+    #[test]
+    fn when_values_are_inserted_then_heights_stay_balanced() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(1));
+        assert_eq!((registry.height(), registry.black_height()), (0, 1));
+
+        for id in 2..=100 {
+            registry.insert(value(id));
+        }
+
+        // 赤黒木の高さは 2 * log2(n + 1) 以下、黒ノードの数は高さ + 1 以下
+        assert!(registry.height() <= 2 * 101_usize.ilog2() as usize);
+        assert!(registry.black_height() <= registry.height() + 1);
+        assert!(registry.black_height() * 2 > registry.height());
+    }
+
+    #[test]
+    fn when_iter_rev_then_values_are_in_descending_order() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=20 {
+            registry.insert(value(id));
+        }
+
+        let ids: Vec<u64> = registry.iter_rev().map(|d| d.numeriacl_id).collect();
+
+        assert_eq!(ids, (1..=20).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn when_from_sorted_then_tree_is_valid_with_minimum_height() {
+        for length in [0_u64, 1, 2, 3, 7, 8, 100] {
+            let registry = DeviceRegistry::from_sorted((1..=length).map(value).collect());
+
+            assert_eq!(registry.validate(), Ok(()));
+            assert_eq!(registry.len(), length as usize);
+            assert_eq!(registry.rotation_count(), 0);
+            let expected_height = if length > 0 {
+                length.ilog2() as usize
+            } else {
+                0
+            };
+            assert_eq!(registry.height(), expected_height);
+            assert_eq!(
+                registry.iter().map(|d| d.numeriacl_id).collect::<Vec<_>>(),
+                (1..=length).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn when_equal_value_is_inserted_then_count_is_incremented() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(IoTDevice::new(1, "first", ""));
+        registry.insert(value(2));
+
+        registry.insert(IoTDevice::new(1, "second", ""));
+
+        assert_eq!(registry.count(&value(1)), 2);
+        assert_eq!(registry.count(&value(2)), 1);
+        assert_eq!(registry.count(&value(3)), 0);
+        // ノードは追加されず、最初に挿入した値を保持する
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.find(value(1)).unwrap().address, "first");
+        assert_eq!(registry.iter().count(), 2);
+        assert_eq!(registry.validate(), Ok(()));
+    }
+
+    #[test]
+    fn when_equal_value_is_inserted_then_tree_is_walked_once() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=7 {
+            registry.insert(value(id));
+        }
+        registry.reset_stats();
+        registry.find(value(1));
+        let found = registry.comparison_count();
+
+        registry.reset_stats();
+        registry.insert(value(1));
+
+        // 等しい値を見つけるまでの1回の探索と同じ比較回数になる
+        assert_eq!(registry.comparison_count(), found);
+        assert_eq!(registry.count(&value(1)), 2);
+        assert_eq!(registry.len(), 7);
+    }
+
+    #[test]
+    fn when_duplicated_value_is_removed_then_count_is_decremented() {
+        let mut registry = DeviceRegistry::default();
+        registry.insert(value(1));
+        registry.insert(value(1));
+
+        assert_eq!(registry.remove(&value(1)), Some(value(1)));
+        assert_eq!(registry.count(&value(1)), 1);
+        assert_eq!(registry.len(), 1);
+
+        assert_eq!(registry.remove(&value(1)), Some(value(1)));
+        assert_eq!(registry.count(&value(1)), 0);
+        assert!(registry.is_empty());
+        assert_eq!(registry.root_value(), None);
+        assert_eq!(registry.remove(&value(1)), None);
+    }
+
+    #[test]
+    fn when_values_are_removed_then_tree_stays_valid() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=200 {
+            registry.insert(value(id * 37 % 211));
+        }
+
+        for id in 1..=200 {
+            let removed = id * 53 % 211;
+            if registry.contains(&value(removed)) {
+                assert_eq!(registry.remove(&value(removed)), Some(value(removed)));
+                assert!(!registry.contains(&value(removed)));
+                assert_eq!(registry.validate(), Ok(()));
+            }
+        }
+
+        let remaining: Vec<u64> = registry.iter().map(|d| d.numeriacl_id).collect();
+        assert_eq!(remaining.len(), registry.len());
+        for (k, id) in remaining.iter().enumerate() {
+            assert_eq!(registry.select(k), Some(value(*id)));
+        }
+    }
+
+    #[test]
+    fn when_sorted_values_have_duplicates_then_from_sorted_keeps_counts() {
+        let registry = DeviceRegistry::from_sorted([1, 1, 2, 3, 3, 3].map(value).to_vec());
+
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.count(&value(1)), 2);
+        assert_eq!(registry.count(&value(3)), 3);
+        assert_eq!(registry.validate(), Ok(()));
+    }
 
-        registry.rotate(node.clone(), super::Rotation::Right);
+    #[test]
+    fn when_to_json_then_tree_structure_is_nested_json() {
+        let mut registry = DeviceRegistry::default();
+        assert_eq!(registry.to_json(), "null");
 
-        // Assert
-        assert_eq!(registry.length, 6);
-        let new_p = registry.root.as_ref().unwrap().clone();
-        assert!(new_p.borrow().is_root());
-        let new_pl = new_p.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_pl.borrow().v, l);
-        let new_ll = new_pl.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_ll.borrow().v, gl);
-        let new_lr = new_pl.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_lr.borrow().v, n);
-        let new_nl = new_lr.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_nl.borrow().v, gr);
-        let new_nr = new_lr.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_nr.borrow().v, r);
+        registry.insert(value(1));
+        registry.insert(value(2));
+        registry.insert(value(3));
+
+        assert_eq!(
+            registry.to_json(),
+            concat!(
+                r#"{"color":"Black","value":"2","#,
+                r#""left":{"color":"Red","value":"1","left":null,"right":null},"#,
+                r#""right":{"color":"Red","value":"3","left":null,"right":null}}"#
+            )
+        );
     }
 
     #[test]
-    fn test_rotate_when_node_is_left_child_then_rotate_right_should_make_right_child_of_parent_left_child_of_node(
-    ) {
-        // 6
-        //  r 10
-        //    l 8
-        //      l 7
-        //      r 9
-        //    r 11
-        // after rotate based on 10
-        // 6
-        //  r 8
-        //    l 7
-        //    r 10
-        //      l 9
-        //      r 11
+    fn when_value_has_special_characters_then_to_json_escapes_them() {
+        let mut json = String::new();
 
-        init();
-        let mut registry = DeviceRegistry::default();
-        let p = value(6);
-        registry.insert_internal(p.clone());
-        let n = value(10);
-        registry.insert_internal(n.clone());
-        let r = value(11);
-        registry.insert_internal(r.clone());
-        let l = value(8);
-        registry.insert_internal(l.clone());
-        let gl = value(7);
-        registry.insert_internal(gl.clone());
-        let gr = value(9);
-        registry.insert_internal(gr.clone());
+        super::push_json_string(&mut json, "a\"b\\c\n");
 
-        // Act
-        let node = registry
-            .root
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .right
-            .as_ref()
-            .unwrap()
-            .clone();
+        assert_eq!(json, r#""a\"b\\c\n""#);
+    }
 
-        registry.rotate(node.clone(), super::Rotation::Right);
+    #[test]
+    fn when_with_key_then_values_are_ordered_by_extracted_key() {
+        let mut registry = DeviceRegistry::with_key(|d: &IoTDevice| d.address.clone());
+        registry.insert(IoTDevice::new(1, "c", ""));
+        registry.insert(IoTDevice::new(2, "a", ""));
+        registry.insert(IoTDevice::new(3, "b", ""));
 
-        // Assert
-        assert_eq!(registry.length, 6);
-        let new_p = registry.root.as_ref().unwrap().clone();
-        assert!(new_p.borrow().is_root());
-        let new_pl = new_p.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_pl.borrow().v, l);
-        let new_ll = new_pl.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_ll.borrow().v, gl);
-        let new_lr = new_pl.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_lr.borrow().v, n);
-        let new_nl = new_lr.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_nl.borrow().v, gr);
-        let new_nr = new_lr.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_nr.borrow().v, r);
+        let ids: Vec<u64> = registry.iter().map(|d| d.numeriacl_id).collect();
+
+        assert_eq!(ids, vec![2, 3, 1]);
+        assert_eq!(registry.validate(), Ok(()));
+        // idが異なっていてもキーが等しければ同じ値とみなす
+        assert_eq!(
+            registry
+                .find(IoTDevice::new(9, "a", ""))
+                .map(|d| d.numeriacl_id),
+            Some(2)
+        );
+        assert_eq!(
+            registry
+                .remove(&IoTDevice::new(9, "b", ""))
+                .map(|d| d.numeriacl_id),
+            Some(3)
+        );
+        assert_eq!(registry.len(), 2);
     }
 
     #[test]
-    fn test_rotate_when_node_is_root_rotate_right_should_make_left_child_root() {
-        // 6
-        //  l 4
-        //    l 2
-        //    r 5
-        //  r 10
-        // after rotate based on 6
-        // 4
-        //  l 2
-        //  r 6
-        //    l 5
-        //    r 10
+    fn when_value_does_not_implement_ord_then_with_key_can_order_it() {
+        #[derive(Clone, Debug)]
+        struct Reading {
+            id: u64,
+            celsius: f64,
+        }
+        impl core::fmt::Display for Reading {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                write!(f, "{}: {}", self.id, self.celsius)
+            }
+        }
+        let mut registry = DeviceRegistry::with_key(|r: &Reading| r.id);
 
-        init();
+        for id in (1..=20).rev() {
+            registry.insert(Reading {
+                id,
+                celsius: id as f64 / 2.0,
+            });
+        }
+
+        assert_eq!(registry.validate(), Ok(()));
+        assert_eq!(registry.min().map(|r| r.id), Some(1));
+        assert_eq!(registry.select(9).map(|r| r.celsius), Some(5.0));
+    }
+
+    #[test]
+    fn when_value_exists_then_successor_and_predecessor_are_neighbors() {
         let mut registry = DeviceRegistry::default();
-        let n = value(6);
-        registry.insert_internal(n.clone());
-        let l = value(4);
-        registry.insert_internal(l.clone());
-        let r = value(10);
-        registry.insert_internal(r.clone());
-        let gl = value(2);
-        registry.insert_internal(gl.clone());
-        let gr = value(5);
-        registry.insert_internal(gr.clone());
+        for id in 1..=20 {
+            registry.insert(value(id * 2));
+        }
 
-        // Act
-        let node = registry.root.as_ref().unwrap().clone();
+        for id in 2..20 {
+            assert_eq!(registry.successor(&value(id * 2)), Some(value(id * 2 + 2)));
+            assert_eq!(
+                registry.predecessor(&value(id * 2)),
+                Some(value(id * 2 - 2))
+            );
+        }
+        assert_eq!(registry.successor(&value(40)), None);
+        assert_eq!(registry.predecessor(&value(2)), None);
+    }
 
-        registry.rotate(node.clone(), super::Rotation::Right);
+    #[test]
+    fn when_value_does_not_exist_then_successor_and_predecessor_use_insert_position() {
+        let mut registry = DeviceRegistry::default();
+        for id in 1..=20 {
+            registry.insert(value(id * 2));
+        }
 
-        // Assert
-        let new_p = registry
-            .root
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .parent
-            .as_ref()
-            .unwrap()
-            .clone();
-        assert!(new_p.borrow().is_root());
-        assert_eq!(new_p.borrow().v, l);
-        let new_l = new_p
-            .borrow()
-            .right
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .left
-            .as_ref()
-            .unwrap()
-            .clone();
-        assert_eq!(new_l.borrow().v, gr);
+        assert_eq!(registry.successor(&value(7)), Some(value(8)));
+        assert_eq!(registry.predecessor(&value(7)), Some(value(6)));
+        assert_eq!(registry.successor(&value(0)), Some(value(2)));
+        assert_eq!(registry.predecessor(&value(0)), None);
+        assert_eq!(registry.successor(&value(41)), None);
+        assert_eq!(registry.predecessor(&value(41)), Some(value(40)));
     }
 
     #[test]
-    fn test_rotate_when_node_is_left_child_then_rotate_left_should_make_right_child_of_parent_right_child_of_node(
-    ) {
-        // 6
-        //  r 10
-        //    l 8
-        //    r 12
-        //      l 11
-        //      r 13
-        // after rotate based on 10
-        // 6
-        //  r 12
-        //    l 10
-        //      l 8
-        //      r 11
-        //    r 13
-        init();
+    fn when_clear_then_all_values_are_removed() {
         let mut registry = DeviceRegistry::default();
-        let p = value(6);
-        registry.insert_internal(p.clone());
-        let n = value(10);
-        registry.insert_internal(n.clone());
-        let l = value(8);
-        registry.insert_internal(l.clone());
-        let r = value(12);
-        registry.insert_internal(r.clone());
-        let gl = value(11);
-        registry.insert_internal(gl.clone());
-        let gr = value(13);
-        registry.insert_internal(gr.clone());
+        for id in 1..=1000 {
+            registry.insert(value(id));
+        }
+        let root = Rc::downgrade(registry.root.as_ref().unwrap());
 
-        // Act
-        let node = registry
-            .root
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .right
-            .as_ref()
-            .unwrap()
-            .clone();
+        registry.clear();
 
-        registry.rotate(node.clone(), super::Rotation::Left);
+        assert_eq!(registry.length, 0);
+        assert!(registry.is_empty());
+        assert_eq!(registry.find(value(1)), None);
+        assert_eq!(registry.find(value(500)), None);
+        // 循環参照が切り離され、ノードが解放されている
+        assert!(root.upgrade().is_none());
 
-        // Assert
-        assert_eq!(registry.length, 6);
-        let new_p = registry.root.as_ref().unwrap().clone();
-        assert!(new_p.borrow().is_root());
-        assert_eq!(new_p.borrow().v, p);
-        let new_pr = new_p.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_pr.borrow().v, r);
-        let new_rl = new_pr.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_rl.borrow().v, n);
-        let new_rr = new_pr.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_rr.borrow().v, gr);
-        let new_nl = new_rl.borrow().left.as_ref().unwrap().clone();
-        assert_eq!(new_nl.borrow().v, l);
-        let new_nr = new_rl.borrow().right.as_ref().unwrap().clone();
-        assert_eq!(new_nr.borrow().v, gl);
+        registry.insert(value(1));
+        assert_eq!(registry.find(value(1)), Some(value(1)));
+    }
+
+    /// 生存しているインスタンスの数を数える値
+    #[derive(Debug)]
+    struct Tracked {
+        id: u64,
+        live: Rc<core::cell::Cell<usize>>,
+    }
+
+    impl Tracked {
+        fn new(id: u64, live: &Rc<core::cell::Cell<usize>>) -> Tracked {
+            live.set(live.get() + 1);
+            Tracked {
+                id,
+                live: live.clone(),
+            }
+        }
+    }
+
+    impl Clone for Tracked {
+        fn clone(&self) -> Self {
+            Tracked::new(self.id, &self.live)
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.live.set(self.live.get() - 1);
+        }
+    }
+
+    impl core::fmt::Display for Tracked {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            write!(f, "{}", self.id)
+        }
+    }
+
+    impl PartialEq for Tracked {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for Tracked {}
+
+    impl PartialOrd for Tracked {
+        fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Tracked {
+        fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+            self.id.cmp(&other.id)
+        }
     }
 
     #[test]
-    fn when_only_root_node_and_search_value_exists_then_returns_the_matched_element() {
-        let mut registry = DeviceRegistry::default();
-        registry.insert(value(5));
-        let result = registry.find(value(5));
-        assert_eq!(result, Some(value(5)));
+    fn when_registry_is_dropped_then_all_nodes_are_deallocated() {
+        let live = Rc::new(core::cell::Cell::new(0));
+        {
+            let mut registry = DeviceRegistry::default();
+            for id in 1..=100 {
+                registry.insert(Tracked::new(id, &live));
+            }
+            for id in 1..=30 {
+                registry.remove(&Tracked::new(id * 3, &live));
+            }
+            assert_eq!(registry.validate(), Ok(()));
+            assert_eq!(live.get(), 70);
+        }
+
+        assert_eq!(live.get(), 0);
     }
 
     #[test]
-    fn when_some_nodes_and_search_value_exists_then_returns_the_matched_element() {
+    fn when_tree_is_empty_then_root_value_returns_none() {
+        let registry = DeviceRegistry::<IoTDevice>::default();
+        assert_eq!(registry.root_value(), None);
+    }
+
+    #[test]
+    fn when_tree_is_rebalanced_then_root_value_returns_new_root() {
         let mut registry = DeviceRegistry::default();
-        registry.insert(value(5));
-        registry.insert(value(6));
-        registry.insert(value(4));
-        let result = registry.find(value(6));
-        assert_eq!(result, Some(value(6)));
+        registry.insert(value(1));
+        registry.insert(value(2));
+        registry.insert(value(3));
+        assert_eq!(registry.root_value(), Some(value(2)));
     }
 
     #[test]
-    fn when_threre_are_some_nodes_and_search_value_does_not_exist_then_returns_none() {
+    fn when_complex_tree_is_rebalanced_then_root_value_returns_new_root() {
         let mut registry = DeviceRegistry::default();
-        registry.insert(value(5));
-        registry.insert(value(6));
-        registry.insert(value(4));
-        let result = registry.find(value(7));
-        assert_eq!(result, None);
+        for id in 1..=8 {
+            registry.insert(value(id));
+        }
+        assert_eq!(registry.root_value(), Some(value(4)));
     }
 }
@@ -1,12 +1,13 @@
-use log::debug;
-use std::collections::BTreeMap;
+use alloc::collections::{btree_map, BTreeMap};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::ops::Bound;
 
 enum InsertResult<V> {
     Added,
     Updated(V),
 }
 
-enum TrieNode<V> {
+enum TrieNode<K, V> {
     /// 中間ノード。文字列の途中の文字を表し、値は持たない
     ///
     /// 例: "rust"と"rust-lang"という文字列を格納する場合
@@ -35,7 +36,9 @@ enum TrieNode<V> {
     ///      g[E]  <- "rust-lang"の終端
     /// ```
     Internal {
-        next: BTreeMap<char, Box<TrieNode<V>>>,
+        next: BTreeMap<K, Box<TrieNode<K, V>>>,
+        /// 自ノードを含む部分木に含まれるEntryノードの数
+        entries: usize,
     },
     /// エントリーノード。文字列の最後の文字を表し、値を持つ。
     /// 他の文字列の途中の文字である可能性があるため、nextも持つ
@@ -46,42 +49,73 @@ enum TrieNode<V> {
     /// - g[E] はEntryノード（"rust-lang"のエントリー）でnextは空
     Entry {
         value: V,
-        next: BTreeMap<char, Box<TrieNode<V>>>,
+        next: BTreeMap<K, Box<TrieNode<K, V>>>,
+        /// 自ノードを含む部分木に含まれるEntryノードの数
+        entries: usize,
     },
 }
 
-impl<V> TrieNode<V> {
+impl<K, V> TrieNode<K, V> {
     fn new_internal() -> Self {
         Self::Internal {
             next: BTreeMap::new(),
+            entries: 0,
         }
     }
 
-    fn next(&self) -> &BTreeMap<char, Box<TrieNode<V>>> {
+    fn next(&self) -> &BTreeMap<K, Box<TrieNode<K, V>>> {
         match self {
-            Self::Internal { next } => next,
+            Self::Internal { next, .. } => next,
             Self::Entry { next, .. } => next,
         }
     }
 
-    fn next_mut(&mut self) -> &mut BTreeMap<char, Box<TrieNode<V>>> {
+    fn next_mut(&mut self) -> &mut BTreeMap<K, Box<TrieNode<K, V>>> {
         match self {
-            Self::Internal { next } => next,
+            Self::Internal { next, .. } => next,
             Self::Entry { next, .. } => next,
         }
     }
 
+    /// 自ノードを含む部分木に含まれるEntryノードの数を取得します
+    fn entries(&self) -> usize {
+        match self {
+            Self::Internal { entries, .. } => *entries,
+            Self::Entry { entries, .. } => *entries,
+        }
+    }
+
+    fn entries_mut(&mut self) -> &mut usize {
+        match self {
+            Self::Internal { entries, .. } => entries,
+            Self::Entry { entries, .. } => entries,
+        }
+    }
+
+    /// 子ノードとエントリー数を同時に可変で借用します
+    fn next_and_entries_mut(&mut self) -> (&mut Children<K, V>, &mut usize) {
+        match self {
+            Self::Internal { next, entries } => (next, entries),
+            Self::Entry { next, entries, .. } => (next, entries),
+        }
+    }
+
     fn make_entry(&mut self, value: V) -> InsertResult<V> {
         match self {
-            Self::Internal { next } => {
-                let next = std::mem::take(next);
-                *self = Self::Entry { value, next };
+            Self::Internal { next, entries } => {
+                let next = core::mem::take(next);
+                let entries = *entries;
+                *self = Self::Entry {
+                    value,
+                    next,
+                    entries,
+                };
                 InsertResult::Added
             }
             Self::Entry {
                 value: old_value, ..
             } => {
-                let old = std::mem::replace(old_value, value);
+                let old = core::mem::replace(old_value, value);
                 InsertResult::Updated(old)
             }
         }
@@ -92,9 +126,10 @@ impl<V> TrieNode<V> {
     fn take_value(&mut self) -> Option<V> {
         match self {
             Self::Internal { .. } => None,
-            Self::Entry { next, .. } => {
-                let next = std::mem::take(next);
-                let temp = std::mem::replace(self, Self::Internal { next });
+            Self::Entry { next, entries, .. } => {
+                let next = core::mem::take(next);
+                let entries = *entries;
+                let temp = core::mem::replace(self, Self::Internal { next, entries });
                 if let Self::Entry { value, .. } = temp {
                     Some(value)
                 } else {
@@ -111,6 +146,13 @@ impl<V> TrieNode<V> {
         }
     }
 
+    fn value_mut(&mut self) -> Option<&mut V> {
+        match self {
+            Self::Internal { .. } => None,
+            Self::Entry { value, .. } => Some(value),
+        }
+    }
+
     #[allow(dead_code)]
     fn is_internal(&self) -> bool {
         matches!(self, Self::Internal { .. })
@@ -119,27 +161,233 @@ impl<V> TrieNode<V> {
     /// ノードが値を持つまたは他の文字列の途中の文字列の中間ノードとして使用されているとき、trueを返します
     fn is_used(&self) -> bool {
         match self {
-            Self::Internal { next } => !next.is_empty(),
+            Self::Internal { next, .. } => !next.is_empty(),
             Self::Entry { .. } => true,
         }
     }
 }
 
-pub struct TrieTree<V> {
+/// TrieTreeの操作に失敗した場合のエラー
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrieError {
+    /// キーが空である
+    /// 空のキーはrootの専用の場所に保持できるため、現在はどの操作もこのエラーを返しません
+    EmptyKey,
+}
+
+impl core::fmt::Display for TrieError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::EmptyKey => write!(f, "key is empty"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TrieError {}
+
+/// 子ノードのマップ
+type Children<K, V> = BTreeMap<K, Box<TrieNode<K, V>>>;
+
+/// キーと値のペアを保持するトライ木
+/// キーは記号Kの列で、デフォルトの`TrieTree<V>`は文字列 (`char`の列) をキーにします。
+/// `TrieTree<V, u8>`のようにバイト列をキーにする場合は`add_symbols`などの記号の列を受け取る操作を使います
+pub struct TrieTree<V, K = char> {
     length: usize,
-    root: BTreeMap<char, Box<TrieNode<V>>>,
+    root: BTreeMap<K, Box<TrieNode<K, V>>>,
+    /// 空のキーの値。rootはノードを持たないため、専用の場所に保持する
+    root_value: Option<V>,
+    /// trueの場合は、キーを小文字に正規化してから辿る
+    case_insensitive: bool,
+    /// 変更のたびに増加する世代番号。カーソルが途中で変更されたことを検出するために使用する
+    generation: u64,
 }
 
-impl<V> Default for TrieTree<V> {
+impl<V, K> Default for TrieTree<V, K> {
     fn default() -> Self {
         Self {
             length: usize::default(),
             root: BTreeMap::new(),
+            root_value: None,
+            case_insensitive: false,
+            generation: 0,
         }
     }
 }
 
-impl<V> TrieTree<V> {
+/// ペアを順番に`add`してTrieTreeを作成します。同じキーが複数ある場合は後の値が残ります
+///
+/// # 例
+/// ```
+/// # use ch05_rubust_trees::trie::TrieTree;
+/// let trie: TrieTree<i32> = vec![("rust".to_string(), 1), ("rust".to_string(), 2)]
+///     .into_iter()
+///     .collect();
+///
+/// assert_eq!(trie.len(), 1);
+/// assert_eq!(trie.find("rust"), Some(&2));
+/// ```
+impl<V> FromIterator<(String, V)> for TrieTree<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let mut trie = TrieTree::default();
+        for (key, value) in iter {
+            trie.add(key, value);
+        }
+        trie
+    }
+}
+
+/// TrieTreeを借用せずに、prefixから始まるエントリーをキーの辞書順に辿るカーソル
+///
+/// カーソルは最後に返したキーだけを保持するため、TrieTreeを借用し続けません。
+/// そのかわり、カーソル作成後にTrieTreeが変更された場合は`next_entry`がpanicします
+///
+/// # 例
+/// ```
+/// # use ch05_rubust_trees::trie::TrieTree;
+/// let mut trie = TrieTree::default();
+/// trie.add("rust".to_string(), 1);
+/// trie.add("rust-lang".to_string(), 2);
+/// trie.add("ruby".to_string(), 3);
+///
+/// let mut cursor = trie.cursor("rus");
+/// assert_eq!(cursor.next_entry(&trie), Some(("rust".to_string(), &1)));
+/// assert_eq!(cursor.next_entry(&trie), Some(("rust-lang".to_string(), &2)));
+/// assert_eq!(cursor.next_entry(&trie), None);
+/// ```
+pub struct TrieCursor {
+    prefix: Vec<char>,
+    last: Option<Vec<char>>,
+    generation: u64,
+}
+
+impl TrieCursor {
+    /// 次のエントリーを取得します
+    ///
+    /// # Panics
+    /// カーソル作成後にTrieTreeが変更されていた場合
+    pub fn next_entry<'a, V>(&mut self, trie: &'a TrieTree<V>) -> Option<(String, &'a V)> {
+        assert_eq!(
+            self.generation, trie.generation,
+            "trie was mutated while a cursor was active"
+        );
+        let (value, next) = if self.prefix.is_empty() {
+            (trie.root_value.as_ref(), &trie.root)
+        } else {
+            let node = trie.get_last_node(&self.prefix)?;
+            (node.value(), node.next())
+        };
+        let after = self.last.as_ref().map(|last| &last[self.prefix.len()..]);
+        let mut key = self.prefix.clone();
+        let value = TrieTree::first_entry_after(value, next, &mut key, after)?;
+        let result = key.iter().collect();
+        self.last = Some(key);
+        Some((result, value))
+    }
+}
+
+/// `TrieTree::entry`で取得する、キーに対応する値の格納場所
+///
+/// # 例
+/// ```
+/// # use ch05_rubust_trees::trie::TrieTree;
+/// let mut trie = TrieTree::default();
+/// for word in ["rust", "ruby", "rust"] {
+///     *trie.entry(word.to_string()).or_insert(0) += 1;
+/// }
+///
+/// assert_eq!(trie.find("rust"), Some(&2));
+/// assert_eq!(trie.find("ruby"), Some(&1));
+/// ```
+pub enum Entry<'a, V, K = char> {
+    /// キーが存在する
+    Occupied(OccupiedEntry<'a, V>),
+    /// キーが存在しない
+    Vacant(VacantEntry<'a, V, K>),
+}
+
+/// 値を持つEntryノードの値への参照
+pub struct OccupiedEntry<'a, V> {
+    value: &'a mut V,
+}
+
+/// 値を挿入するキー。挿入するまでノードは作成しません
+/// キーを辿った結果 (経路上のエントリー数と、到達した位置) を保持するため、挿入時にrootから辿り直しません
+pub struct VacantEntry<'a, V, K = char> {
+    length: &'a mut usize,
+    generation: &'a mut u64,
+    /// 到達した位置より上にあるノードのエントリー数
+    path_entries: Vec<&'a mut usize>,
+    slot: VacantSlot<'a, V, K>,
+}
+
+/// 値を挿入する位置
+enum VacantSlot<'a, V, K> {
+    /// 空のキー
+    Root(&'a mut Option<V>),
+    /// キーの最後の文字のノードは存在するが、値を持たない
+    Node(&'a mut TrieNode<K, V>),
+    /// 存在しない最初の文字の位置と、それより後ろの文字の列
+    Missing(btree_map::VacantEntry<'a, K, Box<TrieNode<K, V>>>, Vec<K>),
+}
+
+impl<'a, V, K: Ord + Copy> VacantEntry<'a, V, K> {
+    /// 値を挿入し、挿入した値への参照を返します
+    /// 存在しないノードだけを作成し、エントリー数は辿った経路上でのみ更新します
+    pub fn insert(self, value: V) -> &'a mut V {
+        *self.length += 1;
+        *self.generation += 1;
+        for entries in self.path_entries {
+            *entries += 1;
+        }
+        let current = match self.slot {
+            VacantSlot::Root(slot) => return slot.insert(value),
+            VacantSlot::Node(node) => node,
+            VacantSlot::Missing(vacant, rest) => {
+                let mut current = vacant.insert(Box::new(TrieNode::new_internal()));
+                for c in rest {
+                    *current.entries_mut() += 1;
+                    current = current
+                        .next_mut()
+                        .entry(c)
+                        .or_insert_with(|| Box::new(TrieNode::new_internal()));
+                }
+                current
+            }
+        };
+        *current.entries_mut() += 1;
+        current.make_entry(value);
+        current.value_mut().expect("node must be an entry")
+    }
+}
+
+impl<'a, V, K: Ord + Copy> Entry<'a, V, K> {
+    /// キーが存在しない場合はdefaultを挿入し、値への参照を返します
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// キーが存在しない場合はdefaultの結果を挿入し、値への参照を返します
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.value,
+            Self::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// キーが存在する場合は値にfを適用します
+    pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Self {
+        match self {
+            Self::Occupied(entry) => {
+                f(entry.value);
+                Self::Occupied(entry)
+            }
+            Self::Vacant(entry) => Self::Vacant(entry),
+        }
+    }
+}
+
+impl<V, K: Ord + Copy> TrieTree<V, K> {
     pub fn is_empty(&self) -> bool {
         self.length == 0
     }
@@ -148,18 +396,25 @@ impl<V> TrieTree<V> {
         self.length
     }
 
-    pub fn add(&mut self, key: String, v: V) {
-        assert!(!key.is_empty(), "key must not be empty");
-        debug!("[trie::add] key: {}", key);
-
-        let chars: Vec<char> = key.chars().collect();
+    /// 記号の列をキーとして値を追加します
+    /// すでにキーが存在する場合は値を置き換え、古い値を返します
+    /// 空のキーの値はrootの専用の場所に保持します
+    pub fn add_symbols(&mut self, key: &[K], v: V) -> Option<V> {
+        self.generation += 1;
+        if key.is_empty() {
+            let old = self.root_value.replace(v);
+            if old.is_none() {
+                self.length += 1;
+            }
+            return old;
+        }
         let mut current = self
             .root
-            .entry(chars[0])
+            .entry(key[0])
             .or_insert_with(|| Box::new(TrieNode::new_internal()));
 
         // 2文字目以降があれば処理
-        for &c in chars[1..].iter() {
+        for &c in key[1..].iter() {
             let next = current
                 .next_mut()
                 .entry(c)
@@ -168,58 +423,101 @@ impl<V> TrieTree<V> {
         }
 
         // currentは常に最後の文字のノードを指している
-        let result = current.make_entry(v);
-        match result {
+        match current.make_entry(v) {
             InsertResult::Added => {
                 self.length += 1;
-                debug!("added: {key}");
+                self.update_entries(key, |entries| entries + 1);
+                None
             }
-            InsertResult::Updated(_) => debug!("updated: {key}"),
+            InsertResult::Updated(old) => Some(old),
         }
     }
 
-    pub fn find(&self, s: &str) -> Option<&V> {
-        debug!("[trie::find] s: {}", s);
-        let chars: Vec<char> = s.chars().collect();
+    /// 記号の列をキーとして値の格納場所を取得します
+    /// キーの経路は一度だけ辿り、キーが存在しない場合の挿入は到達した位置から行います
+    pub fn entry_symbols(&mut self, key: &[K]) -> Entry<'_, V, K> {
+        let Self {
+            length,
+            root,
+            root_value,
+            generation,
+            ..
+        } = self;
+        let Some((&first, rest)) = key.split_first() else {
+            return match root_value {
+                Some(value) => Entry::Occupied(OccupiedEntry { value }),
+                None => Entry::Vacant(VacantEntry {
+                    length,
+                    generation,
+                    path_entries: Vec::new(),
+                    slot: VacantSlot::Root(root_value),
+                }),
+            };
+        };
+
+        let mut path_entries = Vec::new();
+        let mut next = root;
+        let mut c = first;
+        let mut rest = rest.iter();
+        let node = loop {
+            let node = match next.entry(c) {
+                btree_map::Entry::Occupied(occupied) => occupied.into_mut(),
+                btree_map::Entry::Vacant(vacant) => {
+                    return Entry::Vacant(VacantEntry {
+                        length,
+                        generation,
+                        path_entries,
+                        slot: VacantSlot::Missing(vacant, rest.copied().collect()),
+                    });
+                }
+            };
+            match rest.next() {
+                Some(&following) => {
+                    let (children, entries) = node.next_and_entries_mut();
+                    path_entries.push(entries);
+                    next = children;
+                    c = following;
+                }
+                None => break node,
+            }
+        };
 
-        if chars.is_empty() {
-            return None;
+        if node.value().is_some() {
+            Entry::Occupied(OccupiedEntry {
+                value: node.value_mut().expect("node must be an entry"),
+            })
+        } else {
+            Entry::Vacant(VacantEntry {
+                length,
+                generation,
+                path_entries,
+                slot: VacantSlot::Node(node),
+            })
         }
+    }
 
-        let mut current = self.root.get(&chars[0])?;
-
-        for &c in chars[1..].iter() {
-            current = current.next().get(&c)?;
+    /// 記号の列をキーとして値を取得します
+    pub fn find_symbols(&self, key: &[K]) -> Option<&V> {
+        if key.is_empty() {
+            return self.root_value.as_ref();
         }
-
-        current.value()
+        self.get_last_node(key)?.value()
     }
 
-    /// キーに対応する値を削除します
-    ///
-    /// # 例
-    /// ```
-    /// # use ch05_rubust_trees::trie::TrieTree;
-    /// let mut trie = TrieTree::default();
-    /// trie.add("rust".to_string(), 1);
-    /// trie.add("rust-lang".to_string(), 2);
-    ///
-    /// assert_eq!(trie.remove("rust"), Some(1));  // "rust"を削除。"rust-lang"は保持
-    /// assert_eq!(trie.find("rust"), None);       // "rust"は見つからない
-    /// assert_eq!(trie.find("rust-lang"), Some(&2)); // "rust-lang"はまだ存在
-    /// ```
-    pub fn remove(&mut self, key: &str) -> Option<V> {
-        debug!("[trie::remove] key: {}", key);
+    /// 記号の列をキーとして値を削除します
+    pub fn remove_symbols(&mut self, key: &[K]) -> Option<V> {
         if key.is_empty() {
-            return None;
+            let value = self.root_value.take()?;
+            self.length -= 1;
+            self.generation += 1;
+            return Some(value);
         }
-
-        let chars: Vec<char> = key.chars().collect();
-
-        let last_node = self.get_last_node_mut(&chars)?;
+        let last_node = self.get_last_node_mut(key)?;
         let value = last_node.take_value()?;
         let is_last_node_used = last_node.is_used();
         self.length -= 1;
+        self.generation += 1;
+        self.update_entries(key, |entries| entries - 1);
 
         // 他の文字列でノードを使用中なので削除しない
         if is_last_node_used {
@@ -227,15 +525,96 @@ impl<V> TrieTree<V> {
         }
 
         // ノードまで移動しつつパスを記録
-        let path: Vec<(usize, char)> = chars.iter().enumerate().map(|(i, &c)| (i, c)).collect();
+        let path: Vec<(usize, K)> = key.iter().enumerate().map(|(i, &c)| (i, c)).collect();
 
         // パスを逆順に走査し、未使用のノードを削除
-        self.fix_tree(&chars, path);
+        self.fix_tree(key, path);
 
         Some(value)
     }
 
-    fn fix_tree(&mut self, chars: &[char], path: Vec<(usize, char)>) {
+    /// すべてのキーを削除します
+    pub fn clear(&mut self) {
+        self.root.clear();
+        self.root_value = None;
+        self.length = 0;
+        self.generation += 1;
+    }
+
+    /// [デバッグ用] 各ノードが保持するエントリー数が実際の部分木のエントリー数と一致するか検証します
+    pub fn verify(&self) -> bool {
+        let mut total = usize::from(self.root_value.is_some());
+        for node in self.root.values() {
+            match Self::verify_node(node) {
+                Some(entries) => total += entries,
+                None => return false,
+            }
+        }
+        total == self.length
+    }
+
+    /// ノード数を取得します
+    fn node_count(&self) -> usize {
+        let mut count = 0;
+        let mut stack: Vec<&TrieNode<K, V>> =
+            self.root.values().map(|node| node.as_ref()).collect();
+        while let Some(node) = stack.pop() {
+            count += 1;
+            stack.extend(node.next().values().map(|node| node.as_ref()));
+        }
+        count
+    }
+
+    /// 子ノードの数ごとに、その数の子ノードを持つノードの数を集計します
+    /// 子ノードが1つのノードが多い場合は、経路を圧縮したradix木が有効です
+    pub fn branching_factor_histogram(&self) -> BTreeMap<usize, usize> {
+        let mut histogram = BTreeMap::new();
+        let mut stack: Vec<&TrieNode<K, V>> =
+            self.root.values().map(|node| node.as_ref()).collect();
+        while let Some(node) = stack.pop() {
+            *histogram.entry(node.next().len()).or_insert(0) += 1;
+            stack.extend(node.next().values().map(|node| node.as_ref()));
+        }
+        histogram
+    }
+
+    /// [デバッグ用] 要素数・ノード数・エントリー数が正しいかを1行で取得します
+    pub fn describe(&self) -> String {
+        format!(
+            "TrieTree {{ len: {}, node_count: {}, valid: {} }}",
+            self.len(),
+            self.node_count(),
+            self.verify()
+        )
+    }
+
+    /// 部分木を再計算したエントリー数を返します。キャッシュと一致しない場合はNoneを返します
+    fn verify_node(node: &TrieNode<K, V>) -> Option<usize> {
+        let mut entries = usize::from(node.value().is_some());
+        for child in node.next().values() {
+            entries += Self::verify_node(child)?;
+        }
+        (entries == node.entries() && node.is_used()).then_some(entries)
+    }
+
+    /// charsのパス上にある各ノードのエントリー数を更新します
+    fn update_entries(&mut self, chars: &[K], update: impl Fn(usize) -> usize) {
+        let Some(mut current) = self.root.get_mut(&chars[0]) else {
+            return;
+        };
+        *current.entries_mut() = update(current.entries());
+        for c in chars[1..].iter() {
+            let Some(next) = current.next_mut().get_mut(c) else {
+                return;
+            };
+            current = next;
+            *current.entries_mut() = update(current.entries());
+        }
+    }
+
+    /// パスを末尾から辿り、使用されていないノードを削除します
+    /// 使用中のノード (Entryノードまたは他の子ノードを持つノード) の祖先はすべて使用中なので、そこで削除を止めます
+    fn fix_tree(&mut self, chars: &[K], path: Vec<(usize, K)>) {
         let mut can_remove_parent = true;
         for i in (0..path.len()).rev() {
             if !can_remove_parent {
@@ -246,7 +625,19 @@ impl<V> TrieTree<V> {
         }
     }
 
-    fn get_last_node_mut(&mut self, chars: &[char]) -> Option<&mut Box<TrieNode<V>>> {
+    fn get_last_node(&self, chars: &[K]) -> Option<&TrieNode<K, V>> {
+        if chars.is_empty() {
+            return None;
+        }
+
+        let mut current = self.root.get(&chars[0])?;
+        for &c in chars[1..].iter() {
+            current = current.next().get(&c)?;
+        }
+        Some(current)
+    }
+
+    fn get_last_node_mut(&mut self, chars: &[K]) -> Option<&mut Box<TrieNode<K, V>>> {
         if chars.is_empty() {
             return None;
         }
@@ -258,7 +649,7 @@ impl<V> TrieTree<V> {
         Some(current)
     }
 
-    fn get_node_at_mut(&mut self, chars: &[char], index: usize) -> Option<&mut Box<TrieNode<V>>> {
+    fn get_node_at_mut(&mut self, chars: &[K], index: usize) -> Option<&mut Box<TrieNode<K, V>>> {
         if index == 0 {
             self.root.get_mut(&chars[0])
         } else {
@@ -270,7 +661,7 @@ impl<V> TrieTree<V> {
         }
     }
 
-    fn remove_node(&mut self, chars: &[char], index: usize, c: char) -> (Option<V>, bool) {
+    fn remove_node(&mut self, chars: &[K], index: usize, c: K) -> (Option<V>, bool) {
         if index == 0 {
             if let Some(node) = self.root.get_mut(&c) {
                 if !node.is_used() {
@@ -292,314 +683,1931 @@ impl<V> TrieTree<V> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
-    }
-
-    #[derive(Debug, PartialEq)]
-    struct TestValue {
-        id: u64,
+/// 文字列をキーとするTrieTreeの操作
+/// キーは`char`の列として扱います
+impl<V> TrieTree<V> {
+    /// キーの昇順に並んだペアからTrieTreeを作成します
+    /// 現在のノードまでの経路を保持し、前のキーと共通するprefixのノードを再利用するため、
+    /// キーごとにrootから辿り直しません。同じキーが複数ある場合は後の値が残ります
+    /// 昇順になっていないペア以降は`add`で1つずつ追加します
+    pub fn from_sorted(pairs: Vec<(String, V)>) -> TrieTree<V> {
+        let mut pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (key.chars().collect::<Vec<char>>(), value))
+            .peekable();
+        // 空のキーは昇順の先頭に並ぶ
+        let mut root_value = None;
+        while pairs.peek().is_some_and(|(key, _)| key.is_empty()) {
+            root_value = pairs.next().map(|(_, value)| value);
+        }
+        let root = Self::build_sorted(&mut pairs, &mut vec![]);
+        let length = usize::from(root_value.is_some())
+            + root.values().map(|node| node.entries()).sum::<usize>();
+        let mut trie = TrieTree {
+            length,
+            root,
+            root_value,
+            case_insensitive: false,
+            generation: 0,
+        };
+        // 昇順でなかった残りのペアは1つずつ追加する
+        for (key, value) in pairs {
+            trie.add(key.into_iter().collect(), value);
+        }
+        trie
     }
 
-    impl TestValue {
-        fn new(id: u64) -> Self {
-            Self { id }
+    /// pathから始まるキーのペアを取り出し、pathのノードの子ノードを作成します
+    fn build_sorted(
+        pairs: &mut core::iter::Peekable<impl Iterator<Item = (Vec<char>, V)>>,
+        path: &mut Vec<char>,
+    ) -> BTreeMap<char, Box<TrieNode<char, V>>> {
+        let mut next = BTreeMap::new();
+        while let Some((key, _)) = pairs.peek() {
+            if key.len() <= path.len() || !key.starts_with(path) {
+                break;
+            }
+            let c = key[path.len()];
+            // 作成済みの子ノード以前の文字に戻るキーは昇順ではないため、残りのペアとして扱う
+            if next.last_key_value().is_some_and(|(&last, _)| c <= last) {
+                break;
+            }
+            path.push(c);
+            let mut value = None;
+            while pairs.peek().is_some_and(|(key, _)| key == path) {
+                value = pairs.next().map(|(_, value)| value);
+            }
+            let children = Self::build_sorted(pairs, path);
+            path.pop();
+
+            let entries = usize::from(value.is_some())
+                + children
+                    .values()
+                    .map(|child| child.entries())
+                    .sum::<usize>();
+            let node = match value {
+                Some(value) => TrieNode::Entry {
+                    value,
+                    next: children,
+                    entries,
+                },
+                None => TrieNode::Internal {
+                    next: children,
+                    entries,
+                },
+            };
+            next.insert(c, Box::new(node));
         }
+        next
     }
 
-    #[test]
-    fn add_should_insert_single_char_key() {
-        // Arrange
-        init();
-        let mut trie = TrieTree::default();
-
-        // Act
-        trie.add("a".to_string(), TestValue::new(1));
-
-        // Assert
-        assert_eq!(trie.len(), 1);
-        assert_eq!(trie.find("a").unwrap().id, 1);
+    /// キーと値を追加します。すでにキーが存在する場合は値を置き換えます
+    /// 空のキーも追加できるため、panicしません
+    pub fn add(&mut self, key: String, v: V) {
+        self.insert(key, v);
     }
 
-    #[test]
-    fn add_should_insert_multiple_char_key() {
-        // Arrange
-        init();
-        let mut trie = TrieTree::default();
-
-        // Act
-        trie.add("abc".to_string(), TestValue::new(1));
-
-        // Assert
-        assert_eq!(trie.len(), 1);
-        assert_eq!(trie.find("abc").unwrap().id, 1);
+    /// キーと値を追加し、すでにキーが存在した場合は置き換えた古い値を返します
+    /// `insert`の結果を`Ok`で包んで返すだけで、現在は`Err`を返すことはありません
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// assert_eq!(trie.try_add("rust".to_string(), 1), Ok(None));
+    /// assert_eq!(trie.try_add("rust".to_string(), 2), Ok(Some(1)));
+    /// assert_eq!(trie.try_add("".to_string(), 3), Ok(None));
+    /// ```
+    pub fn try_add(&mut self, key: String, v: V) -> Result<Option<V>, TrieError> {
+        Ok(self.insert(key, v))
     }
 
-    #[test]
-    fn add_should_update_existing_key() {
-        // Arrange
-        init();
-        let mut trie = TrieTree::default();
-        trie.add("abc".to_string(), TestValue::new(1));
-
-        // Act
-        trie.add("abc".to_string(), TestValue::new(2));
-
-        // Assert
-        assert_eq!(trie.len(), 1);
-        assert_eq!(trie.find("abc").unwrap().id, 2);
+    /// キーと値を追加し、すでにキーが存在した場合は置き換えた古い値を返します
+    /// 空のキーも追加できるため、どのようなキーでもpanicしません
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// assert_eq!(trie.insert("rust".to_string(), 1), None);
+    /// assert_eq!(trie.insert("rust".to_string(), 2), Some(1));
+    /// assert_eq!(trie.insert("".to_string(), 3), None);
+    /// ```
+    pub fn insert(&mut self, key: String, v: V) -> Option<V> {
+        debug!("[trie::insert] key: {}", key);
+
+        let chars = self.key_chars(&key);
+        let old = self.add_symbols(&chars, v);
+        match old {
+            None => debug!("added: {key}"),
+            Some(_) => debug!("updated: {key}"),
+        }
+        old
     }
 
-    #[test]
-    fn add_should_insert_prefix_key() {
-        // Arrange
-        init();
-        let mut trie = TrieTree::default();
-        trie.add("abc".to_string(), TestValue::new(1));
-
-        // Act
-        trie.add("ab".to_string(), TestValue::new(2));
+    /// 大文字と小文字を区別しないTrieTreeを作成します
+    /// `add`・`find`・`remove`などキーを受け取る操作は、キーを小文字に正規化してから辿ります。
+    /// そのため"Rust"と"rust"は同じキーになり、`keys`などが返すキーは小文字になります
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::new_case_insensitive();
+    /// trie.add("Rust".to_string(), 1);
+    ///
+    /// assert_eq!(trie.find("RUST"), Some(&1));
+    /// assert_eq!(trie.keys(), vec!["rust"]);
+    /// ```
+    pub fn new_case_insensitive() -> TrieTree<V> {
+        TrieTree {
+            case_insensitive: true,
+            ..TrieTree::default()
+        }
+    }
 
-        // Assert
-        assert_eq!(trie.len(), 2);
-        assert_eq!(trie.find("abc").unwrap().id, 1);
-        assert_eq!(trie.find("ab").unwrap().id, 2);
+    /// キーを辿る文字の列に変換します。大文字と小文字を区別しない場合は小文字に正規化します
+    fn key_chars(&self, key: &str) -> Vec<char> {
+        if self.case_insensitive {
+            key.chars().flat_map(char::to_lowercase).collect()
+        } else {
+            key.chars().collect()
+        }
     }
 
-    #[test]
-    fn add_should_insert_different_key_with_same_prefix() {
-        // Arrange
-        init();
-        let mut trie = TrieTree::default();
-        trie.add("abc".to_string(), TestValue::new(1));
+    /// キーに対応する値の格納場所を取得します
+    /// キーが存在しない場合は、値を挿入するまでノードを作成しません
+    pub fn entry(&mut self, key: String) -> Entry<'_, V> {
+        let chars = self.key_chars(&key);
+        self.entry_symbols(&chars)
+    }
 
-        // Act
-        trie.add("abx".to_string(), TestValue::new(2));
+    pub fn find(&self, s: &str) -> Option<&V> {
+        debug!("[trie::find] s: {}", s);
+        let chars = self.key_chars(s);
+        self.find_symbols(&chars)
+    }
 
-        // Assert
-        assert_eq!(trie.len(), 2);
-        assert_eq!(trie.find("abc").unwrap().id, 1);
-        assert_eq!(trie.find("abx").unwrap().id, 2);
+    /// キーが登録されている場合はtrueを返します
+    /// Internalノードで終わる途中の文字列はfalseになります
+    pub fn contains_key(&self, s: &str) -> bool {
+        self.find(s).is_some()
     }
 
-    #[test]
-    fn add_should_update_single_char_key() {
-        // Arrange
-        init();
-        let mut trie = TrieTree::default();
+    /// キーに対応する値を削除します
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("rust".to_string(), 1);
+    /// trie.add("rust-lang".to_string(), 2);
+    ///
+    /// assert_eq!(trie.remove("rust"), Some(1));  // "rust"を削除。"rust-lang"は保持
+    /// assert_eq!(trie.find("rust"), None);       // "rust"は見つからない
+    /// assert_eq!(trie.find("rust-lang"), Some(&2)); // "rust-lang"はまだ存在
+    /// ```
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        debug!("[trie::remove] key: {}", key);
+        let chars = self.key_chars(key);
+        self.remove_symbols(&chars)
+    }
+
+    /// prefixから始まるキーの数を取得します
+    /// 各ノードが部分木のエントリー数を保持しているため、prefixの長さに比例する時間で計算できます
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("rust".to_string(), 1);
+    /// trie.add("rust-lang".to_string(), 2);
+    /// trie.add("ruby".to_string(), 3);
+    ///
+    /// assert_eq!(trie.count_prefix("ru"), 3);
+    /// assert_eq!(trie.count_prefix("rust"), 2);
+    /// assert_eq!(trie.count_prefix("go"), 0);
+    /// ```
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        let chars = self.key_chars(prefix);
+        if chars.is_empty() {
+            return self.len();
+        }
+        self.get_last_node(&chars).map_or(0, |node| node.entries())
+    }
+
+    /// prefixから始まるキーのうち、fがfalseを返すエントリーを削除します
+    /// prefixの外にあるキーは変更されません。使用されなくなったノードも削除します
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("/a/1".to_string(), 1);
+    /// trie.add("/a/2".to_string(), 2);
+    /// trie.add("/b/1".to_string(), 1);
+    ///
+    /// trie.retain_prefix("/a/", |_, v| *v % 2 == 0);
+    /// assert_eq!(trie.find("/a/1"), None);
+    /// assert_eq!(trie.find("/a/2"), Some(&2));
+    /// assert_eq!(trie.find("/b/1"), Some(&1));
+    /// ```
+    pub fn retain_prefix(&mut self, prefix: &str, mut f: impl FnMut(&str, &V) -> bool) {
+        let chars = self.key_chars(prefix);
+        let prefix: String = chars.iter().collect();
+        let mut removed = vec![];
+        let mut key = prefix.clone();
+        let mut check = |key: &str, value: &V| {
+            if !f(key, value) {
+                removed.push(String::from(key));
+            }
+        };
+        if chars.is_empty() {
+            if let Some(value) = self.root_value.as_ref() {
+                check("", value);
+            }
+            Self::walk_entries(&self.root, &mut key, &mut check);
+        } else if let Some(node) = self.get_last_node(&chars) {
+            if let Some(value) = node.value() {
+                check(&prefix, value);
+            }
+            Self::walk_entries(node.next(), &mut key, &mut check);
+        }
+
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    /// prefixから始まるキーをすべて削除し、削除したキーの数を返します
+    /// prefixのノードを部分木ごと切り離し、使用されなくなった祖先ノードも削除します
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("/sensors/room1/temp".to_string(), 1);
+    /// trie.add("/sensors/room1/humidity".to_string(), 2);
+    /// trie.add("/sensors/room2/temp".to_string(), 3);
+    ///
+    /// assert_eq!(trie.remove_prefix("/sensors/room1/"), 2);
+    /// assert_eq!(trie.len(), 1);
+    /// assert_eq!(trie.find("/sensors/room2/temp"), Some(&3));
+    /// ```
+    pub fn remove_prefix(&mut self, prefix: &str) -> usize {
+        debug!("[trie::remove_prefix] prefix: {}", prefix);
+        let chars = self.key_chars(prefix);
+        if chars.is_empty() {
+            let removed = self.len();
+            self.clear();
+            return removed;
+        }
+        let Some(removed) = self.get_last_node(&chars).map(|node| node.entries()) else {
+            return 0;
+        };
+
+        // 祖先ノードのエントリー数を更新してから部分木を切り離す
+        let last = chars.len() - 1;
+        let (ancestors, c) = (&chars[..last], chars[last]);
+        if !ancestors.is_empty() {
+            self.update_entries(ancestors, |entries| entries - removed);
+        }
+        if last == 0 {
+            self.root.remove(&c);
+        } else if let Some(parent) = self.get_node_at_mut(&chars, last) {
+            parent.next_mut().remove(&c);
+        }
+        self.length -= removed;
+        self.generation += 1;
+
+        // パスを逆順に走査し、未使用のノードを削除
+        let path: Vec<(usize, char)> = ancestors.iter().enumerate().map(|(i, &c)| (i, c)).collect();
+        self.fix_tree(ancestors, path);
+
+        removed
+    }
+
+    /// パターンに一致するすべてのキーを辞書順で取得します
+    /// `*`は0文字以上の任意の文字列に一致します。ワイルドカードとして扱う`*`は最初の1つだけです
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("/floor-1/sensor".to_string(), 1);
+    /// trie.add("/floor-2/sensor".to_string(), 2);
+    /// trie.add("/floor-2/camera".to_string(), 3);
+    ///
+    /// assert_eq!(
+    ///     trie.matches_glob("/floor-*/sensor"),
+    ///     vec!["/floor-1/sensor", "/floor-2/sensor"]
+    /// );
+    /// ```
+    pub fn matches_glob(&self, pattern: &str) -> Vec<String> {
+        let pattern: String = self.key_chars(pattern).into_iter().collect();
+        let Some((prefix, suffix)) = pattern.split_once('*') else {
+            return match self.find(&pattern) {
+                Some(_) => vec![pattern],
+                None => vec![],
+            };
+        };
+
+        // ワイルドカードの手前まではprefixのノードを辿り、そこから部分木全体に分岐する
+        let mut matches = vec![];
+        let mut key = String::from(prefix);
+        let mut check = |key: &str, _: &V| {
+            if key.len() >= prefix.len() + suffix.len() && key.ends_with(suffix) {
+                matches.push(String::from(key));
+            }
+        };
+        let chars: Vec<char> = prefix.chars().collect();
+        if chars.is_empty() {
+            if let Some(value) = self.root_value.as_ref() {
+                check("", value);
+            }
+            Self::walk_entries(&self.root, &mut key, &mut check);
+        } else if let Some(node) = self.get_last_node(&chars) {
+            if let Some(value) = node.value() {
+                check(prefix, value);
+            }
+            Self::walk_entries(node.next(), &mut key, &mut check);
+        }
+        matches
+    }
+
+    /// sのprefixになっているキーのうち、もっとも長いキーとその値を取得します
+    /// sを1文字ずつ辿り、最後に通過したEntryノードを返します
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("/sensors".to_string(), 1);
+    /// trie.add("/sensors/temp".to_string(), 2);
+    ///
+    /// assert_eq!(
+    ///     trie.longest_prefix_match("/sensors/temp/3"),
+    ///     Some(("/sensors/temp".to_string(), &2))
+    /// );
+    /// assert_eq!(trie.longest_prefix_match("/actuators/1"), None);
+    /// ```
+    pub fn longest_prefix_match(&self, s: &str) -> Option<(String, &V)> {
+        let s: String = self.key_chars(s).into_iter().collect();
+        let mut longest = self.root_value.as_ref().map(|value| (0, value));
+        let mut next = &self.root;
+        for (i, c) in s.char_indices() {
+            let Some(node) = next.get(&c) else {
+                break;
+            };
+            if let Some(value) = node.value() {
+                longest = Some((i + c.len_utf8(), value));
+            }
+            next = node.next();
+        }
+        longest.map(|(end, value)| (String::from(&s[..end]), value))
+    }
+
+    /// queryとの編集距離 (レーベンシュタイン距離) がmax_distance以下のキーと値をキーの辞書順で取得します
+    /// ノードを辿りながら編集距離のDP表を1行ずつ計算し、行の最小値がmax_distanceを超えた部分木は辿りません
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("rust".to_string(), 1);
+    /// trie.add("ruby".to_string(), 2);
+    ///
+    /// assert_eq!(trie.fuzzy_find("rost", 1), vec![("rust".to_string(), &1)]);
+    /// assert_eq!(trie.fuzzy_find("rust", 2).len(), 2);
+    /// ```
+    pub fn fuzzy_find(&self, query: &str, max_distance: usize) -> Vec<(String, &V)> {
+        let query = self.key_chars(query);
+        // 空のキーとqueryの各prefixとの編集距離
+        let row: Vec<usize> = (0..=query.len()).collect();
+        let mut found = vec![];
+        if let Some(value) = self.root_value.as_ref() {
+            if query.len() <= max_distance {
+                found.push((String::new(), value));
+            }
+        }
+        Self::fuzzy_find_rec(
+            &self.root,
+            &mut String::new(),
+            &query,
+            &row,
+            max_distance,
+            &mut found,
+        );
+        found
+    }
+
+    /// rowは親ノードまでのキーとqueryの各prefixとの編集距離です
+    fn fuzzy_find_rec<'a>(
+        next: &'a BTreeMap<char, Box<TrieNode<char, V>>>,
+        key: &mut String,
+        query: &[char],
+        row: &[usize],
+        max_distance: usize,
+        found: &mut Vec<(String, &'a V)>,
+    ) {
+        for (&c, child) in next {
+            let mut current = Vec::with_capacity(row.len());
+            current.push(row[0] + 1);
+            for (i, &q) in query.iter().enumerate() {
+                let replace = row[i] + usize::from(q != c);
+                let insert = current[i] + 1;
+                let delete = row[i + 1] + 1;
+                current.push(replace.min(insert).min(delete));
+            }
+            // 行の最小値より編集距離が小さくなることはないため、部分木を辿らない
+            if current.iter().min().is_some_and(|&min| min > max_distance) {
+                continue;
+            }
+
+            key.push(c);
+            if let Some(value) = child.value() {
+                if current[query.len()] <= max_distance {
+                    found.push((key.clone(), value));
+                }
+            }
+            Self::fuzzy_find_rec(child.next(), key, query, &current, max_distance, found);
+            key.pop();
+        }
+    }
+
+    /// prefixから始まるキーが存在する場合はtrueを返します
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        self.count_prefix(prefix) > 0
+    }
+
+    /// prefixに一致するノードがEntryノードの場合 (prefix自体がキーとして登録されている場合) に値を取得します
+    /// prefixが中間ノードの場合は、`has_prefix`がtrueでもNoneを返します
+    pub fn prefix_value(&self, prefix: &str) -> Option<&V> {
+        let chars = self.key_chars(prefix);
+        self.find_symbols(&chars)
+    }
+
+    /// prefixから始まるキーの値をキーの辞書順で取得します
+    /// prefix自体がキーの場合は、その値も含みます
+    ///
+    /// # 例
+    /// ```
+    /// # use ch05_rubust_trees::trie::TrieTree;
+    /// let mut trie = TrieTree::default();
+    /// trie.add("/a".to_string(), 0);
+    /// trie.add("/a/1".to_string(), 1);
+    /// trie.add("/b/1".to_string(), 2);
+    ///
+    /// assert_eq!(trie.find_prefix("/a"), vec![&0, &1]);
+    /// assert!(trie.find_prefix("/c").is_empty());
+    /// ```
+    pub fn find_prefix(&self, prefix: &str) -> Vec<&V> {
+        let chars = self.key_chars(prefix);
+        if chars.is_empty() {
+            return self.values();
+        }
+        let Some(node) = self.get_last_node(&chars) else {
+            return vec![];
+        };
+        let mut values = Vec::with_capacity(node.entries());
+        values.extend(node.value());
+        Self::walk_entries(node.next(), &mut String::from(prefix), &mut |_, value| {
+            values.push(value)
+        });
+        values
+    }
+
+    /// すべてのキーを辞書順で取得します
+    /// Entryノードで終わるキーだけを返し、Internalノードで終わる途中の文字列は含みません
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::with_capacity(self.length);
+        self.walk_all_entries(&mut |key, _| keys.push(String::from(key)));
+        keys
+    }
+
+    /// すべてのキーと値の参照のペアをキーの辞書順で取得します
+    pub fn entries(&self) -> Vec<(String, &V)> {
+        let mut entries = Vec::with_capacity(self.length);
+        self.walk_all_entries(&mut |key, value| entries.push((String::from(key), value)));
+        entries
+    }
+
+    /// すべての値をキーの辞書順で取得します
+    pub fn values(&self) -> Vec<&V> {
+        let mut values = vec![];
+        self.walk_all_entries(&mut |_, value| values.push(value));
+        values
+    }
+
+    /// TrieTreeを消費して、すべてのキーと値のペアをキーの辞書順で取得します
+    pub fn into_pairs(self) -> Vec<(String, V)> {
+        let mut pairs = Vec::with_capacity(self.length);
+        if let Some(value) = self.root_value {
+            pairs.push((String::new(), value));
+        }
+        Self::into_pairs_rec(self.root, &mut String::new(), &mut pairs);
+        pairs
+    }
+
+    fn into_pairs_rec(
+        next: BTreeMap<char, Box<TrieNode<char, V>>>,
+        key: &mut String,
+        pairs: &mut Vec<(String, V)>,
+    ) {
+        for (c, child) in next {
+            key.push(c);
+            match *child {
+                TrieNode::Internal { next, .. } => Self::into_pairs_rec(next, key, pairs),
+                TrieNode::Entry { value, next, .. } => {
+                    pairs.push((key.clone(), value));
+                    Self::into_pairs_rec(next, key, pairs);
+                }
+            }
+            key.pop();
+        }
+    }
+
+    /// 空のキーを含むすべてのエントリーをキーの辞書順に走査し、キーと値に対してcallbackを適用します
+    fn walk_all_entries<'a>(&'a self, callback: &mut impl FnMut(&str, &'a V)) {
+        if let Some(value) = self.root_value.as_ref() {
+            callback("", value);
+        }
+        Self::walk_entries(&self.root, &mut String::new(), callback);
+    }
+
+    /// 部分木のエントリーをキーの辞書順に走査し、キーと値に対してcallbackを適用します
+    /// keyには部分木の親ノードまでのキーを渡します
+    fn walk_entries<'a>(
+        next: &'a BTreeMap<char, Box<TrieNode<char, V>>>,
+        key: &mut String,
+        callback: &mut impl FnMut(&str, &'a V),
+    ) {
+        for (&c, child) in next {
+            key.push(c);
+            if let Some(value) = child.value() {
+                callback(key, value);
+            }
+            Self::walk_entries(child.next(), key, callback);
+            key.pop();
+        }
+    }
+
+    /// prefixから始まるエントリーを辿るカーソルを作成します
+    pub fn cursor(&self, prefix: &str) -> TrieCursor {
+        TrieCursor {
+            prefix: self.key_chars(prefix),
+            last: None,
+            generation: self.generation,
+        }
+    }
+
+    /// keyの辞書順で、afterより後にある最初のエントリーを探します
+    /// afterがNoneの場合は、自ノードを含む最初のエントリーを返します
+    /// 見つかったエントリーのキーはkeyに格納されます
+    fn first_entry_after<'a>(
+        value: Option<&'a V>,
+        next: &'a BTreeMap<char, Box<TrieNode<char, V>>>,
+        key: &mut Vec<char>,
+        after: Option<&[char]>,
+    ) -> Option<&'a V> {
+        let (first, rest) = match after {
+            None => {
+                if value.is_some() {
+                    return value;
+                }
+                (None, None)
+            }
+            // 自ノードのキーはafter以下なので、自ノードの値はスキップする
+            Some(after) => match after.split_first() {
+                Some((&c, rest)) => (Some(c), Some(rest)),
+                // 自ノードのキーとafterが一致するため、子ノードのキーはすべてafterより後になる
+                None => (None, None),
+            },
+        };
+
+        if let (Some(c), Some(rest)) = (first, rest) {
+            if let Some(child) = next.get(&c) {
+                key.push(c);
+                let found = Self::first_entry_after(child.value(), child.next(), key, Some(rest));
+                if found.is_some() {
+                    return found;
+                }
+                key.pop();
+            }
+        }
+
+        let lower = first.map_or(Bound::Unbounded, Bound::Excluded);
+        for (&c, child) in next.range((lower, Bound::Unbounded)) {
+            key.push(c);
+            let found = Self::first_entry_after(child.value(), child.next(), key, None);
+            if found.is_some() {
+                return found;
+            }
+            key.pop();
+        }
+        None
+    }
+}
+
+impl<V: Ord> TrieTree<V> {
+    /// すべてのキーと値のペアを値の昇順で取得します
+    /// 値が等しい場合はキーの辞書順になります
+    pub fn entries_by_value(&self) -> Vec<(String, &V)> {
+        let mut entries = self.entries();
+        // キーの辞書順に集めているため、安定ソートで同じ値のキーの順番を保つ
+        entries.sort_by_key(|(_, value)| *value);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iot::IoTDevice;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct TestValue {
+        id: u64,
+    }
+
+    impl TestValue {
+        fn new(id: u64) -> Self {
+            Self { id }
+        }
+    }
+
+    #[test]
+    fn add_should_insert_single_char_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+
+        // Act
+        trie.add("a".to_string(), TestValue::new(1));
+
+        // Assert
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find("a").unwrap().id, 1);
+    }
+
+    #[test]
+    fn add_should_insert_multiple_char_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+
+        // Act
+        trie.add("abc".to_string(), TestValue::new(1));
+
+        // Assert
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find("abc").unwrap().id, 1);
+    }
+
+    #[test]
+    fn add_should_update_existing_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("abc".to_string(), TestValue::new(1));
+
+        // Act
+        trie.add("abc".to_string(), TestValue::new(2));
+
+        // Assert
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find("abc").unwrap().id, 2);
+    }
+
+    #[test]
+    fn add_should_insert_prefix_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("abc".to_string(), TestValue::new(1));
+
+        // Act
+        trie.add("ab".to_string(), TestValue::new(2));
+
+        // Assert
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.find("abc").unwrap().id, 1);
+        assert_eq!(trie.find("ab").unwrap().id, 2);
+    }
+
+    #[test]
+    fn add_should_insert_different_key_with_same_prefix() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("abc".to_string(), TestValue::new(1));
+
+        // Act
+        trie.add("abx".to_string(), TestValue::new(2));
+
+        // Assert
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.find("abc").unwrap().id, 1);
+        assert_eq!(trie.find("abx").unwrap().id, 2);
+    }
+
+    #[test]
+    fn add_should_update_single_char_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("a".to_string(), TestValue::new(1));
+
+        // Act
+        trie.add("a".to_string(), TestValue::new(2));
+
+        // Assert
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find("a").unwrap().id, 2);
+    }
+
+    #[test]
+    fn add_should_store_empty_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+
+        // Act
+        trie.add("".to_string(), TestValue::new(1));
+        trie.add("".to_string(), TestValue::new(2));
+        trie.add("a".to_string(), TestValue::new(3));
+
+        // Assert
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.find(""), Some(&TestValue::new(2)));
+        assert_eq!(trie.keys(), vec!["", "a"]);
+        assert_eq!(trie.count_prefix(""), 2);
+        assert_eq!(trie.node_count(), 1);
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn insert_should_return_replaced_value() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+
+        // Act
+        let added = trie.insert("rust".to_string(), TestValue::new(1));
+        let replaced = trie.insert("rust".to_string(), TestValue::new(2));
+        let empty = trie.insert("".to_string(), TestValue::new(3));
+
+        // Assert
+        assert_eq!(added, None);
+        assert_eq!(replaced, Some(TestValue::new(1)));
+        assert_eq!(empty, None);
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.find("rust"), Some(&TestValue::new(2)));
+    }
+
+    #[test]
+    fn try_add_should_accept_empty_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+
+        // Act
+        let added = trie.try_add("rust".to_string(), TestValue::new(1));
+        let replaced = trie.try_add("rust".to_string(), TestValue::new(2));
+        let empty = trie.try_add("".to_string(), TestValue::new(3));
+
+        // Assert
+        assert_eq!(added, Ok(None));
+        assert_eq!(replaced, Ok(Some(TestValue::new(1))));
+        assert_eq!(empty, Ok(None));
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.find(""), Some(&TestValue::new(3)));
+    }
+
+    #[test]
+    fn remove_should_remove_empty_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("".to_string(), TestValue::new(1));
+        trie.add("a".to_string(), TestValue::new(2));
+
+        // Act
+        let removed = trie.remove("");
+
+        // Assert
+        assert_eq!(removed, Some(TestValue::new(1)));
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find(""), None);
+        assert_eq!(trie.remove(""), None);
+        assert_eq!(trie.find("a"), Some(&TestValue::new(2)));
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn empty_key_should_be_visited_first() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::from_sorted(vec![
+            ("".to_string(), TestValue::new(1)),
+            ("b".to_string(), TestValue::new(2)),
+        ]);
+        trie.add("a".to_string(), TestValue::new(3));
+
+        // Act
+        let mut cursor = trie.cursor("");
+        let first = cursor.next_entry(&trie).map(|(key, _)| key);
+
+        // Assert
+        assert_eq!(first.as_deref(), Some(""));
+        assert_eq!(trie.len(), 3);
+        assert_eq!(
+            trie.longest_prefix_match("c")
+                .map(|(key, _)| key)
+                .as_deref(),
+            Some("")
+        );
+        assert_eq!(trie.matches_glob("*"), vec!["", "a", "b"]);
+        let pairs = trie.into_pairs();
+        assert_eq!(pairs[0], ("".to_string(), TestValue::new(1)));
+        assert_eq!(pairs.len(), 3);
+    }
+
+    #[test]
+    fn find_should_return_none_for_missing_key() {
+        // Arrange
+        init();
+        let trie = TrieTree::<TestValue>::default();
+
+        // Assert
+        assert_eq!(trie.find("not_exists"), None);
+        assert_eq!(trie.find(""), None);
+    }
+
+    #[test]
+    fn add_should_handle_many_items() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        let n = 1000;
+
+        // Act: Add items with incrementing ids
+        for i in 0..n {
+            let key = format!("key{}", i);
+            trie.add(key, TestValue::new(i as u64));
+        }
+
+        // Assert
+        assert_eq!(trie.len(), n);
+
+        // verify random access
+        assert_eq!(trie.find("key0").unwrap().id, 0);
+        assert_eq!(trie.find("key42").unwrap().id, 42);
+        assert_eq!(trie.find("key999").unwrap().id, 999);
+
+        // verify all items are accessible
+        for i in 0..n {
+            let key = format!("key{}", i);
+            assert_eq!(trie.find(&key).unwrap().id, i as u64);
+        }
+    }
+
+    #[test]
+    fn remove_should_remove_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("abc".to_string(), TestValue::new(1));
+
+        // Act
+        let removed = trie.remove("abc");
+
+        // Assert
+        assert_eq!(trie.len(), 0);
+        assert_eq!(removed.unwrap().id, 1);
+        assert_eq!(trie.find("abc"), None);
+    }
+
+    #[test]
+    fn remove_should_keep_other_keys_with_same_prefix() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("rust-lang".to_string(), TestValue::new(2));
+
+        // Act: "rust"を削除
+        let removed = trie.remove("rust");
+
+        // Assert: "rust"は削除され、"rust-lang"は保持される
+        assert_eq!(trie.len(), 1);
+        assert_eq!(removed.unwrap().id, 1);
+        assert_eq!(trie.find("rust"), None);
+        assert_eq!(trie.find("rust-lang").unwrap().id, 2);
+    }
+
+    #[test]
+    fn remove_should_cleanup_unused_nodes() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+
+        // Act: "rust"を削除
+        let removed = trie.remove("rust");
+
+        // Assert
+        assert_eq!(trie.len(), 0);
+        assert_eq!(removed.unwrap().id, 1);
+        // 'r', 'u', 's', 't' のノードが全て削除されていることを確認
+        println!("{:?}", trie.root.keys());
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn remove_should_keep_nodes_used_by_other_keys() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("rust-lang".to_string(), TestValue::new(2));
+        trie.add("ruby".to_string(), TestValue::new(3));
+
+        // Act: "rust-lang"を削除
+        let removed = trie.remove("rust-lang");
+
+        // Assert: "rust"と"ruby"は保持される
+        assert_eq!(trie.len(), 2);
+        assert_eq!(removed.unwrap().id, 2);
+        assert_eq!(trie.find("rust").unwrap().id, 1);
+        assert_eq!(trie.find("ruby").unwrap().id, 3);
+        assert_eq!(trie.find("rust-lang"), None);
+    }
+
+    #[test]
+    fn remove_should_return_none_for_missing_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+
+        // Act
+        let removed = trie.remove("not_exists");
+
+        // Assert
+        assert_eq!(trie.len(), 1);
+        assert_eq!(removed, None);
+    }
+
+    #[test]
+    fn remove_should_return_none_when_tree_is_empty() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::<TestValue>::default();
+
+        // Act
+        let removed = trie.remove("not_exists");
+
+        // Assert
+        assert_eq!(trie.len(), 0);
+        assert_eq!(removed, None);
+    }
+
+    #[test]
+    fn remove_should_remove_key_for_single_char_key() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
         trie.add("a".to_string(), TestValue::new(1));
 
         // Act
-        trie.add("a".to_string(), TestValue::new(2));
+        let removed = trie.remove("a");
+
+        // Assert
+        assert_eq!(trie.len(), 0);
+        assert_eq!(removed.unwrap().id, 1);
+        assert_eq!(trie.find("a"), None);
+    }
+
+    #[test]
+    fn remove_node_should_return_none_for_unused_nodes() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("rust-lang".to_string(), TestValue::new(2));
+        // 一度"rust"を削除してInternalノードにする
+        trie.remove("rust");
+        // Act
+        let actual = trie.remove_node(&"rust-lang".chars().collect::<Vec<char>>(), 4, 'l');
+
+        // Act & Assert: 未使用になったノードを削除
+        assert_eq!(actual, (None, false));
+    }
+
+    #[test]
+    fn remove_should_keep_intermediate_values() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        // "r"と"rust"をこの順で追加（"r"がBranchノードになる）
+        trie.add("r".to_string(), TestValue::new(1));
+        trie.add("rust".to_string(), TestValue::new(2));
+
+        // Act: "rust"を削除
+        let removed = trie.remove("rust");
+
+        // Assert: "rust"は削除され、"r"は保持される
+        assert_eq!(removed.unwrap().id, 2);
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find("r").unwrap().id, 1);
+        assert_eq!(trie.find("rust"), None);
+    }
+
+    #[test]
+    fn entry_should_count_occurrences() {
+        // Arrange
+        init();
+        let words = ["rust", "ruby", "rust", "go", "rust", "ruby", "rusty"];
+        let mut trie = TrieTree::default();
+
+        // Act
+        for word in words {
+            *trie.entry(word.to_string()).or_insert(0) += 1;
+        }
+
+        // Assert
+        assert_eq!(trie.len(), 4);
+        assert_eq!(
+            trie.entries(),
+            vec![
+                ("go".to_string(), &1),
+                ("ruby".to_string(), &2),
+                ("rust".to_string(), &3),
+                ("rusty".to_string(), &1),
+            ]
+        );
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn entry_should_modify_only_existing_value() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+
+        // Act
+        trie.entry("rust".to_string())
+            .and_modify(|value| value.id = 10)
+            .or_insert(TestValue::new(0));
+        trie.entry("ru".to_string())
+            .and_modify(|value| value.id = 20)
+            .or_insert(TestValue::new(2));
+
+        // Assert
+        assert_eq!(trie.find("rust"), Some(&TestValue::new(10)));
+        assert_eq!(trie.find("ru"), Some(&TestValue::new(2)));
+        assert_eq!(trie.len(), 2);
+        assert!(trie.verify());
+    }
+
+    /// 比較された回数を数える記号
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct CountedSymbol(u8);
+
+    static SYMBOL_COMPARISONS: core::sync::atomic::AtomicUsize =
+        core::sync::atomic::AtomicUsize::new(0);
+
+    impl PartialOrd for CountedSymbol {
+        fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for CountedSymbol {
+        fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+            SYMBOL_COMPARISONS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+            self.0.cmp(&other.0)
+        }
+    }
+
+    /// 処理中に記号が比較された回数を返します
+    fn count_comparisons(f: impl FnOnce()) -> usize {
+        let before = SYMBOL_COMPARISONS.load(core::sync::atomic::Ordering::Relaxed);
+        f();
+        SYMBOL_COMPARISONS.load(core::sync::atomic::Ordering::Relaxed) - before
+    }
+
+    #[test]
+    fn entry_should_walk_key_path_once() {
+        // Arrange
+        init();
+        let symbols = |s: &str| s.bytes().map(CountedSymbol).collect::<Vec<_>>();
+        let mut trie: TrieTree<i32, CountedSymbol> = TrieTree::default();
+        for key in ["rust", "ruby", "rusty", "go"] {
+            trie.add_symbols(&symbols(key), 0);
+        }
+        let occupied = symbols("rust");
+        let vacant = symbols("rustacean");
+
+        // Act
+        let find_occupied = count_comparisons(|| {
+            trie.find_symbols(&occupied);
+        });
+        let entry_occupied = count_comparisons(|| {
+            *trie.entry_symbols(&occupied).or_insert(0) += 1;
+        });
+        let find_vacant = count_comparisons(|| {
+            trie.find_symbols(&vacant);
+        });
+        let entry_vacant = count_comparisons(|| {
+            *trie.entry_symbols(&vacant).or_insert(0) += 1;
+        });
+
+        // Assert
+        assert_eq!(entry_occupied, find_occupied);
+        assert_eq!(entry_vacant, find_vacant);
+        assert_eq!(trie.find_symbols(&occupied), Some(&1));
+        assert_eq!(trie.find_symbols(&vacant), Some(&1));
+        assert_eq!(trie.len(), 5);
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn collect_should_add_all_pairs() {
+        // Arrange
+        init();
+        let devices = vec![
+            IoTDevice::new(1, "10.0.0.1", "/floor-1/sensor"),
+            IoTDevice::new(2, "10.0.0.2", "/floor-1/camera"),
+            IoTDevice::new(3, "10.0.0.3", "/floor-2/sensor"),
+            IoTDevice::new(4, "10.0.0.4", "/floor-2/door"),
+            IoTDevice::new(5, "10.0.0.5", "/floor-1/sensor"),
+        ];
+
+        // Act
+        let trie: TrieTree<IoTDevice> = devices
+            .into_iter()
+            .map(|device| (device.path.clone(), device))
+            .collect();
+
+        // Assert
+        assert_eq!(trie.len(), 4);
+        assert_eq!(
+            trie.find("/floor-1/sensor").map(|d| d.numeriacl_id),
+            Some(5)
+        );
+        assert_eq!(trie.find("/floor-2/door").map(|d| d.numeriacl_id), Some(4));
+        assert_eq!(trie.find("/floor-3/door"), None);
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn case_insensitive_trie_should_normalize_keys() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::new_case_insensitive();
+
+        // Act
+        trie.add("Rust".to_string(), TestValue::new(1));
+        trie.add("RUST".to_string(), TestValue::new(2));
+        trie.add("Ruby".to_string(), TestValue::new(3));
+
+        // Assert
+        assert_eq!(trie.len(), 2);
+        assert_eq!(trie.find("rust"), Some(&TestValue::new(2)));
+        assert_eq!(trie.find("rUsT"), Some(&TestValue::new(2)));
+        assert_eq!(trie.keys(), vec!["ruby", "rust"]);
+        assert_eq!(trie.count_prefix("RU"), 2);
+        assert_eq!(trie.matches_glob("R*T"), vec!["rust"]);
+        assert_eq!(trie.matches_glob("RUST"), vec!["rust"]);
+        assert_eq!(
+            trie.longest_prefix_match("RUST-Lang"),
+            Some(("rust".to_string(), &TestValue::new(2)))
+        );
+        assert_eq!(
+            trie.fuzzy_find("RUSK", 1),
+            vec![("rust".to_string(), &TestValue::new(2))]
+        );
+        assert_eq!(
+            trie.cursor("RUB").next_entry(&trie).map(|(key, _)| key),
+            Some("ruby".to_string())
+        );
+        let mut visited = vec![];
+        trie.retain_prefix("RUB", |key, _| {
+            visited.push(key.to_string());
+            true
+        });
+        assert_eq!(visited, vec!["ruby"]);
+        assert_eq!(trie.remove("RUBY"), Some(TestValue::new(3)));
+        assert_eq!(trie.len(), 1);
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn default_trie_should_be_case_sensitive() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+
+        // Act
+        trie.add("Rust".to_string(), TestValue::new(1));
 
         // Assert
-        assert_eq!(trie.len(), 1);
-        assert_eq!(trie.find("a").unwrap().id, 2);
+        assert_eq!(trie.find("rust"), None);
+        assert_eq!(trie.find("Rust"), Some(&TestValue::new(1)));
     }
 
     #[test]
-    #[should_panic(expected = "key must not be empty")]
-    fn add_should_panic_on_empty_key() {
+    fn remove_should_delete_only_divergent_node() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
+        trie.add("abc".to_string(), TestValue::new(1));
+        trie.add("abd".to_string(), TestValue::new(2));
 
         // Act
-        trie.add("".to_string(), TestValue::new(1));
+        let removed = trie.remove("abc");
+
+        // Assert: 'c'だけが削除され、'a'と'b'は"abd"のために保持される
+        assert_eq!(removed, Some(TestValue::new(1)));
+        assert_eq!(trie.node_count(), 3);
+        let b = trie.get_last_node(&['a', 'b']).unwrap();
+        assert_eq!(b.next().keys().collect::<Vec<_>>(), vec![&'d']);
+        assert_eq!(trie.find("abd"), Some(&TestValue::new(2)));
+        assert!(trie.verify());
     }
 
     #[test]
-    fn find_should_return_none_for_missing_key() {
+    fn remove_should_stop_cleanup_at_intermediate_entry() {
         // Arrange
         init();
-        let trie = TrieTree::<TestValue>::default();
+        let mut trie = TrieTree::default();
+        trie.add("ab".to_string(), TestValue::new(1));
+        trie.add("abcd".to_string(), TestValue::new(2));
+
+        // Act
+        let removed = trie.remove("abcd");
+
+        // Assert: 'c'と'd'は削除され、Entryノードの'b'は保持される
+        assert_eq!(removed, Some(TestValue::new(2)));
+        assert_eq!(trie.node_count(), 2);
+        let b = trie.get_last_node(&['a', 'b']).unwrap();
+        assert!(!b.is_internal());
+        assert!(b.next().is_empty());
+        assert_eq!(trie.find("ab"), Some(&TestValue::new(1)));
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn contains_key_should_return_true_only_for_stored_keys() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("rust-lang".to_string(), TestValue::new(2));
+
+        // Act & Assert
+        assert!(trie.contains_key("rust"));
+        assert!(trie.contains_key("rust-lang"));
+        // "rus"と"rust-"はInternalノード
+        assert!(!trie.contains_key("rus"));
+        assert!(!trie.contains_key("rust-"));
+        assert!(!trie.contains_key(""));
+    }
+
+    #[test]
+    fn clear_should_remove_all_keys() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("ruby".to_string(), TestValue::new(2));
+
+        // Act
+        trie.clear();
 
         // Assert
-        assert_eq!(trie.find("not_exists"), None);
-        assert_eq!(trie.find(""), None);
+        assert!(trie.is_empty());
+        assert!(!trie.contains_key("rust"));
+        assert_eq!(
+            trie.describe(),
+            "TrieTree { len: 0, node_count: 0, valid: true }"
+        );
+        trie.add("go".to_string(), TestValue::new(3));
+        assert_eq!(trie.keys(), vec!["go"]);
     }
 
     #[test]
-    fn add_should_handle_many_items() {
+    fn count_prefix_should_count_keys_sharing_prefix() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        let n = 1000;
+        for (i, key) in ["/a", "/a/1", "/a/2", "/a/2/x", "/ab", "/b/1"]
+            .iter()
+            .enumerate()
+        {
+            trie.add(key.to_string(), TestValue::new(i as u64));
+        }
 
-        // Act: Add items with incrementing ids
-        for i in 0..n {
-            let key = format!("key{}", i);
-            trie.add(key, TestValue::new(i as u64));
+        // Act & Assert
+        // "/a"自体もEntryノードなので数える
+        assert_eq!(trie.count_prefix("/a"), 5);
+        assert_eq!(trie.count_prefix("/a/"), 3);
+        assert_eq!(trie.count_prefix("/a/2"), 2);
+        assert_eq!(trie.count_prefix("/b"), 1);
+        assert_eq!(trie.count_prefix("/"), 6);
+        assert_eq!(trie.count_prefix(""), trie.len());
+        assert_eq!(trie.count_prefix("/c"), 0);
+        assert_eq!(trie.count_prefix("/a/2/xy"), 0);
+    }
+
+    #[test]
+    fn count_prefix_should_match_recomputed_count_after_many_operations() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        let mut expected = BTreeMap::new();
+
+        // Act: 追加と削除を繰り返す
+        for i in 0..300u64 {
+            let key = format!("dev/{}/{}", i % 7, i);
+            trie.add(key.clone(), TestValue::new(i));
+            expected.insert(key, i);
         }
+        for i in (0..300u64).filter(|i| i % 3 == 0) {
+            let key = format!("dev/{}/{}", i % 7, i);
+            trie.remove(&key);
+            expected.remove(&key);
+        }
+        trie.add("dev".to_string(), TestValue::new(1000));
+        expected.insert("dev".to_string(), 1000);
 
         // Assert
-        assert_eq!(trie.len(), n);
+        assert!(trie.verify());
+        for prefix in ["", "d", "dev", "dev/", "dev/3", "dev/3/1", "dev/6/20", "x"] {
+            let recomputed = expected.keys().filter(|k| k.starts_with(prefix)).count();
+            assert_eq!(trie.count_prefix(prefix), recomputed, "prefix: {prefix}");
+        }
+    }
 
-        // verify random access
-        assert_eq!(trie.find("key0").unwrap().id, 0);
-        assert_eq!(trie.find("key42").unwrap().id, 42);
-        assert_eq!(trie.find("key999").unwrap().id, 999);
+    #[test]
+    fn cached_entries_should_match_recount_after_overlapping_updates() {
+        // Arrange
+        init();
+        let keys = ["a", "ab", "abc", "abd", "abcd", "b", "ba", "bab"];
+        let mut trie = TrieTree::from_sorted(
+            keys.iter()
+                .enumerate()
+                .map(|(i, key)| (key.to_string(), TestValue::new(i as u64)))
+                .collect(),
+        );
+
+        // Act: 部分木のエントリー数を更新するすべての操作を重ねて適用する
+        trie.remove("ab");
+        trie.remove("abcd");
+        trie.entry("abce".to_string()).or_insert(TestValue::new(10));
+        trie.entry("a".to_string()).or_insert(TestValue::new(11));
+        trie.add("".to_string(), TestValue::new(12));
+        trie.remove_prefix("ba");
+        trie.retain_prefix("ab", |key, _| key != "abd");
+        trie.add("bc".to_string(), TestValue::new(13));
+
+        // Assert: すべてのキーのすべてのprefixで、キャッシュされた数と数え直した数が一致する
+        let keys = trie.keys();
+        assert_eq!(keys, vec!["", "a", "abc", "abce", "b", "bc"]);
+        assert!(trie.verify());
+        for key in keys.iter() {
+            for (end, _) in key.char_indices().chain([(key.len(), ' ')]) {
+                let prefix = &key[..end];
+                let recounted = keys.iter().filter(|k| k.starts_with(prefix)).count();
+                assert_eq!(trie.count_prefix(prefix), recounted, "prefix: {prefix}");
+            }
+        }
+    }
 
-        // verify all items are accessible
-        for i in 0..n {
-            let key = format!("key{}", i);
-            assert_eq!(trie.find(&key).unwrap().id, i as u64);
+    #[test]
+    fn retain_prefix_should_only_affect_keys_under_prefix() {
+        init();
+        let mut trie = TrieTree::default();
+        for (key, id) in [
+            ("/floor-1", 0),
+            ("/floor-1/a", 1),
+            ("/floor-1/b", 2),
+            ("/floor-1/c/d", 3),
+            ("/floor-10", 4),
+            ("/floor-2/a", 5),
+        ] {
+            trie.add(key.to_string(), TestValue::new(id));
         }
+
+        trie.retain_prefix("/floor-1/", |key, value| {
+            key.ends_with('b') || value.id == 3
+        });
+
+        assert_eq!(trie.len(), 5);
+        assert_eq!(trie.find("/floor-1/a"), None);
+        assert_eq!(trie.find("/floor-1/b"), Some(&TestValue::new(2)));
+        assert_eq!(trie.find("/floor-1/c/d"), Some(&TestValue::new(3)));
+        assert_eq!(trie.find("/floor-1"), Some(&TestValue::new(0)));
+        assert_eq!(trie.find("/floor-10"), Some(&TestValue::new(4)));
+        assert_eq!(trie.find("/floor-2/a"), Some(&TestValue::new(5)));
+        assert!(trie
+            .get_last_node(&"/floor-1/a".chars().collect::<Vec<_>>())
+            .is_none());
+        assert!(trie.verify());
+
+        trie.retain_prefix("/floor-1", |_, _| false);
+
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.find("/floor-2/a"), Some(&TestValue::new(5)));
+        assert!(trie.verify());
     }
 
     #[test]
-    fn remove_should_remove_key() {
+    fn remove_prefix_should_remove_all_keys_under_prefix() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        trie.add("abc".to_string(), TestValue::new(1));
+        trie.add("/sensors/room1/temp".to_string(), TestValue::new(1));
+        trie.add("/sensors/room1/humidity".to_string(), TestValue::new(2));
+        trie.add("/sensors/room1/door".to_string(), TestValue::new(3));
+        trie.add("/sensors/room2/temp".to_string(), TestValue::new(4));
 
         // Act
-        let removed = trie.remove("abc");
+        let removed = trie.remove_prefix("/sensors/room1/");
 
         // Assert
-        assert_eq!(trie.len(), 0);
-        assert_eq!(removed.unwrap().id, 1);
-        assert_eq!(trie.find("abc"), None);
+        assert_eq!(removed, 3);
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.keys(), vec!["/sensors/room2/temp"]);
+        assert_eq!(trie.count_prefix("/sensors/"), 1);
+        assert!(!trie.has_prefix("/sensors/room1"));
+        assert_eq!(trie.node_count(), "/sensors/room2/temp".len());
+        assert!(trie.verify());
+        assert_eq!(trie.remove_prefix("/sensors/room1/"), 0);
+        assert_eq!(trie.remove_prefix("/sensors/room2/temp"), 1);
+        assert!(trie.is_empty());
+        assert_eq!(trie.node_count(), 0);
     }
 
     #[test]
-    fn remove_should_keep_other_keys_with_same_prefix() {
+    fn describe_should_contain_len_and_node_count() {
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("ab".to_string(), TestValue::new(1));
+        trie.add("abc".to_string(), TestValue::new(2));
+        trie.add("b".to_string(), TestValue::new(3));
+
+        let summary = trie.describe();
+
+        assert_eq!(summary, "TrieTree { len: 3, node_count: 4, valid: true }");
+    }
+
+    fn glob_trie() -> TrieTree<TestValue> {
+        let mut trie = TrieTree::default();
+        for (id, key) in [
+            "/floor-1",
+            "/floor-1/sensor",
+            "/floor-1/camera",
+            "/floor-2/sensor",
+            "/floor-2/sensor/backup",
+            "/roof/sensor",
+        ]
+        .iter()
+        .enumerate()
+        {
+            trie.add(key.to_string(), TestValue::new(id as u64));
+        }
+        trie
+    }
+
+    #[test]
+    fn matches_glob_should_match_leading_wildcard() {
+        init();
+        let trie = glob_trie();
+
+        let matches = trie.matches_glob("*/sensor");
+
+        assert_eq!(
+            matches,
+            vec!["/floor-1/sensor", "/floor-2/sensor", "/roof/sensor"]
+        );
+    }
+
+    #[test]
+    fn matches_glob_should_match_trailing_wildcard() {
+        init();
+        let trie = glob_trie();
+
+        let matches = trie.matches_glob("/floor-2/*");
+
+        assert_eq!(matches, vec!["/floor-2/sensor", "/floor-2/sensor/backup"]);
+        assert_eq!(trie.matches_glob("/floor-1*").len(), 3);
+        assert_eq!(trie.matches_glob("*").len(), trie.len());
+    }
+
+    #[test]
+    fn matches_glob_should_match_internal_wildcard() {
+        init();
+        let trie = glob_trie();
+
+        let matches = trie.matches_glob("/floor-*/sensor");
+
+        assert_eq!(matches, vec!["/floor-1/sensor", "/floor-2/sensor"]);
+        assert_eq!(trie.matches_glob("/floor-*1"), vec!["/floor-1"]);
+        assert!(trie.matches_glob("/floor-1*1").is_empty());
+        assert!(trie.matches_glob("/basement*").is_empty());
+        assert_eq!(trie.matches_glob("/roof/sensor"), vec!["/roof/sensor"]);
+    }
+
+    #[test]
+    fn into_pairs_should_return_pairs_in_key_order() {
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("rust-lang".to_string(), TestValue::new(1));
+        trie.add("ruby".to_string(), TestValue::new(2));
+        trie.add("rust".to_string(), TestValue::new(3));
+
+        let pairs = trie.into_pairs();
+
+        assert_eq!(
+            pairs,
+            vec![
+                ("ruby".to_string(), TestValue::new(2)),
+                ("rust".to_string(), TestValue::new(3)),
+                ("rust-lang".to_string(), TestValue::new(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn branching_factor_histogram_should_count_nodes_by_children() {
+        init();
+        let mut trie = TrieTree::default();
+        for (id, key) in ["ab", "abc", "ad", "b"].iter().enumerate() {
+            trie.add(key.to_string(), TestValue::new(id as u64));
+        }
+        // a -> {b -> {c}, d}, b
+
+        let histogram = trie.branching_factor_histogram();
+
+        assert_eq!(
+            histogram.into_iter().collect::<Vec<_>>(),
+            vec![(0, 3), (1, 1), (2, 1)]
+        );
+        assert!(TrieTree::<TestValue>::default()
+            .branching_factor_histogram()
+            .is_empty());
+    }
+
+    #[test]
+    fn prefix_value_should_return_value_when_prefix_is_stored_key() {
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("/floor-1".to_string(), TestValue::new(1));
+        trie.add("/floor-1/sensor".to_string(), TestValue::new(2));
+
+        assert!(trie.has_prefix("/floor-1"));
+        assert_eq!(trie.prefix_value("/floor-1"), Some(&TestValue::new(1)));
+    }
+
+    #[test]
+    fn prefix_value_should_return_none_when_prefix_is_internal_node() {
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("/floor-1/sensor".to_string(), TestValue::new(1));
+
+        assert!(trie.has_prefix("/floor-1/"));
+        assert_eq!(trie.prefix_value("/floor-1/"), None);
+        assert!(!trie.has_prefix("/floor-2"));
+        assert_eq!(trie.prefix_value("/floor-2"), None);
+        assert_eq!(trie.prefix_value(""), None);
+    }
+
+    #[test]
+    fn entries_by_value_should_order_ties_by_key() {
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("d".to_string(), 2);
+        trie.add("b".to_string(), 1);
+        trie.add("c".to_string(), 2);
+        trie.add("a".to_string(), 3);
+        trie.add("ab".to_string(), 1);
+
+        let entries = trie.entries_by_value();
+
+        assert_eq!(
+            entries,
+            vec![
+                ("ab".to_string(), &1),
+                ("b".to_string(), &1),
+                ("c".to_string(), &2),
+                ("d".to_string(), &2),
+                ("a".to_string(), &3),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_sorted_should_match_sequential_add() {
+        init();
+        let keys = [
+            "/floor-1",
+            "/floor-1/camera",
+            "/floor-1/sensor",
+            "/floor-1/sensor",
+            "/floor-10",
+            "/floor-2/sensor",
+            "a",
+            "ab",
+            "b",
+        ];
+        let pairs: Vec<(String, u64)> = keys
+            .iter()
+            .enumerate()
+            .map(|(id, key)| (key.to_string(), id as u64))
+            .collect();
+        let mut expected = TrieTree::default();
+        for (key, id) in pairs.iter() {
+            expected.add(key.clone(), *id);
+        }
+
+        let trie = TrieTree::from_sorted(pairs);
+
+        assert_eq!(trie.len(), expected.len());
+        assert!(trie.verify());
+        for key in keys
+            .iter()
+            .chain(["/floor", "/floor-1/", "c", "abc"].iter())
+        {
+            assert_eq!(trie.find(key), expected.find(key), "{key}");
+        }
+        assert_eq!(trie.values(), expected.values());
+        assert_eq!(
+            trie.count_prefix("/floor-1"),
+            expected.count_prefix("/floor-1")
+        );
+        assert_eq!(trie.describe(), expected.describe());
+    }
+
+    #[test]
+    fn from_sorted_should_keep_keys_before_unsorted_pair() {
+        init();
+        // "abcd"は"abd"より前に並ぶべきキーで、作成済みの'c'の部分木に戻る
+        let pairs = ["abc", "abd", "abcd", "a"]
+            .iter()
+            .enumerate()
+            .map(|(id, key)| (key.to_string(), id as u64))
+            .collect();
+
+        let trie = TrieTree::from_sorted(pairs);
+
+        assert_eq!(trie.len(), 4);
+        assert_eq!(trie.find("abc"), Some(&0));
+        assert_eq!(trie.find("abd"), Some(&1));
+        assert_eq!(trie.find("abcd"), Some(&2));
+        assert!(trie.verify());
+    }
+
+    #[test]
+    fn find_prefix_should_return_values_in_subtree() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        trie.add("rust".to_string(), TestValue::new(1));
-        trie.add("rust-lang".to_string(), TestValue::new(2));
+        trie.add("/a/1".to_string(), TestValue::new(1));
+        trie.add("/a/2".to_string(), TestValue::new(2));
+        trie.add("/b/1".to_string(), TestValue::new(3));
+        trie.add("/a".to_string(), TestValue::new(4));
 
-        // Act: "rust"を削除
-        let removed = trie.remove("rust");
+        // Act
+        let values = trie.find_prefix("/a");
 
-        // Assert: "rust"は削除され、"rust-lang"は保持される
-        assert_eq!(trie.len(), 1);
-        assert_eq!(removed.unwrap().id, 1);
-        assert_eq!(trie.find("rust"), None);
-        assert_eq!(trie.find("rust-lang").unwrap().id, 2);
+        // Assert
+        let ids = |values: Vec<&TestValue>| values.iter().map(|v| v.id).collect::<Vec<_>>();
+        assert_eq!(ids(values), vec![4, 1, 2]);
+        assert_eq!(ids(trie.find_prefix("/a/")), vec![1, 2]);
+        assert_eq!(ids(trie.find_prefix("")), vec![4, 1, 2, 3]);
+        assert!(trie.find_prefix("/a/3").is_empty());
     }
 
     #[test]
-    fn remove_should_cleanup_unused_nodes() {
+    fn keys_should_return_inserted_keys_in_order() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("rust-lang".to_string(), TestValue::new(1));
+        trie.add("ruby".to_string(), TestValue::new(2));
+        trie.add("rust".to_string(), TestValue::new(3));
+        trie.add("go".to_string(), TestValue::new(4));
 
-        // Act: "rust"を削除
-        let removed = trie.remove("rust");
+        // Act
+        let keys = trie.keys();
 
         // Assert
-        assert_eq!(trie.len(), 0);
-        assert_eq!(removed.unwrap().id, 1);
-        // 'r', 'u', 's', 't' のノードが全て削除されていることを確認
-        println!("{:?}", trie.root.keys());
-        assert!(trie.root.is_empty());
+        // "r", "ru", "rus", "rust-"などのInternalノードで終わる文字列は含まない
+        assert_eq!(keys, vec!["go", "ruby", "rust", "rust-lang"]);
+        assert!(TrieTree::<TestValue>::default().keys().is_empty());
     }
 
     #[test]
-    fn remove_should_keep_nodes_used_by_other_keys() {
+    fn entries_should_return_keys_with_values_in_order() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        trie.add("rust".to_string(), TestValue::new(1));
-        trie.add("rust-lang".to_string(), TestValue::new(2));
-        trie.add("ruby".to_string(), TestValue::new(3));
+        let devices = [
+            crate::iot::IoTDevice::new(1, "sensor", "/floor-2/sensor"),
+            crate::iot::IoTDevice::new(2, "camera", "/floor-1/camera"),
+            crate::iot::IoTDevice::new(3, "sensor", "/floor-1/sensor"),
+        ];
+        for device in devices.iter() {
+            trie.add(device.path.clone(), device.clone());
+        }
 
-        // Act: "rust-lang"を削除
-        let removed = trie.remove("rust-lang");
+        // Act
+        let entries = trie.entries();
 
-        // Assert: "rust"と"ruby"は保持される
-        assert_eq!(trie.len(), 2);
-        assert_eq!(removed.unwrap().id, 2);
-        assert_eq!(trie.find("rust").unwrap().id, 1);
-        assert_eq!(trie.find("ruby").unwrap().id, 3);
-        assert_eq!(trie.find("rust-lang"), None);
+        // Assert
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["/floor-1/camera", "/floor-1/sensor", "/floor-2/sensor"]
+        );
+        let values: Vec<&crate::iot::IoTDevice> = entries.iter().map(|(_, v)| *v).collect();
+        assert_eq!(values, vec![&devices[1], &devices[2], &devices[0]]);
     }
 
     #[test]
-    fn remove_should_return_none_for_missing_key() {
+    fn longest_prefix_match_should_return_deepest_entry() {
+        // Arrange
+        init();
+        let mut trie = TrieTree::default();
+        trie.add("a".to_string(), TestValue::new(1));
+        trie.add("ab".to_string(), TestValue::new(2));
+        trie.add("abcd".to_string(), TestValue::new(3));
+
+        // Act
+        let matched = trie.longest_prefix_match("abc");
+
+        // Assert
+        assert_eq!(matched, Some(("ab".to_string(), &TestValue::new(2))));
+        assert_eq!(
+            trie.longest_prefix_match("abcde"),
+            Some(("abcd".to_string(), &TestValue::new(3)))
+        );
+        assert_eq!(
+            trie.longest_prefix_match("ax"),
+            Some(("a".to_string(), &TestValue::new(1)))
+        );
+        assert_eq!(trie.longest_prefix_match("b"), None);
+        assert_eq!(trie.longest_prefix_match(""), None);
+    }
+
+    #[test]
+    fn fuzzy_find_should_return_keys_within_distance() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
         trie.add("rust".to_string(), TestValue::new(1));
+        trie.add("rusty".to_string(), TestValue::new(2));
+        trie.add("ruby".to_string(), TestValue::new(3));
+        trie.add("go".to_string(), TestValue::new(4));
 
         // Act
-        let removed = trie.remove("not_exists");
+        let found = trie.fuzzy_find("rost", 1);
 
         // Assert
-        assert_eq!(trie.len(), 1);
-        assert_eq!(removed, None);
+        let keys = |found: Vec<(String, &TestValue)>| {
+            found.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(found), vec!["rust"]);
+        assert!(trie.fuzzy_find("rost", 0).is_empty());
+        assert_eq!(keys(trie.fuzzy_find("rost", 2)), vec!["rust", "rusty"]);
+        assert_eq!(
+            keys(trie.fuzzy_find("rost", 3)),
+            vec!["go", "ruby", "rust", "rusty"]
+        );
+        assert_eq!(keys(trie.fuzzy_find("rust", 0)), vec!["rust"]);
+        assert_eq!(keys(trie.fuzzy_find("", 2)), vec!["go"]);
     }
 
     #[test]
-    fn remove_should_return_none_when_tree_is_empty() {
+    fn values_should_return_values_in_key_order() {
         // Arrange
         init();
-        let mut trie = TrieTree::<TestValue>::default();
+        let mut trie = TrieTree::default();
+        trie.add("rust-lang".to_string(), TestValue::new(1));
+        trie.add("ruby".to_string(), TestValue::new(2));
+        trie.add("rust".to_string(), TestValue::new(3));
 
         // Act
-        let removed = trie.remove("not_exists");
+        let values = trie.values();
 
         // Assert
-        assert_eq!(trie.len(), 0);
-        assert_eq!(removed, None);
+        let ids: Vec<u64> = values.iter().map(|v| v.id).collect();
+        assert_eq!(ids, vec![2, 3, 1]);
     }
 
     #[test]
-    fn remove_should_remove_key_for_single_char_key() {
+    fn cursor_should_visit_entries_under_prefix_in_order() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        trie.add("a".to_string(), TestValue::new(1));
+        for (i, key) in ["/a/2", "/a", "/b/1", "/a/1/x", "/a/1"].iter().enumerate() {
+            trie.add(key.to_string(), TestValue::new(i as u64));
+        }
 
         // Act
-        let removed = trie.remove("a");
+        let mut cursor = trie.cursor("/a");
+        let mut keys = vec![];
+        while let Some((key, _)) = cursor.next_entry(&trie) {
+            keys.push(key);
+        }
 
         // Assert
-        assert_eq!(trie.len(), 0);
-        assert_eq!(removed.unwrap().id, 1);
-        assert_eq!(trie.find("a"), None);
+        assert_eq!(keys, vec!["/a", "/a/1", "/a/1/x", "/a/2"]);
+        let mut cursor = trie.cursor("");
+        assert_eq!(
+            cursor.next_entry(&trie).map(|(k, v)| (k, v.id)),
+            Some(("/a".to_string(), 1))
+        );
+        assert_eq!(trie.cursor("/c").next_entry(&trie), None);
     }
 
     #[test]
-    fn remove_node_should_return_none_for_unused_nodes() {
+    #[should_panic(expected = "trie was mutated while a cursor was active")]
+    fn cursor_should_detect_mutation() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
         trie.add("rust".to_string(), TestValue::new(1));
-        trie.add("rust-lang".to_string(), TestValue::new(2));
-        // 一度"rust"を削除してInternalノードにする
+        trie.add("ruby".to_string(), TestValue::new(2));
+        let mut cursor = trie.cursor("ru");
+        assert!(cursor.next_entry(&trie).is_some());
+
+        // Act: カーソル作成後に変更する
         trie.remove("rust");
+
+        // Assert
+        cursor.next_entry(&trie);
+    }
+
+    #[test]
+    fn symbols_should_be_used_as_byte_keys() {
+        // Arrange
+        init();
+        let mut trie: TrieTree<TestValue, u8> = TrieTree::default();
+        trie.add_symbols(&[192, 168, 0, 1], TestValue::new(1));
+        trie.add_symbols(&[192, 168, 0, 2], TestValue::new(2));
+        trie.add_symbols(&[10, 0, 0, 1], TestValue::new(3));
+
         // Act
-        let actual = trie.remove_node(&"rust-lang".chars().collect::<Vec<char>>(), 4, 'l');
+        let replaced = trie.add_symbols(&[10, 0, 0, 1], TestValue::new(4));
+        let removed = trie.remove_symbols(&[192, 168, 0, 1]);
 
-        // Act & Assert: 未使用になったノードを削除
-        assert_eq!(actual, (None, false));
+        // Assert
+        assert_eq!(replaced, Some(TestValue::new(3)));
+        assert_eq!(removed, Some(TestValue::new(1)));
+        assert_eq!(trie.len(), 2);
+        assert_eq!(
+            trie.find_symbols(&[192, 168, 0, 2]),
+            Some(&TestValue::new(2))
+        );
+        assert_eq!(trie.find_symbols(&[10, 0, 0, 1]), Some(&TestValue::new(4)));
+        assert_eq!(trie.find_symbols(&[192, 168, 0, 1]), None);
+        assert_eq!(trie.find_symbols(&[192, 168]), None);
+        assert_eq!(trie.find_symbols(&[]), None);
+        // 10.0.0.1 -> 4ノード, 192.168.0.2 -> 4ノード
+        assert_eq!(
+            trie.describe(),
+            "TrieTree { len: 2, node_count: 8, valid: true }"
+        );
     }
 
     #[test]
-    fn remove_should_keep_intermediate_values() {
+    fn symbols_should_match_str_api_for_char_keys() {
         // Arrange
         init();
         let mut trie = TrieTree::default();
-        // "r"と"rust"をこの順で追加（"r"がBranchノードになる）
-        trie.add("r".to_string(), TestValue::new(1));
-        trie.add("rust".to_string(), TestValue::new(2));
+        trie.add("rust".to_string(), TestValue::new(1));
 
-        // Act: "rust"を削除
-        let removed = trie.remove("rust");
+        // Act
+        trie.add_symbols(&['r', 'u', 'b', 'y'], TestValue::new(2));
 
-        // Assert: "rust"は削除され、"r"は保持される
-        assert_eq!(removed.unwrap().id, 2);
-        assert_eq!(trie.len(), 1);
-        assert_eq!(trie.find("r").unwrap().id, 1);
-        assert_eq!(trie.find("rust"), None);
+        // Assert
+        assert_eq!(trie.find("ruby"), Some(&TestValue::new(2)));
+        assert_eq!(
+            trie.find_symbols(&['r', 'u', 's', 't']),
+            Some(&TestValue::new(1))
+        );
+        assert_eq!(
+            trie.remove_symbols(&['r', 'u', 's', 't']),
+            Some(TestValue::new(1))
+        );
+        assert_eq!(trie.keys(), vec!["ruby"]);
     }
 }